@@ -0,0 +1,4 @@
+DROP TABLE calibrations;
+DROP TABLE pump_events;
+DROP TABLE measurements;
+DROP TABLE plants;
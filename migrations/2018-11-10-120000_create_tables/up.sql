@@ -0,0 +1,39 @@
+CREATE TABLE plants (
+  uuid UUID PRIMARY KEY,
+  name TEXT NOT NULL,
+  description TEXT NOT NULL,
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Every field of every measurement, like InfluxDB stores them; the uuid tag is copied into
+-- plant_uuid so that the readings of a plant can be found through an index
+CREATE TABLE measurements (
+  time TIMESTAMPTZ NOT NULL,
+  measurement TEXT NOT NULL,
+  tags JSONB NOT NULL DEFAULT '{}',
+  field TEXT NOT NULL,
+  value DOUBLE PRECISION NOT NULL,
+  plant_uuid UUID,
+  PRIMARY KEY (measurement, tags, field, time)
+);
+
+CREATE INDEX measurements_plant_idx ON measurements (plant_uuid, measurement, field, time DESC)
+  WHERE plant_uuid IS NOT NULL;
+CREATE INDEX measurements_time_idx ON measurements (measurement, field, time DESC);
+
+CREATE TABLE pump_events (
+  time TIMESTAMPTZ NOT NULL,
+  plant_uuid UUID NOT NULL,
+  running BOOLEAN NOT NULL,
+  PRIMARY KEY (plant_uuid, time)
+);
+
+CREATE INDEX pump_events_time_idx ON pump_events (time);
+
+-- The range of moisture voltages of each plant during the last week
+CREATE TABLE calibrations (
+  plant_uuid UUID PRIMARY KEY,
+  moisture_p05 DOUBLE PRECISION,
+  moisture_p95 DOUBLE PRECISION,
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,511 @@
+//! A small HTTP API for controlling a running precip instance.
+
+use std::collections;
+use std::io;
+use std::net;
+use std::sync;
+
+use chrono;
+use failure;
+use futures;
+use hyper;
+use serde;
+use serde_json;
+use slog;
+use tokio;
+use url;
+use uuid;
+
+use db;
+use export;
+use i2c;
+use queue;
+use watering;
+
+pub struct Api {
+    log: slog::Logger,
+    waterers: collections::HashMap<uuid::Uuid, sync::Arc<watering::Waterer>>,
+    queue: sync::Arc<queue::RunQueue>,
+    health: sync::Arc<i2c::Health>,
+    db: sync::Arc<db::Db>,
+    exporter: sync::Arc<export::Exporter>,
+}
+
+// Longer exports are better done with the export command, which doesn't tie up the API
+const MAX_EXPORT_DAYS: i64 = 366;
+
+type Response =
+    Box<futures::Future<Item = hyper::Response<hyper::Body>, Error = hyper::Error> + Send>;
+
+#[derive(Debug, Serialize)]
+struct WaterResponse {
+    uuid: uuid::Uuid,
+    start: chrono::DateTime<chrono::Utc>,
+    deferred: bool,
+}
+
+/// The moisture rollups of a plant, in the shape that the dashboard plots them in.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PlantMoisture {
+    id: uuid::Uuid,
+    moisture_timeseries: MoistureTimeseries,
+    min_moisture: Option<f64>,
+    max_moisture: Option<f64>,
+    last_moisture: Option<f64>,
+    min_raw_voltage: Option<f64>,
+    max_raw_voltage: Option<f64>,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MoistureTimeseries {
+    measurement_start: Vec<chrono::DateTime<chrono::Utc>>,
+    min: Vec<f64>,
+    p25: Vec<f64>,
+    p50: Vec<f64>,
+    p75: Vec<f64>,
+    max: Vec<f64>,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+impl Api {
+    pub fn new(
+        log: slog::Logger,
+        waterers: collections::HashMap<uuid::Uuid, sync::Arc<watering::Waterer>>,
+        queue: sync::Arc<queue::RunQueue>,
+        health: sync::Arc<i2c::Health>,
+        db: sync::Arc<db::Db>,
+        exporter: sync::Arc<export::Exporter>,
+    ) -> Self {
+        Api {
+            log,
+            waterers,
+            queue,
+            health,
+            db,
+            exporter,
+        }
+    }
+
+    pub fn serve(
+        self,
+        addr: net::SocketAddr,
+    ) -> Result<impl futures::Future<Item = (), Error = failure::Error>, failure::Error> {
+        use futures::Future;
+
+        info!(self.log, "serving API on {}", addr);
+
+        let api = sync::Arc::new(self);
+        Ok(hyper::Server::try_bind(&addr)?
+            .serve(move || {
+                let api = api.clone();
+                hyper::service::service_fn(move |req| api.handle(req))
+            })
+            .map_err(failure::Error::from))
+    }
+
+    fn handle(&self, req: hyper::Request<hyper::Body>) -> Response {
+        use futures::Future;
+
+        let path = req
+            .uri()
+            .path()
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_owned())
+            .collect::<Vec<_>>();
+        let query = parse_query(req.uri().query());
+
+        debug!(
+            self.log,
+            "API request method={} path={:?}",
+            req.method(),
+            path
+        );
+
+        let path = path.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        let response = match (req.method(), path.as_slice()) {
+            (method, &["plants", uuid, "water"]) if *method == hyper::Method::POST => {
+                let force = query.get("force").map_or(false, |v| v == "true");
+                match uuid.parse::<uuid::Uuid>() {
+                    Ok(uuid) => self.water(uuid, force),
+                    Err(e) => error(hyper::StatusCode::BAD_REQUEST, e),
+                }
+            }
+            (method, &["queue"]) if *method == hyper::Method::GET => {
+                json(hyper::StatusCode::OK, &self.queue.status())
+            }
+            (method, &["db"]) if *method == hyper::Method::GET => {
+                json(hyper::StatusCode::OK, &self.db.host_stats())
+            }
+            (method, &["i2c"]) if *method == hyper::Method::GET => {
+                json(hyper::StatusCode::OK, &self.health.status())
+            }
+            (method, &["global"]) if *method == hyper::Method::GET => {
+                return Box::new(self.db.collect_global_stats().then(|result| {
+                    Ok(match result {
+                        Ok(stats) => json(hyper::StatusCode::OK, &stats),
+                        Err(e) => error(hyper::StatusCode::BAD_GATEWAY, e),
+                    })
+                }));
+            }
+            (method, &["export"]) if *method == hyper::Method::GET => {
+                return self.export(&query);
+            }
+            (method, &["moisture"]) if *method == hyper::Method::GET => {
+                return self.moisture(&query);
+            }
+            _ => error(hyper::StatusCode::NOT_FOUND, "no such resource"),
+        };
+        Box::new(futures::future::ok(response))
+    }
+
+    /// Exports a range of days, like `/export?from=2018-10-01&to=2018-10-31&plants=<uuid>,<uuid>`;
+    /// CSV is streamed as it is queried, and Parquet once the file is written.
+    fn export(&self, query: &collections::HashMap<String, String>) -> Response {
+        use futures::Future;
+        use futures::Stream;
+
+        let request = (|| -> Result<_, failure::Error> {
+            let from = match query.get("from") {
+                Some(from) => from.parse::<chrono::NaiveDate>()?,
+                None => bail!("the first day to export is missing"),
+            };
+            let to = match query.get("to") {
+                Some(to) => to.parse::<chrono::NaiveDate>()?,
+                None => self.exporter.today(),
+            };
+            let plants = query
+                .get("plants")
+                .map_or("", |p| p.as_str())
+                .split(',')
+                .filter(|p| !p.is_empty())
+                .map(|p| p.parse::<uuid::Uuid>())
+                .collect::<Result<Vec<_>, _>>()?;
+            let format = query
+                .get("format")
+                .map_or(Ok(export::Format::Csv), |f| f.parse())?;
+            if from > to {
+                bail!("the first day {} is after the last day {}", from, to);
+            }
+            if to - from >= chrono::Duration::days(MAX_EXPORT_DAYS) {
+                bail!(
+                    "at most {} days can be exported at once; use the export command for more",
+                    MAX_EXPORT_DAYS
+                );
+            }
+            Ok((from, to, plants, format))
+        })();
+        let (from, to, plants, format) = match request {
+            Ok(request) => request,
+            Err(e) => {
+                return Box::new(futures::future::ok(error(
+                    hyper::StatusCode::BAD_REQUEST,
+                    e,
+                )))
+            }
+        };
+
+        let (start, end) = self.exporter.days(from, to);
+        let response = hyper::Response::builder()
+            .status(hyper::StatusCode::OK)
+            .header(hyper::header::CONTENT_TYPE, format.content_type())
+            .header(
+                hyper::header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"precip-{}-{}.{}\"",
+                    from,
+                    to,
+                    format.extension()
+                )
+                .as_str(),
+            )
+            .body(());
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                return Box::new(futures::future::ok(error(
+                    hyper::StatusCode::INTERNAL_SERVER_ERROR,
+                    e,
+                )))
+            }
+        };
+
+        match format {
+            export::Format::Csv => {
+                let body = self
+                    .exporter
+                    .csv(start, end, plants)
+                    .map_err(|e| e.compat());
+                Box::new(futures::future::ok(
+                    response.map(|()| hyper::Body::wrap_stream(body)),
+                ))
+            }
+            export::Format::Parquet => {
+                use std::io::Seek;
+
+                let file = match export::tempfile().and_then(|f| Ok((f.try_clone()?, f))) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        return Box::new(futures::future::ok(error(
+                            hyper::StatusCode::INTERNAL_SERVER_ERROR,
+                            e,
+                        )))
+                    }
+                };
+                let (mut file, written) = file;
+                Box::new(
+                    self.exporter
+                        .parquet(start, end, plants, written)
+                        .and_then(move |()| {
+                            file.seek(io::SeekFrom::Start(0))?;
+                            Ok(file)
+                        })
+                        .then(|result| {
+                            Ok(match result {
+                                Ok(file) => response.map(|()| {
+                                    hyper::Body::wrap_stream(
+                                        export::file_blocks(file).map_err(|e| e.compat()),
+                                    )
+                                }),
+                                Err(e) => error(hyper::StatusCode::BAD_GATEWAY, e),
+                            })
+                        }),
+                )
+            }
+        }
+    }
+
+    /// The moisture rollups and ranges of every plant over a range of days, like
+    /// `/moisture?from=2018-11-14&to=2018-11-20`; the last week by default.
+    fn moisture(&self, query: &collections::HashMap<String, String>) -> Response {
+        use futures::Future;
+
+        let range = (|| -> Result<_, failure::Error> {
+            let to = match query.get("to") {
+                Some(to) => to.parse::<chrono::NaiveDate>()?,
+                None => self.exporter.today(),
+            };
+            let from = match query.get("from") {
+                Some(from) => from.parse::<chrono::NaiveDate>()?,
+                None => to - chrono::Duration::days(6),
+            };
+            if from > to {
+                bail!("the first day {} is after the last day {}", from, to);
+            }
+            Ok(self.exporter.days(from, to))
+        })();
+        let (start, end) = match range {
+            Ok(range) => range,
+            Err(e) => {
+                return Box::new(futures::future::ok(error(
+                    hyper::StatusCode::BAD_REQUEST,
+                    e,
+                )))
+            }
+        };
+
+        Box::new(
+            self.db
+                .collect_samples_timeseries(start, end)
+                .join3(
+                    self.db.collect_stats(start, end),
+                    self.db.collect_samples_range(start, end),
+                )
+                .then(|result| {
+                    Ok(match result {
+                        Ok((samples, stats, ranges)) => json(
+                            hyper::StatusCode::OK,
+                            &plant_moisture(samples, stats, ranges),
+                        ),
+                        Err(e) => error(hyper::StatusCode::BAD_GATEWAY, e),
+                    })
+                }),
+        )
+    }
+
+    fn water(&self, uuid: uuid::Uuid, force: bool) -> hyper::Response<hyper::Body> {
+        use futures::Future;
+
+        let waterer = match self.waterers.get(&uuid) {
+            Some(waterer) => waterer.clone(),
+            None => return error(hyper::StatusCode::NOT_FOUND, "no such plant with a pump"),
+        };
+
+        let trigger = watering::Trigger::Manual { force };
+        let now = waterer.clock().now();
+        let start = match waterer.start_time(trigger, now) {
+            Some(start) => start,
+            None => {
+                return error(
+                    hyper::StatusCode::CONFLICT,
+                    "no time outside of blackout windows",
+                )
+            }
+        };
+
+        let log = self.log.clone();
+        tokio::spawn(watering::run(waterer, trigger).map_err(move |e| {
+            error!(log, "manual pump run failed uuid={}: {}", uuid, e);
+        }));
+
+        json(
+            hyper::StatusCode::ACCEPTED,
+            &WaterResponse {
+                uuid,
+                start,
+                deferred: start > now,
+            },
+        )
+    }
+}
+
+/// Calls the API of a running precip instance, returning the decoded JSON response.
+pub fn call(
+    addr: net::SocketAddr,
+    method: hyper::Method,
+    path: &str,
+) -> Result<serde_json::Value, failure::Error> {
+    use futures::Future;
+    use futures::Stream;
+
+    let request = hyper::Request::builder()
+        .method(method)
+        .uri(format!("http://{}{}", addr, path).as_str())
+        .body(hyper::Body::empty())?;
+
+    let mut runtime = tokio::runtime::Runtime::new()?;
+    let (status, body) =
+        runtime.block_on(hyper::Client::new().request(request).and_then(|response| {
+            let status = response.status();
+            response
+                .into_body()
+                .concat2()
+                .map(move |body| (status, body))
+        }))?;
+
+    let value = serde_json::from_slice::<serde_json::Value>(&body)?;
+    if status.is_success() {
+        Ok(value)
+    } else {
+        bail!(
+            "API request failed with {}: {}",
+            status,
+            value["error"].as_str().unwrap_or("unknown error")
+        )
+    }
+}
+
+// The samples are ordered by time
+fn plant_moisture(
+    samples: Vec<db::model::SampleTimeseries>,
+    stats: Vec<db::model::Stats>,
+    ranges: Vec<db::model::SampleRange>,
+) -> Vec<PlantMoisture> {
+    let mut plants = collections::BTreeMap::<uuid::Uuid, PlantMoisture>::new();
+    for sample in samples {
+        let timeseries = &mut plant_entry(&mut plants, sample.module_uuid).moisture_timeseries;
+        timeseries.measurement_start.push(sample.slice);
+        timeseries.min.push(sample.min_raw_voltage);
+        timeseries.p25.push(sample.p25_raw_voltage);
+        timeseries.p50.push(sample.p50_raw_voltage);
+        timeseries.p75.push(sample.p75_raw_voltage);
+        timeseries.max.push(sample.max_raw_voltage);
+    }
+    for stats in stats {
+        let plant = plant_entry(&mut plants, stats.module_uuid);
+        plant.min_moisture = Some(stats.min_moisture);
+        plant.max_moisture = Some(stats.max_moisture);
+        plant.last_moisture = Some(stats.last_moisture);
+    }
+    for range in ranges {
+        let plant = plant_entry(&mut plants, range.module_uuid);
+        plant.min_raw_voltage = Some(range.min_raw_voltage);
+        plant.max_raw_voltage = Some(range.max_raw_voltage);
+    }
+
+    plants.into_iter().map(|(_, plant)| plant).collect()
+}
+
+fn plant_entry(
+    plants: &mut collections::BTreeMap<uuid::Uuid, PlantMoisture>,
+    id: uuid::Uuid,
+) -> &mut PlantMoisture {
+    plants.entry(id).or_insert_with(|| PlantMoisture {
+        id,
+        moisture_timeseries: MoistureTimeseries::default(),
+        min_moisture: None,
+        max_moisture: None,
+        last_moisture: None,
+        min_raw_voltage: None,
+        max_raw_voltage: None,
+    })
+}
+
+fn parse_query(query: Option<&str>) -> collections::HashMap<String, String> {
+    url::form_urlencoded::parse(query.unwrap_or("").as_bytes())
+        .into_owned()
+        .collect()
+}
+
+fn json<A>(status: hyper::StatusCode, value: &A) -> hyper::Response<hyper::Body>
+where
+    A: serde::Serialize,
+{
+    match serde_json::to_vec(value) {
+        Ok(body) => hyper::Response::builder()
+            .status(status)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(hyper::Body::from(body))
+            .unwrap(),
+        Err(e) => error(hyper::StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+fn error<E>(status: hyper::StatusCode, error: E) -> hyper::Response<hyper::Body>
+where
+    E: ToString,
+{
+    let body = serde_json::to_vec(&ErrorResponse {
+        error: error.to_string(),
+    })
+    .unwrap();
+
+    hyper::Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(hyper::Body::from(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queries_are_percent_decoded() {
+        let query = parse_query(Some(
+            "plants=1c4a8f5e-7d8b-4f2a-9c3e-5b6d7e8f9a0b%2C2d5b9f6e-8e9c-4a3b-8d4f-6c7e8f9a0b1c\
+             &from=2018%2D11%2D20&to=2018-11-27+06:00&force",
+        ));
+        assert_eq!(
+            query["plants"],
+            "1c4a8f5e-7d8b-4f2a-9c3e-5b6d7e8f9a0b,2d5b9f6e-8e9c-4a3b-8d4f-6c7e8f9a0b1c"
+        );
+        assert_eq!(query["from"], "2018-11-20");
+        // A plus is a space, like in forms
+        assert_eq!(query["to"], "2018-11-27 06:00");
+        assert_eq!(query["force"], "");
+    }
+
+    #[test]
+    fn missing_queries_are_empty() {
+        assert!(parse_query(None).is_empty());
+        assert!(parse_query(Some("")).is_empty());
+    }
+}
@@ -0,0 +1,114 @@
+use chrono;
+use chrono_tz;
+
+use config;
+use schedule;
+
+/// How many overlapping windows to skip past before giving up on finding an allowed instant.
+const MAX_WINDOWS: usize = 1000;
+
+/// Windows of time during which pumps must not run, in the wall-clock time of the installation.
+#[derive(Clone, Debug)]
+pub struct Calendar {
+    timezone: chrono_tz::Tz,
+    quiet_hours: Vec<config::QuietHours>,
+    periods: Vec<config::BlackoutPeriod>,
+    dates: Vec<chrono::NaiveDate>,
+}
+
+impl Calendar {
+    pub fn new(timezone: chrono_tz::Tz, blackout: &config::Blackout) -> Self {
+        Calendar {
+            timezone,
+            quiet_hours: blackout.quiet_hours.clone(),
+            periods: blackout.periods.clone(),
+            dates: blackout.dates.clone(),
+        }
+    }
+
+    /// Applies a per-plant override, which either extends or replaces this calendar.
+    pub fn with_override(&self, blackout: &config::PlantBlackout) -> Self {
+        let mut calendar = if blackout.inherit {
+            self.clone()
+        } else {
+            Calendar::new(self.timezone, &config::Blackout::default())
+        };
+
+        calendar
+            .quiet_hours
+            .extend(blackout.blackout.quiet_hours.iter().cloned());
+        calendar
+            .periods
+            .extend(blackout.blackout.periods.iter().cloned());
+        calendar
+            .dates
+            .extend(blackout.blackout.dates.iter().cloned());
+
+        calendar
+    }
+
+    pub fn is_blacked_out(&self, t: chrono::DateTime<chrono::Utc>) -> bool {
+        self.window_end(t).is_some()
+    }
+
+    /// The earliest instant at or after `t` that is not blacked out.
+    pub fn next_allowed(
+        &self,
+        t: chrono::DateTime<chrono::Utc>,
+    ) -> Option<chrono::DateTime<chrono::Utc>> {
+        let mut t = t;
+
+        for _ in 0..MAX_WINDOWS {
+            match self.window_end(t) {
+                Some(end) => t = end,
+                None => return Some(t),
+            }
+        }
+
+        None
+    }
+
+    /// The latest end of all windows that `t` falls within, if any.
+    fn window_end(
+        &self,
+        t: chrono::DateTime<chrono::Utc>,
+    ) -> Option<chrono::DateTime<chrono::Utc>> {
+        let local = t.with_timezone(&self.timezone).naive_local();
+        let date = local.date();
+        let time = local.time();
+
+        let quiet_hours = self.quiet_hours.iter().filter_map(|q| {
+            if q.from <= q.to {
+                if q.from <= time && time < q.to {
+                    Some(date.and_time(q.to))
+                } else {
+                    None
+                }
+            } else if time >= q.from {
+                Some(date.succ().and_time(q.to))
+            } else if time < q.to {
+                Some(date.and_time(q.to))
+            } else {
+                None
+            }
+        });
+
+        let periods = self
+            .periods
+            .iter()
+            .filter(|p| p.from <= local && local < p.to)
+            .map(|p| p.to);
+
+        let dates = self
+            .dates
+            .iter()
+            .filter(|d| **d == date)
+            .map(|d| d.succ().and_hms(0, 0, 0));
+
+        quiet_hours
+            .chain(periods)
+            .chain(dates)
+            .max()
+            .map(|end| schedule::resolve_wall_clock(self.timezone, end))
+    }
+}
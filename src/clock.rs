@@ -0,0 +1,276 @@
+//! The time that the jobs go by, which is a virtual time that only moves on when the jobs wait for
+//! it while replaying a recording, so that a replay sees the same times on every run.
+
+use std::sync;
+use std::time;
+
+use chrono;
+use failure;
+use futures;
+use tokio;
+
+use futures::Future;
+
+pub type Sleep = Box<futures::Future<Item = (), Error = failure::Error> + Send>;
+
+pub type Ticks = Box<futures::Stream<Item = (), Error = failure::Error> + Send>;
+
+#[derive(Clone, Debug)]
+pub enum Clock {
+    /// The wall clock.
+    System,
+    /// Stands still until the timeline is advanced.
+    Virtual(sync::Arc<Timeline>),
+}
+
+/// The virtual time of a replay, and everything that waits for it.
+#[derive(Debug)]
+pub struct Timeline {
+    state: sync::Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    now: chrono::DateTime<chrono::Utc>,
+    // in order of when they started sleeping, which is the order they wake up in at the same time
+    sleepers: Vec<(
+        chrono::DateTime<chrono::Utc>,
+        futures::sync::oneshot::Sender<()>,
+    )>,
+    // changes whenever something starts sleeping or is woken up
+    generation: u64,
+}
+
+impl Clock {
+    pub fn now(&self) -> chrono::DateTime<chrono::Utc> {
+        match *self {
+            Clock::System => chrono::Utc::now(),
+            Clock::Virtual(ref timeline) => timeline.now(),
+        }
+    }
+
+    /// The time after a duration from now.
+    pub fn after(&self, duration: time::Duration) -> chrono::DateTime<chrono::Utc> {
+        later(self.now(), duration)
+    }
+
+    pub fn sleep(&self, duration: time::Duration) -> Sleep {
+        match *self {
+            Clock::System => Box::new(
+                tokio::timer::Delay::new(time::Instant::now() + duration)
+                    .map_err(failure::Error::from),
+            ),
+            Clock::Virtual(ref timeline) => timeline.sleep_until(self.after(duration)),
+        }
+    }
+
+    /// Sleeps until the time, or not at all if it has passed.
+    pub fn sleep_until(&self, time: chrono::DateTime<chrono::Utc>) -> Sleep {
+        match *self {
+            Clock::System => self.sleep(
+                (time - self.now())
+                    .to_std()
+                    .unwrap_or(time::Duration::new(0, 0)),
+            ),
+            Clock::Virtual(ref timeline) => timeline.sleep_until(time),
+        }
+    }
+
+    /// Ticks right away, and then every time the duration has passed.
+    pub fn interval(&self, duration: time::Duration) -> Ticks {
+        use futures::Stream;
+
+        match *self {
+            Clock::System => Box::new(
+                tokio::timer::Interval::new(time::Instant::now(), duration)
+                    .map(|_| ())
+                    .map_err(failure::Error::from),
+            ),
+            Clock::Virtual(ref timeline) => {
+                let timeline = timeline.clone();
+                let start = timeline.now();
+                Box::new(futures::stream::unfold(start, move |next| {
+                    Some(
+                        timeline
+                            .sleep_until(next)
+                            .map(move |()| ((), later(next, duration))),
+                    )
+                }))
+            }
+        }
+    }
+}
+
+impl Timeline {
+    pub fn new(origin: chrono::DateTime<chrono::Utc>) -> Self {
+        Timeline {
+            state: sync::Mutex::new(State {
+                now: origin,
+                sleepers: Vec::new(),
+                generation: 0,
+            }),
+        }
+    }
+
+    pub fn now(&self) -> chrono::DateTime<chrono::Utc> {
+        self.state.lock().unwrap().now
+    }
+
+    /// The earliest time that anything still sleeps until.
+    pub fn next_wakeup(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .sleepers
+            .retain(|&(_, ref sender)| !sender.is_canceled());
+        state.sleepers.iter().map(|&(time, _)| time).min()
+    }
+
+    /// Changes whenever something starts sleeping or is woken up, so that it can be told whether
+    /// the jobs are all waiting.
+    pub fn generation(&self) -> u64 {
+        self.state.lock().unwrap().generation
+    }
+
+    /// Moves the time on, and wakes up everything that sleeps until then.  The time never goes
+    /// back.
+    pub fn advance(&self, time: chrono::DateTime<chrono::Utc>) {
+        let due = {
+            let mut state = self.state.lock().unwrap();
+            if time > state.now {
+                state.now = time;
+            }
+            let now = state.now;
+            let (due, sleeping): (Vec<_>, Vec<_>) =
+                state.sleepers.drain(..).partition(|&(t, _)| t <= now);
+            state.sleepers = sleeping;
+            if !due.is_empty() {
+                state.generation += 1;
+            }
+            due
+        };
+
+        for (_, sender) in due {
+            // Whatever slept may not be waiting anymore
+            let _ = sender.send(());
+        }
+    }
+
+    fn sleep_until(&self, time: chrono::DateTime<chrono::Utc>) -> Sleep {
+        let mut state = self.state.lock().unwrap();
+        if time <= state.now {
+            return Box::new(futures::future::ok(()));
+        }
+
+        let (sender, receiver) = futures::sync::oneshot::channel();
+        state.sleepers.push((time, sender));
+        state.generation += 1;
+        Box::new(receiver.map_err(|_| format_err!("The timeline was dropped")))
+    }
+}
+
+// Durations too long for chrono never pass
+fn later(
+    time: chrono::DateTime<chrono::Utc>,
+    duration: time::Duration,
+) -> chrono::DateTime<chrono::Utc> {
+    chrono::Duration::from_std(duration)
+        .ok()
+        .and_then(|duration| time.checked_add_signed(duration))
+        .unwrap_or_else(|| chrono::MAX_DATE.and_hms(0, 0, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync;
+    use std::time;
+
+    use chrono;
+    use futures;
+
+    use futures::Async;
+    use futures::Future;
+    use futures::Stream;
+
+    use super::*;
+
+    fn time(s: &str) -> chrono::DateTime<chrono::Utc> {
+        s.parse().unwrap()
+    }
+
+    // Polls once, in a task that nothing wakes up
+    fn poll<T, E, F>(mut f: F) -> futures::Poll<T, E>
+    where
+        F: FnMut() -> futures::Poll<T, E>,
+    {
+        futures::future::poll_fn(|| -> Result<_, ()> { Ok(Async::Ready(f())) })
+            .wait()
+            .unwrap()
+    }
+
+    #[test]
+    fn the_virtual_time_only_moves_when_advanced() {
+        let timeline = sync::Arc::new(Timeline::new(time("2018-11-20T06:00:00Z")));
+        let clock = Clock::Virtual(timeline.clone());
+
+        assert_eq!(clock.now(), time("2018-11-20T06:00:00Z"));
+        timeline.advance(time("2018-11-20T07:00:00Z"));
+        assert_eq!(clock.now(), time("2018-11-20T07:00:00Z"));
+        assert_eq!(
+            clock.after(time::Duration::from_secs(90)),
+            time("2018-11-20T07:01:30Z")
+        );
+
+        // Never backwards
+        timeline.advance(time("2018-11-20T06:30:00Z"));
+        assert_eq!(clock.now(), time("2018-11-20T07:00:00Z"));
+    }
+
+    #[test]
+    fn sleepers_wake_up_when_their_time_has_come() {
+        let timeline = sync::Arc::new(Timeline::new(time("2018-11-20T06:00:00Z")));
+        let clock = Clock::Virtual(timeline.clone());
+
+        let mut passed = clock.sleep_until(time("2018-11-20T05:00:00Z"));
+        let mut short = clock.sleep(time::Duration::from_secs(60));
+        let mut long = clock.sleep_until(time("2018-11-20T07:00:00Z"));
+        assert!(poll(|| passed.poll()).unwrap().is_ready());
+        assert!(poll(|| short.poll()).unwrap().is_not_ready());
+        assert_eq!(timeline.next_wakeup(), Some(time("2018-11-20T06:01:00Z")));
+
+        let generation = timeline.generation();
+        timeline.advance(time("2018-11-20T06:30:00Z"));
+        assert!(timeline.generation() != generation);
+        assert!(poll(|| short.poll()).unwrap().is_ready());
+        assert!(poll(|| long.poll()).unwrap().is_not_ready());
+        assert_eq!(timeline.next_wakeup(), Some(time("2018-11-20T07:00:00Z")));
+
+        // Sleepers that gave up are forgotten
+        drop(long);
+        assert_eq!(timeline.next_wakeup(), None);
+    }
+
+    #[test]
+    fn intervals_tick_at_the_virtual_time() {
+        let timeline = sync::Arc::new(Timeline::new(time("2018-11-20T06:00:00Z")));
+        let clock = Clock::Virtual(timeline.clone());
+        let mut ticks = clock.interval(time::Duration::from_secs(600));
+
+        let mut times = Vec::new();
+        while times.len() < 3 {
+            match poll(|| ticks.poll()).unwrap() {
+                Async::Ready(Some(())) => times.push(clock.now()),
+                Async::Ready(None) => panic!("the interval ended"),
+                Async::NotReady => timeline.advance(timeline.next_wakeup().unwrap()),
+            }
+        }
+
+        assert_eq!(
+            times,
+            vec![
+                time("2018-11-20T06:00:00Z"),
+                time("2018-11-20T06:10:00Z"),
+                time("2018-11-20T06:20:00Z"),
+            ]
+        );
+    }
+}
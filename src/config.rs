@@ -1,22 +1,69 @@
 use std::collections;
+use std::env;
+use std::fs;
+use std::net;
 use std::u8;
 
+use chrono;
+use chrono_tz;
 use config_rs;
 use failure;
-use influent;
 use serde;
 use uuid;
 
+use db;
+use pumps;
+use sensors;
+use sun;
+
+/// The measurement of the moisture probes, which the moisture indices are computed from.
+pub const PLANT_MEASUREMENT: &str = "plant";
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     pub db: Db,
+    #[serde(default = "default_state_dir")]
+    pub state_dir: String,
+    #[serde(default)]
+    pub location: Location,
+    #[serde(default)]
+    pub api: Api,
+    #[serde(default)]
+    pub gpio: Gpio,
+    #[serde(default)]
+    pub sampling: Sampling,
+    #[serde(default = "default_sensors")]
+    pub sensor: collections::HashMap<String, Sensor>,
+    #[serde(default)]
+    pub blackout: Blackout,
+    #[serde(default)]
+    pub power_group: collections::HashMap<String, PowerGroup>,
+    pub vacation: Option<Vacation>,
     pub plant: collections::HashMap<uuid::Uuid, Plant>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Db {
+    // InfluxDB hosts; unused for PostgreSQL
+    #[serde(default)]
     pub hosts: Vec<String>,
-    pub credentials: DbCredentials,
+    // whether to write to every host, or fail over to the next host
+    #[serde(default = "default_db_mode")]
+    pub mode: db::influx::Mode,
+    // how often to check whether failed hosts are back
+    #[serde(default = "default_db_health_check_interval_seconds")]
+    pub health_check_interval_seconds: u64,
+    // how long an InfluxDB host may take to respond to a request, before it's considered down
+    #[serde(default = "default_db_timeout_seconds")]
+    pub timeout_seconds: u64,
+    // for InfluxDB 1.x
+    pub credentials: Option<DbCredentials>,
+    // for InfluxDB 2.x
+    pub v2: Option<DbV2>,
+    // for PostgreSQL, instead of InfluxDB
+    pub postgres: Option<DbPostgres>,
+    #[serde(default)]
+    pub retention: Retention,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -26,23 +73,223 @@ pub struct DbCredentials {
     pub database: String,
 }
 
+/// Retention policies and continuous queries; these are only managed on InfluxDB 1.x.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Retention {
+    // how long to keep every sample
+    #[serde(default = "default_retention_raw_days")]
+    pub raw_days: u32,
+    // whether to store every sample, or only the rollups of them
+    #[serde(default = "default_true")]
+    pub store_raw: bool,
+    // the resolution of the samples that are kept forever
+    #[serde(default = "default_retention_downsample_interval_minutes")]
+    pub downsample_interval_minutes: u32,
+    // whether precip creates and migrates the retention policies and continuous queries
+    #[serde(default = "default_true")]
+    pub manage: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct DbV2 {
+    pub token: String,
+    pub org: String,
+    pub bucket: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct DbPostgres {
+    pub url: String,
+    // whether to store the time series in TimescaleDB hypertables
+    #[serde(default)]
+    pub timescale: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Location {
+    // defaults to the timezone of the system
+    #[serde(
+        default = "default_timezone",
+        deserialize_with = "deserialize_timezone"
+    )]
+    pub timezone: chrono_tz::Tz,
+    // in degrees, north is positive
+    pub latitude: Option<f64>,
+    // in degrees, east is positive
+    pub longitude: Option<f64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Api {
+    pub listen: net::SocketAddr,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Gpio {
+    pub backend: pumps::Backend,
+    // the default chip for the cdev backend
+    pub chip: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Sampling {
+    #[serde(default = "default_sample_interval_seconds")]
+    pub interval_seconds: u64,
+    // how often to sample the moisture of a plant while it's being watered
+    #[serde(default = "default_burst_interval_seconds")]
+    pub burst_interval_seconds: u64,
+    // how long to keep sampling at the burst rate after watering
+    #[serde(default = "default_burst_linger_seconds")]
+    pub burst_linger_seconds: u64,
+    // how often to log sensor readings
+    #[serde(default = "default_report_interval_seconds")]
+    pub report_interval_seconds: u64,
+    // how often to update the moisture indices of the plants
+    #[serde(default = "default_index_interval_seconds")]
+    pub index_interval_seconds: u64,
+    // the interval that the min, max, mean and quartiles of the samples are stored for
+    #[serde(default = "default_rollup_interval_seconds")]
+    pub rollup_interval_seconds: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Sensor {
+    pub kind: sensors::SensorKind,
+    #[serde(default = "default_i2c_bus")]
+    pub bus: String,
+    // defaults to the usual address of the sensor
+    #[serde(default, deserialize_with = "deserialize_optional_i2c_address")]
+    pub i2c_address: Option<u16>,
+    // the analog pin of an ADS1115, or the 1-Wire ID of a DS18B20 probe
+    pub channel: Option<String>,
+    // defaults to the global sample interval
+    pub interval_seconds: Option<u64>,
+    pub measurement: String,
+    // field names for the measured quantities, if not the default ones
+    #[serde(default)]
+    pub fields: collections::HashMap<sensors::Quantity, String>,
+    #[serde(default)]
+    pub tags: collections::HashMap<String, String>,
+    // the plant that the sensor is next to; added as a uuid tag
+    pub plant: Option<uuid::Uuid>,
+    // how often to sample while the plant is being watered; defaults to the global burst interval
+    // for moisture probes, and to not sampling faster for other sensors
+    pub burst_interval_seconds: Option<u64>,
+    // whether this is the moisture probe of the plant, whose voltage is checked for faults,
+    // compensated for the soil temperature and used to verify pump runs
+    #[serde(default)]
+    pub moisture: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Blackout {
+    #[serde(default)]
+    pub quiet_hours: Vec<QuietHours>,
+    #[serde(default)]
+    pub periods: Vec<BlackoutPeriod>,
+    #[serde(default)]
+    pub dates: Vec<chrono::NaiveDate>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PlantBlackout {
+    // whether to also apply the global blackout windows
+    #[serde(default = "default_true")]
+    pub inherit: bool,
+    #[serde(flatten)]
+    pub blackout: Blackout,
+}
+
+// wall-clock times; may wrap around midnight
+#[derive(Clone, Debug, Deserialize)]
+pub struct QuietHours {
+    pub from: chrono::NaiveTime,
+    pub to: chrono::NaiveTime,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BlackoutPeriod {
+    pub from: chrono::NaiveDateTime,
+    pub to: chrono::NaiveDateTime,
+}
+
+// pumps sharing a power supply
+#[derive(Clone, Debug, Deserialize)]
+pub struct PowerGroup {
+    pub max_running: usize,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Vacation {
+    // inclusive dates, in the timezone of the location
+    pub from: chrono::NaiveDate,
+    pub to: chrono::NaiveDate,
+    pub reservoir_liters: f64,
+    // how far back before the vacation to look when estimating water consumption
+    #[serde(default = "default_vacation_history_days")]
+    pub history_days: i64,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Plant {
     pub name: String,
     pub description: String,
+    // whether the plant should get water before others when rationing
+    #[serde(default)]
+    pub thirsty: bool,
     pub moisture: Moisture,
+    pub soil_temperature: Option<SoilTemperature>,
     pub pump: Pump,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Moisture {
-    #[serde(deserialize_with = "deserialize_moisture_channel")]
-    pub channel: MoistureChannel,
+    // shorthand for declaring the probe as a sensor, on the default bus
+    #[serde(default, deserialize_with = "deserialize_optional_moisture_channel")]
+    pub channel: Option<MoistureChannel>,
     pub voltage_dry: f64,
     pub voltage_wet: f64,
     // in meters
     pub min: f64,
     pub max: f64,
+    // how much the voltage changes per degree Celsius of soil temperature, at the same moisture
+    #[serde(default)]
+    pub temperature_coefficient: f64,
+    // the soil temperature at which voltage_dry and voltage_wet were measured
+    #[serde(default = "default_moisture_reference_temperature")]
+    pub reference_temperature: f64,
+    // override the global sample intervals of the channel shorthand
+    pub interval_seconds: Option<u64>,
+    pub burst_interval_seconds: Option<u64>,
+    #[serde(default)]
+    pub faults: MoistureFaults,
+}
+
+/// Limits beyond which moisture readings are considered to come from a faulty sensor.
+#[derive(Clone, Debug, Deserialize)]
+pub struct MoistureFaults {
+    // how far beyond voltage_dry and voltage_wet a reading may be; disconnected and shorted probes
+    // float to one of the rails
+    #[serde(default = "default_fault_margin_volts")]
+    pub margin_volts: f64,
+    // readings that vary less than this during the window come from a stuck sensor
+    #[serde(default = "default_fault_stuck_tolerance_volts")]
+    pub stuck_tolerance_volts: f64,
+    #[serde(default = "default_fault_stuck_window_seconds")]
+    pub stuck_window_seconds: u64,
+    // the largest plausible change between consecutive readings
+    #[serde(default = "default_fault_max_step_volts")]
+    pub max_step_volts: f64,
+    // how many plausible readings in a row clear a fault
+    #[serde(default = "default_fault_recovery_readings")]
+    pub recovery_readings: u32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SoilTemperature {
+    // the 1-Wire ID of a DS18B20 probe, like "28-0316a2795dff"
+    pub probe: String,
 }
 
 #[derive(Clone, Debug)]
@@ -53,17 +300,48 @@ pub struct MoistureChannel {
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Pump {
-    pub channel: u8,
+    // the line offset for the cdev backend, or the global pin number for the sysfs backend
+    pub channel: u32,
+    // overrides the default chip for the cdev backend
+    pub chip: Option<String>,
+    #[serde(default)]
+    pub active_low: bool,
     pub enabled: bool,
     pub schedule: Option<PumpSchedule>,
+    pub check: Option<PumpCheck>,
+    pub blackout: Option<PlantBlackout>,
+    pub power_group: Option<String>,
+    pub flow_ml_per_second: Option<f64>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct PumpSchedule {
-    pub start: String,
+    // a cron expression, in the timezone of the location
+    pub start: Option<String>,
+    #[serde(default)]
+    pub daylight_only: bool,
+    pub sun: Option<SunSchedule>,
     pub duration_seconds: u64,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct SunSchedule {
+    pub event: sun::Event,
+    #[serde(default)]
+    pub offset_minutes: i64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PumpCheck {
+    pub delay_seconds: u64,
+    #[serde(default = "default_pump_check_window_seconds")]
+    pub window_seconds: u64,
+    // as a fraction of the range between voltage_dry and voltage_wet
+    pub min_moisture_delta: f64,
+    #[serde(default)]
+    pub retry: bool,
+}
+
 impl Config {
     pub fn load() -> Result<Config, failure::Error> {
         let mut config = config_rs::Config::default();
@@ -73,11 +351,382 @@ impl Config {
         config.merge(config_rs::File::with_name("/etc/precip/config-secret").required(false))?;
         config.merge(config_rs::Environment::with_prefix("PRECIP"))?;
 
-        Ok(config.try_into::<Config>()?)
+        let mut config = config.try_into::<Config>()?;
+        config.declare_moisture_probes()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Declares the moisture probes that plants specify with the channel shorthand as sensors, like
+    // any other sensor
+    fn declare_moisture_probes(&mut self) -> Result<(), failure::Error> {
+        for (uuid, plant) in &self.plant {
+            let channel = match plant.moisture.channel {
+                Some(ref channel) => channel,
+                None => continue,
+            };
+            if self.sensor.contains_key(&plant.name) {
+                bail!(
+                    "The moisture probe of plant {:?} has the same name as a sensor",
+                    plant.name
+                );
+            }
+            self.sensor.insert(
+                plant.name.clone(),
+                Sensor {
+                    kind: sensors::SensorKind::Ads1115,
+                    bus: default_i2c_bus(),
+                    i2c_address: Some(channel.i2c_address),
+                    channel: Some(channel.analog_pin.to_string()),
+                    interval_seconds: plant.moisture.interval_seconds,
+                    measurement: PLANT_MEASUREMENT.to_owned(),
+                    fields: collections::HashMap::new(),
+                    tags: collections::HashMap::new(),
+                    plant: Some(*uuid),
+                    burst_interval_seconds: plant.moisture.burst_interval_seconds,
+                    moisture: true,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), failure::Error> {
+        // Timers can't tick every zero seconds
+        let sampling = &self.sampling;
+        for &(field, seconds) in &[
+            ("interval_seconds", sampling.interval_seconds),
+            ("burst_interval_seconds", sampling.burst_interval_seconds),
+            ("report_interval_seconds", sampling.report_interval_seconds),
+            ("index_interval_seconds", sampling.index_interval_seconds),
+            ("rollup_interval_seconds", sampling.rollup_interval_seconds),
+        ] {
+            if seconds == 0 {
+                bail!("The {} of the sampling has to be at least 1", field);
+            }
+        }
+        if self.db.health_check_interval_seconds == 0 {
+            bail!("The health_check_interval_seconds of the database has to be at least 1");
+        }
+        // Before the sensors that the channel shorthand of plants declares, for a clearer message
+        for plant in self.plant.values() {
+            for &(field, seconds) in &[
+                ("interval_seconds", plant.moisture.interval_seconds),
+                (
+                    "burst_interval_seconds",
+                    plant.moisture.burst_interval_seconds,
+                ),
+            ] {
+                if seconds == Some(0) {
+                    bail!(
+                        "The moisture {} of plant {:?} has to be at least 1",
+                        field,
+                        plant.name
+                    );
+                }
+            }
+        }
+        for (name, sensor) in &self.sensor {
+            for &(field, seconds) in &[
+                ("interval_seconds", sensor.interval_seconds),
+                ("burst_interval_seconds", sensor.burst_interval_seconds),
+            ] {
+                if seconds == Some(0) {
+                    bail!("The {} of sensor {:?} has to be at least 1", field, name);
+                }
+            }
+        }
+
+        for (name, sensor) in &self.sensor {
+            if !sensor.moisture {
+                continue;
+            }
+            if sensor.kind != sensors::SensorKind::Ads1115 {
+                bail!("The moisture probe {:?} has to be an ads1115", name);
+            }
+            if sensor.measurement != PLANT_MEASUREMENT {
+                bail!(
+                    "The moisture probe {:?} has to use the {:?} measurement",
+                    name,
+                    PLANT_MEASUREMENT
+                );
+            }
+            match sensor.plant {
+                Some(ref uuid) if self.plant.contains_key(uuid) => {}
+                _ => bail!("The moisture probe {:?} has to belong to a plant", name),
+            }
+        }
+        for (uuid, plant) in &self.plant {
+            let probes = self
+                .sensor
+                .values()
+                .filter(|s| s.moisture && s.plant == Some(*uuid))
+                .count();
+            if probes != 1 {
+                bail!(
+                    "Plant {:?} has {} moisture probes, but needs exactly one",
+                    plant.name,
+                    probes
+                );
+            }
+        }
+        for (name, group) in &self.power_group {
+            // No pump of the group could ever run
+            if group.max_running == 0 {
+                bail!(
+                    "The max_running of power group {:?} has to be at least 1",
+                    name
+                );
+            }
+        }
+        if let Some(ref vacation) = self.vacation {
+            // The usual consumption is averaged over the days
+            if vacation.history_days < 1 {
+                bail!("The history_days of the vacation has to be at least 1");
+            }
+            // The water of a pump without a flow rate could not be counted against the reservoir
+            for plant in self.plant.values() {
+                match plant.pump.flow_ml_per_second {
+                    Some(flow) if flow > 0.0 => {}
+                    _ => bail!(
+                        "The pump of plant {:?} needs a positive flow_ml_per_second for the vacation",
+                        plant.name
+                    ),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_db_mode() -> db::influx::Mode {
+    db::influx::Mode::Failover
+}
+
+fn default_db_health_check_interval_seconds() -> u64 {
+    30
+}
+
+fn default_db_timeout_seconds() -> u64 {
+    10
+}
+
+fn default_state_dir() -> String {
+    "/var/lib/precip".to_owned()
+}
+
+fn default_sensors() -> collections::HashMap<String, Sensor> {
+    let mut sensors = collections::HashMap::new();
+    sensors.insert(
+        "climate".to_owned(),
+        Sensor {
+            kind: sensors::SensorKind::Bmp280,
+            bus: default_i2c_bus(),
+            i2c_address: None,
+            channel: None,
+            interval_seconds: None,
+            measurement: "global".to_owned(),
+            fields: collections::HashMap::new(),
+            tags: collections::HashMap::new(),
+            plant: None,
+            burst_interval_seconds: None,
+            moisture: false,
+        },
+    );
+    sensors
+}
+
+fn default_i2c_bus() -> String {
+    sensors::registry::DEFAULT_BUS.to_owned()
+}
+
+fn default_sample_interval_seconds() -> u64 {
+    1
+}
+
+fn default_burst_interval_seconds() -> u64 {
+    1
+}
+
+fn default_burst_linger_seconds() -> u64 {
+    300
+}
+
+fn default_report_interval_seconds() -> u64 {
+    60
+}
+
+fn default_index_interval_seconds() -> u64 {
+    3600
+}
+
+fn default_rollup_interval_seconds() -> u64 {
+    60
+}
+
+fn default_retention_raw_days() -> u32 {
+    30
+}
+
+fn default_retention_downsample_interval_minutes() -> u32 {
+    5
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_vacation_history_days() -> i64 {
+    14
+}
+
+fn default_moisture_reference_temperature() -> f64 {
+    20.0
+}
+
+fn default_fault_margin_volts() -> f64 {
+    0.2
+}
+
+fn default_fault_stuck_tolerance_volts() -> f64 {
+    0.0005
+}
+
+fn default_fault_stuck_window_seconds() -> u64 {
+    1800
+}
+
+fn default_fault_max_step_volts() -> f64 {
+    0.5
+}
+
+fn default_fault_recovery_readings() -> u32 {
+    10
+}
+
+fn default_pump_check_window_seconds() -> u64 {
+    30
+}
+
+impl Default for Location {
+    fn default() -> Self {
+        Location {
+            timezone: default_timezone(),
+            latitude: None,
+            longitude: None,
+        }
+    }
+}
+
+impl Default for Api {
+    fn default() -> Self {
+        Api {
+            listen: net::SocketAddr::from(([127, 0, 0, 1], 8080)),
+        }
+    }
+}
+
+impl Default for Gpio {
+    fn default() -> Self {
+        Gpio {
+            backend: pumps::Backend::Cdev,
+            chip: "/dev/gpiochip0".to_owned(),
+        }
+    }
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Sampling {
+            interval_seconds: default_sample_interval_seconds(),
+            burst_interval_seconds: default_burst_interval_seconds(),
+            burst_linger_seconds: default_burst_linger_seconds(),
+            report_interval_seconds: default_report_interval_seconds(),
+            index_interval_seconds: default_index_interval_seconds(),
+            rollup_interval_seconds: default_rollup_interval_seconds(),
+        }
     }
 }
 
-fn deserialize_moisture_channel<'de, D>(deserializer: D) -> Result<MoistureChannel, D::Error>
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            raw_days: default_retention_raw_days(),
+            store_raw: default_true(),
+            downsample_interval_minutes: default_retention_downsample_interval_minutes(),
+            manage: default_true(),
+        }
+    }
+}
+
+impl Default for MoistureFaults {
+    fn default() -> Self {
+        MoistureFaults {
+            margin_volts: default_fault_margin_volts(),
+            stuck_tolerance_volts: default_fault_stuck_tolerance_volts(),
+            stuck_window_seconds: default_fault_stuck_window_seconds(),
+            max_step_volts: default_fault_max_step_volts(),
+            recovery_readings: default_fault_recovery_readings(),
+        }
+    }
+}
+
+impl Location {
+    pub fn sun_location(&self) -> Option<sun::Location> {
+        match (self.latitude, self.longitude) {
+            (Some(latitude), Some(longitude)) => Some(sun::Location {
+                latitude,
+                longitude,
+            }),
+            _ => None,
+        }
+    }
+}
+
+// Like the local time that schedules used to be evaluated in: the TZ variable, or the zone that
+// /etc/localtime links to, or UTC
+fn default_timezone() -> chrono_tz::Tz {
+    let name = env::var("TZ").ok().or_else(|| {
+        fs::read_link("/etc/localtime").ok().and_then(|target| {
+            let target = target.to_string_lossy().into_owned();
+            target
+                .find("zoneinfo/")
+                .map(|i| target[i + "zoneinfo/".len()..].to_owned())
+        })
+    });
+    name.and_then(|name| name.trim_left_matches(':').parse().ok())
+        .unwrap_or(chrono_tz::UTC)
+}
+
+fn deserialize_timezone<'de, D>(deserializer: D) -> Result<chrono_tz::Tz, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let raw = <String as serde::Deserialize>::deserialize(deserializer)?;
+    raw.parse::<chrono_tz::Tz>().map_err(|e| {
+        serde::de::Error::invalid_value(
+            serde::de::Unexpected::Str(&raw),
+            &format!("a valid IANA timezone name: {}", e).as_str(),
+        )
+    })
+}
+
+fn deserialize_optional_i2c_address<'de, D>(deserializer: D) -> Result<Option<u16>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let raw = <String as serde::Deserialize>::deserialize(deserializer)?;
+    u16::from_str_radix(&raw, 16).map(Some).map_err(|e| {
+        serde::de::Error::invalid_value(
+            serde::de::Unexpected::Str(&raw),
+            &format!("a valid hexadecimal integer: {}", e).as_str(),
+        )
+    })
+}
+
+fn deserialize_optional_moisture_channel<'de, D>(
+    deserializer: D,
+) -> Result<Option<MoistureChannel>, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
@@ -97,10 +746,10 @@ where
                 &format!("a valid decimal integer: {}", e).as_str(),
             )
         })?;
-        Ok(MoistureChannel {
+        Ok(Some(MoistureChannel {
             i2c_address,
             analog_pin,
-        })
+        }))
     } else {
         Err(serde::de::Error::invalid_value(
             serde::de::Unexpected::Str(&raw),
@@ -108,23 +757,3 @@ where
         ))
     }
 }
-
-impl From<DbCredentials> for influent::client::Credentials<'static> {
-    fn from(credentials: DbCredentials) -> Self {
-        influent::client::Credentials {
-            username: leak_static_str(credentials.username),
-            password: leak_static_str(credentials.password),
-            database: leak_static_str(credentials.database),
-        }
-    }
-}
-
-fn leak_static_str(s: String) -> &'static str {
-    // TODO: this is a hack due to an annoyance in the influent API.  With async/await in std, this
-    // should be avoidable, due to better lifetime support in async code.
-    unsafe {
-        let ret = ::std::mem::transmute(&s as &str);
-        ::std::mem::forget(s);
-        ret
-    }
-}
@@ -0,0 +1,1322 @@
+//! A small client for the InfluxDB HTTP API that owns its credentials and runs on tokio.
+//!
+//! Both the InfluxDB 1.x API with InfluxQL queries and the InfluxDB 2.x API with Flux queries are
+//! supported.
+
+use std::collections;
+use std::fmt::Write;
+use std::sync;
+use std::time;
+
+use chrono;
+use failure;
+use futures;
+use hyper;
+use serde_json;
+use tokio;
+
+use futures::prelude::async;
+use futures::prelude::await;
+
+/// How many failed writes to keep per host in the `All` mode, until the host is back.
+const MAX_BACKLOG: usize = 10_000;
+
+#[derive(Clone, Debug)]
+pub enum Auth {
+    /// InfluxDB 1.x
+    V1 {
+        username: String,
+        password: String,
+        database: String,
+    },
+    /// InfluxDB 2.x
+    V2 {
+        token: String,
+        org: String,
+        bucket: String,
+    },
+}
+
+/// How requests are spread over the hosts.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Send everything to the first healthy host, in the order they are configured.
+    Failover,
+    /// Write to every host, for replication; queries still fail over.
+    All,
+}
+
+#[derive(Clone)]
+pub struct Client {
+    auth: sync::Arc<Auth>,
+    mode: Mode,
+    hosts: sync::Arc<Vec<String>>,
+    // indexed like the hosts
+    stats: sync::Arc<sync::Mutex<Vec<HostStats>>>,
+    // writes that failed on a host, indexed like the hosts
+    backlogs: sync::Arc<sync::Mutex<Vec<collections::VecDeque<sync::Arc<Request>>>>>,
+    http: hyper::Client<hyper::client::HttpConnector>,
+    // how long a host may take to respond, including the body
+    timeout: time::Duration,
+}
+
+/// Request and error counts of a host, and how long it takes to respond.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HostStats {
+    pub host: String,
+    pub healthy: bool,
+    pub requests: u64,
+    pub errors: u64,
+    pub last_error: Option<String>,
+    pub last_latency_ms: Option<f64>,
+    pub mean_latency_ms: Option<f64>,
+    // health checks, which aren't counted as requests
+    pub probes: u64,
+    pub probe_errors: u64,
+    pub last_probe_latency_ms: Option<f64>,
+    // writes that failed on the host, to be replayed once it's back
+    pub backlog: usize,
+    // writes that were dropped from a full backlog
+    pub dropped: u64,
+}
+
+/// A point in the line protocol.
+#[derive(Clone, Debug)]
+pub struct Point {
+    measurement: String,
+    tags: Vec<(String, String)>,
+    fields: Vec<(String, Value)>,
+    // in nanoseconds since the epoch
+    timestamp: Option<i64>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    Float(f64),
+    Integer(i64),
+    Boolean(bool),
+    String(String),
+}
+
+/// A table of query results, in the shape of an InfluxQL series.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Series {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub tags: collections::HashMap<String, String>,
+    pub columns: Vec<String>,
+    #[serde(default)]
+    pub values: Vec<Vec<serde_json::Value>>,
+}
+
+/// A row of a series, with its values looked up by column name.
+#[derive(Clone, Copy, Debug)]
+pub struct Row<'a> {
+    columns: &'a [String],
+    values: &'a [serde_json::Value],
+}
+
+struct Request {
+    method: hyper::Method,
+    endpoint: &'static str,
+    params: Vec<(&'static str, String)>,
+    content_type: &'static str,
+    body: hyper::Chunk,
+}
+
+enum HostError {
+    // the host is up, but rejected the request
+    Request(failure::Error),
+    // the host is down or broken
+    Host(failure::Error),
+}
+
+#[derive(Serialize)]
+struct FluxQuery {
+    query: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    dialect: FluxDialect,
+}
+
+#[derive(Serialize)]
+struct FluxDialect {
+    header: bool,
+    annotations: Vec<&'static str>,
+}
+
+impl Client {
+    pub fn new(
+        auth: Auth,
+        mode: Mode,
+        hosts: Vec<String>,
+        timeout: time::Duration,
+    ) -> Result<Self, failure::Error> {
+        if hosts.is_empty() {
+            bail!("No InfluxDB hosts are configured");
+        }
+
+        let hosts = hosts
+            .into_iter()
+            .map(|h| h.trim_right_matches('/').to_owned())
+            .collect::<Vec<_>>();
+        let stats = hosts
+            .iter()
+            .map(|host| HostStats {
+                host: host.clone(),
+                healthy: true,
+                requests: 0,
+                errors: 0,
+                last_error: None,
+                last_latency_ms: None,
+                mean_latency_ms: None,
+                probes: 0,
+                probe_errors: 0,
+                last_probe_latency_ms: None,
+                backlog: 0,
+                dropped: 0,
+            })
+            .collect();
+        let backlogs = hosts.iter().map(|_| collections::VecDeque::new()).collect();
+
+        Ok(Client {
+            auth: sync::Arc::new(auth),
+            mode,
+            hosts: sync::Arc::new(hosts),
+            stats: sync::Arc::new(sync::Mutex::new(stats)),
+            backlogs: sync::Arc::new(sync::Mutex::new(backlogs)),
+            http: hyper::Client::new(),
+            timeout,
+        })
+    }
+
+    pub fn auth(&self) -> &Auth {
+        &self.auth
+    }
+
+    pub fn stats(&self) -> Vec<HostStats> {
+        let backlogs = self.backlogs.lock().unwrap();
+        let mut stats = self.stats.lock().unwrap().clone();
+        for (stats, backlog) in stats.iter_mut().zip(backlogs.iter()) {
+            stats.backlog = backlog.len();
+        }
+        stats
+    }
+
+    /// Pings every host, so that hosts that failed are used again once they are back, and
+    /// replays the writes that failed on them.
+    pub fn check_health(&self) -> Box<futures::Future<Item = (), Error = failure::Error> + Send> {
+        use futures::Future;
+
+        Box::new(
+            futures::future::join_all(
+                (0..self.hosts.len())
+                    .map(|i| check_host(self.clone(), i).then(Ok))
+                    .collect::<Vec<_>>(),
+            )
+            .map(|_: Vec<Result<(), failure::Error>>| ()),
+        )
+    }
+
+    // Keeps a write that failed on a host, dropping the oldest one if the backlog is full
+    fn keep(&self, index: usize, request: sync::Arc<Request>) {
+        let mut backlogs = self.backlogs.lock().unwrap();
+        let backlog = &mut backlogs[index];
+        if backlog.len() >= MAX_BACKLOG {
+            backlog.pop_front();
+            self.stats.lock().unwrap()[index].dropped += 1;
+        }
+        backlog.push_back(request);
+    }
+
+    pub fn write(
+        &self,
+        points: &[Point],
+    ) -> Box<futures::Future<Item = (), Error = failure::Error> + Send> {
+        self.write_to(points, None)
+    }
+
+    /// Writes to a retention policy other than the default one on InfluxDB 1.x.
+    pub fn write_to(
+        &self,
+        points: &[Point],
+        policy: Option<&str>,
+    ) -> Box<futures::Future<Item = (), Error = failure::Error> + Send> {
+        use futures::Future;
+
+        let body = points
+            .iter()
+            .map(Point::to_line)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let endpoint = match *self.auth {
+            Auth::V1 { .. } => "write",
+            Auth::V2 { .. } => "api/v2/write",
+        };
+
+        let mut params = vec![("precision", "ns".to_owned())];
+        params.extend(policy.map(|policy| ("rp", policy.to_owned())));
+        let request = Request {
+            method: hyper::Method::POST,
+            endpoint,
+            params,
+            content_type: "text/plain; charset=utf-8",
+            body: hyper::Chunk::from(body),
+        };
+
+        match self.mode {
+            Mode::Failover => Box::new(send(self.clone(), request).map(|_| ())),
+            Mode::All => send_all(self.clone(), request),
+        }
+    }
+
+    /// Runs an InfluxQL query against InfluxDB 1.x, and returns the series of the first statement;
+    /// times are in nanoseconds.
+    pub fn influxql(
+        &self,
+        query: String,
+    ) -> Box<futures::Future<Item = Vec<Series>, Error = failure::Error> + Send> {
+        use futures::Future;
+
+        // Queries like SELECT INTO write data, so they have to be POSTed
+        Box::new(
+            send(
+                self.clone(),
+                Request {
+                    method: hyper::Method::POST,
+                    endpoint: "query",
+                    params: vec![("q", query), ("epoch", "ns".to_owned())],
+                    content_type: "text/plain; charset=utf-8",
+                    body: hyper::Chunk::from(""),
+                },
+            )
+            .and_then(|body| parse_influxql_results(&body)),
+        )
+    }
+
+    /// Runs a Flux query against InfluxDB 2.x, and returns its tables; times are RFC 3339 strings.
+    pub fn flux(
+        &self,
+        query: String,
+    ) -> Box<futures::Future<Item = Vec<Series>, Error = failure::Error> + Send> {
+        use futures::Future;
+
+        let body = match serde_json::to_string(&FluxQuery {
+            query,
+            kind: "flux",
+            dialect: FluxDialect {
+                header: true,
+                annotations: vec!["datatype", "group"],
+            },
+        }) {
+            Ok(body) => body,
+            Err(e) => return Box::new(futures::future::err(e.into())),
+        };
+
+        Box::new(
+            send(
+                self.clone(),
+                Request {
+                    method: hyper::Method::POST,
+                    endpoint: "api/v2/query",
+                    params: Vec::new(),
+                    content_type: "application/json",
+                    body: hyper::Chunk::from(body),
+                },
+            )
+            .and_then(|body| parse_flux_csv(&String::from_utf8_lossy(&body))),
+        )
+    }
+
+    fn uri(&self, host: &str, endpoint: &str, params: &[(&str, String)]) -> String {
+        let mut uri = match *self.auth {
+            Auth::V1 {
+                ref username,
+                ref password,
+                ref database,
+            } => format!(
+                "{}/{}?db={}&u={}&p={}",
+                host,
+                endpoint,
+                encode(database),
+                encode(username),
+                encode(password)
+            ),
+            Auth::V2 {
+                ref org,
+                ref bucket,
+                ..
+            } => format!(
+                "{}/{}?org={}&bucket={}",
+                host,
+                endpoint,
+                encode(org),
+                encode(bucket)
+            ),
+        };
+        for &(key, ref value) in params {
+            write!(uri, "&{}={}", key, encode(value)).unwrap();
+        }
+        uri
+    }
+}
+
+/// Sends a request to the first healthy host that responds, or to the unhealthy ones if none do.
+#[async]
+fn send(client: Client, request: Request) -> Result<hyper::Chunk, failure::Error> {
+    let request = sync::Arc::new(request);
+    let order = {
+        let stats = client.stats.lock().unwrap();
+        let mut order = (0..client.hosts.len()).collect::<Vec<_>>();
+        // stable, so the configured order is kept otherwise
+        order.sort_by_key(|&i| !stats[i].healthy);
+        order
+    };
+
+    let mut last_error = None;
+    for i in order {
+        match await!(send_to(client.clone(), i, request.clone())) {
+            Ok(content) => return Ok(content),
+            // Another host wouldn't accept the request either
+            Err(HostError::Request(e)) => return Err(e),
+            Err(HostError::Host(e)) => last_error = Some(e),
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| format_err!("No InfluxDB hosts are configured")))
+}
+
+/// Sends a request to every healthy host at once, and succeeds if any of them accepted it.  Hosts
+/// that are down get the request once they are back, without waiting for them in the meantime.
+fn send_all(
+    client: Client,
+    request: Request,
+) -> Box<futures::Future<Item = (), Error = failure::Error> + Send> {
+    use futures::Future;
+
+    let request = sync::Arc::new(request);
+    let healthy = client
+        .stats
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|stats| stats.healthy)
+        .collect::<Vec<_>>();
+    let mut kept = false;
+    let mut requests = Vec::new();
+    for (i, &healthy) in healthy.iter().enumerate() {
+        if healthy {
+            requests.push(
+                send_to(client.clone(), i, request.clone()).then(move |result| Ok((i, result))),
+            );
+        } else {
+            // check_health delivers the backlog once the host responds again
+            client.keep(i, request.clone());
+            kept = true;
+        }
+    }
+    Box::new(futures::future::join_all(requests).and_then(
+        move |results: Vec<(usize, Result<hyper::Chunk, HostError>)>| {
+            for &(i, ref result) in &results {
+                if let Err(HostError::Host(_)) = *result {
+                    client.keep(i, request.clone());
+                    kept = true;
+                }
+            }
+            if results.iter().any(|&(_, ref r)| r.is_ok()) {
+                return Ok(());
+            }
+
+            let mut errors = results
+                .into_iter()
+                .filter_map(|(_, r)| match r {
+                    Ok(_) => None,
+                    Err(HostError::Request(e)) | Err(HostError::Host(e)) => Some(e.to_string()),
+                })
+                .collect::<Vec<_>>();
+            if errors.is_empty() {
+                errors.push("every host is down".to_owned());
+            }
+            if kept {
+                bail!(
+                    "All InfluxDB hosts failed, the write is kept until they are back: {}",
+                    errors.join("; ")
+                )
+            }
+            bail!("All InfluxDB hosts failed: {}", errors.join("; "))
+        },
+    ))
+}
+
+/// Pings a host, and replays the writes that failed on it if it responds.  Pings are kept apart
+/// from the request stats, so that they don't skew the latency of actual requests.
+#[async]
+fn check_host(client: Client, index: usize) -> Result<(), failure::Error> {
+    let request = sync::Arc::new(Request {
+        method: hyper::Method::GET,
+        endpoint: "ping",
+        params: Vec::new(),
+        content_type: "text/plain; charset=utf-8",
+        body: hyper::Chunk::from(""),
+    });
+    let host = client.hosts[index].clone();
+    let started = time::Instant::now();
+    let result = await!(exchange(client.clone(), host, request));
+
+    let up = {
+        let mut stats = client.stats.lock().unwrap();
+        let stats = &mut stats[index];
+        stats.probes += 1;
+        stats.last_probe_latency_ms = Some(millis(started.elapsed()));
+        match result {
+            Ok(_) => {
+                stats.healthy = true;
+                true
+            }
+            Err(HostError::Request(ref e)) => {
+                stats.probe_errors += 1;
+                stats.last_error = Some(e.to_string());
+                false
+            }
+            Err(HostError::Host(ref e)) => {
+                stats.probe_errors += 1;
+                stats.last_error = Some(e.to_string());
+                stats.healthy = false;
+                false
+            }
+        }
+    };
+
+    if up {
+        await!(replay_backlog(client, index))?;
+    }
+    Ok(())
+}
+
+/// Sends the writes that failed on a host to it again, in order, until one fails.
+#[async]
+fn replay_backlog(client: Client, index: usize) -> Result<(), failure::Error> {
+    loop {
+        let request = match client.backlogs.lock().unwrap()[index].pop_front() {
+            Some(request) => request,
+            None => break,
+        };
+        match await!(send_to(client.clone(), index, request.clone())) {
+            // A write that the host rejects once would be rejected again
+            Ok(_) | Err(HostError::Request(_)) => {}
+            Err(HostError::Host(e)) => {
+                client.backlogs.lock().unwrap()[index].push_front(request);
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[async]
+fn send_to(
+    client: Client,
+    index: usize,
+    request: sync::Arc<Request>,
+) -> Result<hyper::Chunk, HostError> {
+    let host = client.hosts[index].clone();
+    let started = time::Instant::now();
+    let result = await!(exchange(client.clone(), host.clone(), request));
+
+    let latency_ms = millis(started.elapsed());
+    let mut stats = client.stats.lock().unwrap();
+    let stats = &mut stats[index];
+    stats.requests += 1;
+    stats.last_latency_ms = Some(latency_ms);
+    stats.mean_latency_ms = Some(
+        stats.mean_latency_ms.unwrap_or(0.0)
+            + (latency_ms - stats.mean_latency_ms.unwrap_or(0.0)) / stats.requests as f64,
+    );
+    match result {
+        Ok(_) => stats.healthy = true,
+        Err(HostError::Request(ref e)) => {
+            stats.errors += 1;
+            stats.last_error = Some(e.to_string());
+        }
+        Err(HostError::Host(ref e)) => {
+            stats.errors += 1;
+            stats.last_error = Some(e.to_string());
+            stats.healthy = false;
+        }
+    }
+    result
+}
+
+/// Exchanges a request with a host, which counts as down if it doesn't respond in time.
+fn exchange(
+    client: Client,
+    host: String,
+    request: sync::Arc<Request>,
+) -> Box<futures::Future<Item = hyper::Chunk, Error = HostError> + Send> {
+    use futures::Future;
+
+    let timeout = client.timeout;
+    Box::new(
+        tokio::timer::Timeout::new(exchange_once(client, host.clone(), request), timeout).map_err(
+            move |e| {
+                let elapsed = e.is_elapsed();
+                match e.into_inner() {
+                    Some(e) => e,
+                    None if elapsed => {
+                        HostError::Host(format_err!("{}: timed out after {:?}", host, timeout))
+                    }
+                    None => HostError::Host(format_err!("{}: the request timer failed", host)),
+                }
+            },
+        ),
+    )
+}
+
+#[async]
+fn exchange_once(
+    client: Client,
+    host: String,
+    request: sync::Arc<Request>,
+) -> Result<hyper::Chunk, HostError> {
+    use futures::Stream;
+
+    let mut builder = hyper::Request::builder();
+    builder
+        .method(request.method.clone())
+        .uri(
+            client
+                .uri(&host, request.endpoint, &request.params)
+                .as_str(),
+        )
+        .header(hyper::header::CONTENT_TYPE, request.content_type);
+    if let Auth::V2 { ref token, .. } = *client.auth {
+        builder.header(
+            hyper::header::AUTHORIZATION,
+            format!("Token {}", token).as_str(),
+        );
+    }
+    let http_request = builder
+        .body(hyper::Body::from(request.body.clone()))
+        .map_err(|e| HostError::Request(e.into()))?;
+
+    let response = await!(client.http.request(http_request))
+        .map_err(|e| HostError::Host(format_err!("{}: {}", host, e)))?;
+    let status = response.status();
+    let content = await!(response.into_body().concat2())
+        .map_err(|e| HostError::Host(format_err!("{}: {}", host, e)))?;
+
+    if status.is_success() {
+        Ok(content)
+    } else if status.is_client_error() {
+        Err(HostError::Request(format_err!(
+            "InfluxDB request failed with {}: {}",
+            status,
+            String::from_utf8_lossy(&content)
+        )))
+    } else {
+        Err(HostError::Host(format_err!(
+            "{} responded with {}: {}",
+            host,
+            status,
+            String::from_utf8_lossy(&content)
+        )))
+    }
+}
+
+impl Point {
+    pub fn new(measurement: &str) -> Self {
+        Point {
+            measurement: measurement.to_owned(),
+            tags: Vec::new(),
+            fields: Vec::new(),
+            timestamp: None,
+        }
+    }
+
+    pub fn tag(mut self, key: &str, value: String) -> Self {
+        self.tags.push((key.to_owned(), value));
+        self
+    }
+
+    pub fn field(mut self, key: &str, value: Value) -> Self {
+        self.fields.push((key.to_owned(), value));
+        self
+    }
+
+    pub fn timestamp(mut self, nanoseconds: i64) -> Self {
+        self.timestamp = Some(nanoseconds);
+        self
+    }
+
+    pub fn to_line(&self) -> String {
+        let mut line = escape(&self.measurement, &[',', ' ']);
+
+        // InfluxDB prefers tags sorted by key
+        let mut tags = self.tags.iter().collect::<Vec<_>>();
+        tags.sort();
+        for &(ref key, ref value) in tags {
+            write!(
+                line,
+                ",{}={}",
+                escape(key, &[',', '=', ' ']),
+                escape(value, &[',', '=', ' '])
+            )
+            .unwrap();
+        }
+
+        for (i, &(ref key, ref value)) in self.fields.iter().enumerate() {
+            line.push(if i == 0 { ' ' } else { ',' });
+            line.push_str(&escape(key, &[',', '=', ' ']));
+            line.push('=');
+            match *value {
+                Value::Float(v) => write!(line, "{:?}", v).unwrap(),
+                Value::Integer(v) => write!(line, "{}i", v).unwrap(),
+                Value::Boolean(v) => write!(line, "{}", v).unwrap(),
+                Value::String(ref v) => write!(line, "\"{}\"", escape(v, &['"', '\\'])).unwrap(),
+            }
+        }
+
+        if let Some(timestamp) = self.timestamp {
+            write!(line, " {}", timestamp).unwrap();
+        }
+        line
+    }
+}
+
+fn escape(s: &str, special: &[char]) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        if special.contains(&c) {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+impl Series {
+    pub fn rows<'a>(&'a self) -> impl Iterator<Item = Row<'a>> + 'a {
+        self.values.iter().map(move |values| Row {
+            columns: &self.columns,
+            values,
+        })
+    }
+}
+
+// Missing columns are treated like nulls, since Flux leaves out the columns that InfluxQL fills
+// with nulls
+impl<'a> Row<'a> {
+    pub fn f64(&self, column: &str) -> Result<Option<f64>, failure::Error> {
+        match self.value(column) {
+            None => Ok(None),
+            Some(&serde_json::Value::Number(ref n)) => Ok(n.as_f64()),
+            Some(value) => bail!("Expected a number in column {:?}: {}", column, value),
+        }
+    }
+
+    pub fn bool(&self, column: &str) -> Result<Option<bool>, failure::Error> {
+        match self.value(column) {
+            None => Ok(None),
+            Some(&serde_json::Value::Bool(b)) => Ok(Some(b)),
+            Some(value) => bail!("Expected a boolean in column {:?}: {}", column, value),
+        }
+    }
+
+    /// A number, or a boolean as 0 or 1; strings like tags are treated like nulls.
+    pub fn numeric(&self, column: &str) -> Option<f64> {
+        match self.value(column) {
+            Some(&serde_json::Value::Number(ref n)) => n.as_f64(),
+            Some(&serde_json::Value::Bool(b)) => Some(if b { 1.0 } else { 0.0 }),
+            _ => None,
+        }
+    }
+
+    pub fn str(&self, column: &str) -> Result<Option<&'a str>, failure::Error> {
+        match self.value(column) {
+            None => Ok(None),
+            Some(&serde_json::Value::String(ref s)) => Ok(Some(s)),
+            Some(value) => bail!("Expected a string in column {:?}: {}", column, value),
+        }
+    }
+
+    /// A time, either in nanoseconds since the epoch or in RFC 3339 format.
+    pub fn time(
+        &self,
+        column: &str,
+    ) -> Result<Option<chrono::DateTime<chrono::Utc>>, failure::Error> {
+        use chrono::TimeZone;
+
+        match self.value(column) {
+            None => Ok(None),
+            Some(&serde_json::Value::Number(ref n)) => match n.as_i64() {
+                Some(ns) if ns >= 0 => Ok(Some(
+                    chrono::Utc.timestamp(ns / 1_000_000_000, (ns % 1_000_000_000) as u32),
+                )),
+                _ => bail!("Expected a time in column {:?}: {}", column, n),
+            },
+            Some(&serde_json::Value::String(ref s)) => Ok(Some(s.parse()?)),
+            Some(value) => bail!("Expected a time in column {:?}: {}", column, value),
+        }
+    }
+
+    fn value(&self, column: &str) -> Option<&'a serde_json::Value> {
+        self.columns
+            .iter()
+            .position(|c| c == column)
+            .and_then(|i| self.values.get(i))
+            .filter(|v| !v.is_null())
+    }
+}
+
+/// Quotes a string for use in a Flux query.
+pub fn flux_string(s: &str) -> String {
+    format!("\"{}\"", escape(s, &['"', '\\']))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct QueryResults {
+    #[serde(default)]
+    results: Vec<QueryResult>,
+    // when the query as a whole failed, like when it doesn't parse
+    error: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct QueryResult {
+    statement_id: Option<u32>,
+    error: Option<String>,
+    #[serde(default)]
+    series: Vec<Series>,
+}
+
+/// Decodes an InfluxQL response, failing if any of the statements failed.
+fn parse_influxql_results(body: &[u8]) -> Result<Vec<Series>, failure::Error> {
+    let results: QueryResults = serde_json::from_slice(body)?;
+    if let Some(error) = results.error.as_ref().or_else(|| {
+        results
+            .results
+            .iter()
+            .filter_map(|r| r.error.as_ref())
+            .next()
+    }) {
+        bail!("query failed: {}", error);
+    }
+
+    Ok(results
+        .results
+        .into_iter()
+        .find(|r| r.statement_id == Some(0))
+        .map_or_else(Vec::new, |r| r.series))
+}
+
+/// Decodes the annotated CSV that Flux responds with; the columns in the group key of a table
+/// become its tags, and values are decoded by the declared data types of their columns.
+fn parse_flux_csv(body: &str) -> Result<Vec<Series>, failure::Error> {
+    let mut result = Vec::<Series>::new();
+    let mut datatypes = Vec::new();
+    let mut group = Vec::new();
+    let mut header: Option<Vec<String>> = None;
+    let mut table: Option<String> = None;
+
+    for line in body.lines() {
+        let line = line.trim_right_matches('\r');
+        if line.is_empty() {
+            // Tables with different columns are separated by an empty line
+            header = None;
+            datatypes.clear();
+            group.clear();
+            continue;
+        }
+
+        let cells = split_csv_line(line);
+        if cells[0] == "#datatype" {
+            datatypes = cells;
+            continue;
+        } else if cells[0] == "#group" {
+            group = cells.iter().map(|c| c == "true").collect();
+            continue;
+        } else if cells[0].starts_with('#') {
+            continue;
+        }
+
+        if header.is_none() {
+            header = Some(cells);
+            table = None;
+            continue;
+        }
+        let columns = header.as_ref().unwrap();
+        let cell = |name: &str| {
+            columns
+                .iter()
+                .position(|c| c == name)
+                .and_then(|i| cells.get(i))
+                .map(|c| c.as_str())
+        };
+        let is_value = |i: usize, column: &str| {
+            !column.is_empty()
+                && column != "result"
+                && column != "table"
+                && !group.get(i).cloned().unwrap_or(false)
+        };
+
+        if let Some(error) = cell("error") {
+            bail!("query failed: {}", error);
+        }
+
+        if table.is_none() || table.as_ref().map(|t| t.as_str()) != cell("table") {
+            table = cell("table").map(|t| t.to_owned());
+            let mut series = Series {
+                name: cell("_measurement").unwrap_or("").to_owned(),
+                ..Series::default()
+            };
+            for (i, column) in columns.iter().enumerate() {
+                if is_value(i, column) {
+                    series.columns.push(column.clone());
+                } else if !column.is_empty() && column != "result" && column != "table" {
+                    series
+                        .tags
+                        .insert(column.clone(), cells.get(i).cloned().unwrap_or_default());
+                }
+            }
+            result.push(series);
+        }
+
+        let row = columns
+            .iter()
+            .enumerate()
+            .filter(|&(i, column)| is_value(i, column))
+            .map(|(i, column)| {
+                flux_value(
+                    cells.get(i).map_or("", |c| c.as_str()),
+                    datatypes.get(i).map_or("", |d| d.as_str()),
+                )
+                .map_err(|e| format_err!("column {:?}: {}", column, e))
+            })
+            .collect::<Result<Vec<_>, failure::Error>>()?;
+        result.last_mut().unwrap().values.push(row);
+    }
+
+    Ok(result)
+}
+
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut cells = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                cells.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => cells.push(String::new()),
+            c => cells.last_mut().unwrap().push(c),
+        }
+    }
+    cells
+}
+
+// Times stay strings, and are parsed as RFC 3339 timestamps where needed; like other values
+// without a numeric or boolean data type
+fn flux_value(cell: &str, datatype: &str) -> Result<serde_json::Value, failure::Error> {
+    if cell.is_empty() {
+        return Ok(serde_json::Value::Null);
+    }
+
+    let invalid = || format_err!("invalid {} value {:?}", datatype, cell);
+    Ok(match datatype {
+        "boolean" => match cell {
+            "true" => serde_json::Value::Bool(true),
+            "false" => serde_json::Value::Bool(false),
+            _ => return Err(invalid()),
+        },
+        "long" => serde_json::Value::from(cell.parse::<i64>().map_err(|_| invalid())?),
+        "unsignedLong" => serde_json::Value::from(cell.parse::<u64>().map_err(|_| invalid())?),
+        // JSON has no NaN or infinity
+        "double" => cell.parse::<f64>().map_err(|_| invalid()).map(|n| {
+            serde_json::Number::from_f64(n)
+                .map_or(serde_json::Value::Null, serde_json::Value::Number)
+        })?,
+        _ => serde_json::Value::String(cell.to_owned()),
+    })
+}
+
+fn millis(duration: time::Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + f64::from(duration.subsec_micros()) / 1000.0
+}
+
+/// Percent-encodes a query string value.
+fn encode(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' => {
+                result.push(b as char)
+            }
+            _ => write!(result, "%{:02X}", b).unwrap(),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::Future;
+    use futures::Stream;
+
+    const FLUX_RESPONSE: &str = "\
+#datatype,string,long,dateTime:RFC3339,double,long,string,boolean,string,string\r
+#group,false,false,false,false,false,false,false,true,true\r
+#default,_result,,,,,,,,\r
+,result,table,_time,_value,count,label,ok,_measurement,uuid\r
+,,0,2018-11-20T06:00:00Z,2,10,1.5,true,plant,a\r
+,,0,2018-11-20T06:01:00Z,,11,,false,plant,a\r
+,,1,2018-11-20T06:00:00Z,1.25,12,\"x, \"\"y\"\"\",true,plant,b\r
+\r
+#datatype,string,long,string,unsignedLong\r
+#group,false,false,true,false\r
+#default,_result,,,\r
+,result,table,_measurement,total\r
+,,2,pump,18446744073709551615\r
+\r
+";
+
+    // Requests that the mock server received, as (path and query, authorization, body)
+    type Received = sync::Arc<sync::Mutex<Vec<(String, String, String)>>>;
+
+    /// Serves a canned response, or none at all, on a local port; the server responds with 503
+    /// while `up` is false.
+    fn mock_server(
+        runtime: &mut tokio::runtime::Runtime,
+        response: Option<&'static str>,
+        up: Option<sync::Arc<sync::atomic::AtomicBool>>,
+    ) -> (String, Received) {
+        let received = Received::default();
+        let seen = received.clone();
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(move || {
+            let seen = seen.clone();
+            let up = up.clone();
+            hyper::service::service_fn(move |request: hyper::Request<hyper::Body>| {
+                let seen = seen.clone();
+                let up = up
+                    .as_ref()
+                    .map_or(true, |up| up.load(sync::atomic::Ordering::SeqCst));
+                let target = request
+                    .uri()
+                    .path_and_query()
+                    .map_or_else(String::new, |p| p.as_str().to_owned());
+                let authorization = request
+                    .headers()
+                    .get(hyper::header::AUTHORIZATION)
+                    .and_then(|h| h.to_str().ok())
+                    .unwrap_or("")
+                    .to_owned();
+                request.into_body().concat2().and_then(move |body| {
+                    seen.lock().unwrap().push((
+                        target,
+                        authorization,
+                        String::from_utf8_lossy(&body).into_owned(),
+                    ));
+                    match response {
+                        Some(_) if !up => {
+                            let mut down = hyper::Response::new(hyper::Body::from("down"));
+                            *down.status_mut() = hyper::StatusCode::SERVICE_UNAVAILABLE;
+                            futures::future::Either::A(futures::future::ok(down))
+                        }
+                        Some(response) => futures::future::Either::A(futures::future::ok(
+                            hyper::Response::new(hyper::Body::from(response)),
+                        )),
+                        None => futures::future::Either::B(futures::future::empty()),
+                    }
+                })
+            })
+        });
+        let host = format!("http://{}", server.local_addr());
+        runtime.spawn(server.map_err(|_| ()));
+        (host, received)
+    }
+
+    fn v2_client(host: String, timeout: time::Duration) -> Client {
+        Client::new(
+            Auth::V2 {
+                token: "secret".to_owned(),
+                org: "home".to_owned(),
+                bucket: "precip".to_owned(),
+            },
+            Mode::Failover,
+            vec![host],
+            timeout,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn flux_queries_are_decoded_by_data_type() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let (host, received) = mock_server(&mut runtime, Some(FLUX_RESPONSE), None);
+        let client = v2_client(host, time::Duration::from_secs(10));
+
+        let tables = runtime
+            .block_on(client.flux("from(bucket: \"precip\")".to_owned()))
+            .unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].0, "/api/v2/query?org=home&bucket=precip");
+        assert_eq!(received[0].1, "Token secret");
+        let query: serde_json::Value = serde_json::from_str(&received[0].2).unwrap();
+        assert_eq!(query["query"], "from(bucket: \"precip\")");
+        assert_eq!(
+            query["dialect"]["annotations"],
+            serde_json::Value::from(vec!["datatype", "group"])
+        );
+
+        assert_eq!(tables.len(), 3);
+        assert_eq!(tables[0].name, "plant");
+        assert_eq!(tables[0].tags["uuid"], "a");
+        assert_eq!(
+            tables[0].columns,
+            vec!["_time", "_value", "count", "label", "ok"]
+        );
+        assert_eq!(
+            tables[0].values[0],
+            vec![
+                serde_json::Value::from("2018-11-20T06:00:00Z"),
+                serde_json::Value::from(2.0),
+                serde_json::Value::from(10),
+                // a string, even though it looks like a number
+                serde_json::Value::from("1.5"),
+                serde_json::Value::from(true),
+            ]
+        );
+        assert!(tables[0].values[0][1].is_f64());
+        assert_eq!(
+            tables[0].values[1],
+            vec![
+                serde_json::Value::from("2018-11-20T06:01:00Z"),
+                serde_json::Value::Null,
+                serde_json::Value::from(11),
+                serde_json::Value::Null,
+                serde_json::Value::from(false),
+            ]
+        );
+
+        assert_eq!(tables[1].tags["uuid"], "b");
+        assert_eq!(tables[1].values[0][3], serde_json::Value::from("x, \"y\""));
+
+        assert_eq!(tables[2].name, "pump");
+        assert_eq!(tables[2].columns, vec!["total"]);
+        assert_eq!(
+            tables[2].values[0][0],
+            serde_json::Value::from(u64::max_value())
+        );
+    }
+
+    #[test]
+    fn flux_errors_fail_the_query() {
+        let body = "\
+#datatype,string,string\r
+#group,true,true\r
+#default,,\r
+,error,reference\r
+,\"error calling function \"\"from\"\"\",897\r
+";
+        let error = parse_flux_csv(body).unwrap_err().to_string();
+        assert!(error.contains("error calling function \"from\""), error);
+    }
+
+    #[test]
+    fn flux_values_must_match_their_data_type() {
+        let body = "\
+#datatype,string,long,double\r
+#group,false,false,false\r
+#default,_result,,\r
+,result,table,_value\r
+,,0,many\r
+";
+        let error = parse_flux_csv(body).unwrap_err().to_string();
+        assert!(error.contains("_value"), error);
+    }
+
+    #[test]
+    fn requests_time_out() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let (host, _) = mock_server(&mut runtime, None, None);
+        let client = v2_client(host, time::Duration::from_millis(100));
+
+        let error = runtime
+            .block_on(client.flux("buckets()".to_owned()))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("timed out"), error);
+        assert!(!client.stats()[0].healthy);
+    }
+
+    #[test]
+    fn failed_writes_are_replayed_once_the_host_is_back() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let (first, _) = mock_server(&mut runtime, Some(""), None);
+        let up = sync::Arc::new(sync::atomic::AtomicBool::new(false));
+        let (second, received) = mock_server(&mut runtime, Some(""), Some(up.clone()));
+        let client = Client::new(
+            Auth::V1 {
+                username: "precip".to_owned(),
+                password: "secret".to_owned(),
+                database: "precip".to_owned(),
+            },
+            Mode::All,
+            vec![first, second],
+            time::Duration::from_secs(10),
+        )
+        .unwrap();
+
+        let point = Point::new("plant")
+            .field("moisture", Value::Float(1.5))
+            .timestamp(1);
+        runtime.block_on(client.write(&[point])).unwrap();
+        let stats = client.stats();
+        assert_eq!(stats[0].backlog, 0);
+        assert_eq!(stats[1].backlog, 1);
+        assert!(!stats[1].healthy);
+
+        up.store(true, sync::atomic::Ordering::SeqCst);
+        runtime.block_on(client.check_health()).unwrap();
+        let stats = client.stats();
+        assert_eq!(stats[1].backlog, 0);
+        assert!(stats[1].healthy);
+        assert_eq!(stats[1].probes, 1);
+        // The ping isn't counted as a request
+        assert_eq!(stats[1].requests, 2);
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 3);
+        assert_eq!(received[2].2, "plant moisture=1.5 1");
+    }
+
+    #[test]
+    fn hosts_that_are_down_are_not_waited_for() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let (first, _) = mock_server(&mut runtime, Some(""), None);
+        let (second, _) = mock_server(&mut runtime, None, None);
+        let timeout = time::Duration::from_secs(2);
+        let client = Client::new(
+            Auth::V1 {
+                username: "precip".to_owned(),
+                password: "secret".to_owned(),
+                database: "precip".to_owned(),
+            },
+            Mode::All,
+            vec![first, second],
+            timeout,
+        )
+        .unwrap();
+
+        let point = Point::new("plant")
+            .field("moisture", Value::Float(1.5))
+            .timestamp(1);
+        // The first write finds out that the host doesn't respond
+        runtime.block_on(client.write(&[point.clone()])).unwrap();
+        assert!(!client.stats()[1].healthy);
+
+        let started = time::Instant::now();
+        runtime.block_on(client.write(&[point])).unwrap();
+        assert!(started.elapsed() < timeout / 2);
+        let stats = client.stats();
+        assert_eq!(stats[1].backlog, 2);
+        assert_eq!(stats[1].requests, 1);
+    }
+
+    // Recorded from InfluxDB 1.8, with `epoch=ns`
+    const INFLUXQL_RESPONSE: &str = r#"{"results":[{"statement_id":0,"series":[
+        {"name":"plant","tags":{"uuid":"a"},"columns":["time","moisture","ok","label"],
+         "values":[[1542693600000000000,1.5,true,"basil"],[1542693660000000000,null,null,null]]},
+        {"name":"plant","tags":{"uuid":"b"},"columns":["time","moisture"],
+         "values":[[1542693600000000000,2]]}
+    ]}]}"#;
+
+    #[test]
+    fn influxql_rows_are_read_by_column() {
+        use chrono::TimeZone;
+
+        let series = parse_influxql_results(INFLUXQL_RESPONSE.as_bytes()).unwrap();
+        assert_eq!(series.len(), 2);
+        assert_eq!(series[0].tags["uuid"], "a");
+
+        let rows = series[0].rows().collect::<Vec<_>>();
+        assert_eq!(
+            rows[0].time("time").unwrap(),
+            Some(chrono::Utc.ymd(2018, 11, 20).and_hms(6, 0, 0))
+        );
+        assert_eq!(rows[0].f64("moisture").unwrap(), Some(1.5));
+        assert_eq!(rows[0].bool("ok").unwrap(), Some(true));
+        assert_eq!(rows[0].str("label").unwrap(), Some("basil"));
+        assert_eq!(rows[0].numeric("ok"), Some(1.0));
+        assert_eq!(rows[0].numeric("label"), None);
+
+        // Nulls and missing columns are both missing values
+        assert_eq!(
+            rows[1].time("time").unwrap(),
+            Some(chrono::Utc.ymd(2018, 11, 20).and_hms(6, 1, 0))
+        );
+        assert_eq!(rows[1].f64("moisture").unwrap(), None);
+        assert_eq!(rows[1].bool("ok").unwrap(), None);
+        assert_eq!(rows[1].str("label").unwrap(), None);
+        assert_eq!(rows[1].f64("missing").unwrap(), None);
+
+        let rows = series[1].rows().collect::<Vec<_>>();
+        assert_eq!(rows[0].f64("moisture").unwrap(), Some(2.0));
+    }
+
+    #[test]
+    fn influxql_rows_reject_values_of_the_wrong_type() {
+        let series = parse_influxql_results(INFLUXQL_RESPONSE.as_bytes()).unwrap();
+        let row = series[0].rows().next().unwrap();
+        assert!(row.f64("label").is_err());
+        assert!(row.bool("moisture").is_err());
+        assert!(row.str("ok").is_err());
+        assert!(row.time("ok").is_err());
+        assert!(row.time("label").is_err());
+    }
+
+    #[test]
+    fn influxql_times_can_be_rfc3339() {
+        use chrono::TimeZone;
+
+        let body = r#"{"results":[{"statement_id":0,"series":[{"name":"pump","columns":["time"],
+            "values":[["2018-11-20T06:00:00.5Z"],[-1]]}]}]}"#;
+        let series = parse_influxql_results(body.as_bytes()).unwrap();
+        let rows = series[0].rows().collect::<Vec<_>>();
+        assert_eq!(
+            rows[0].time("time").unwrap(),
+            Some(chrono::Utc.ymd(2018, 11, 20).and_hms_milli(6, 0, 0, 500))
+        );
+        assert!(rows[1].time("time").is_err());
+    }
+
+    #[test]
+    fn influxql_results_without_series_are_empty() {
+        let body = r#"{"results":[{"statement_id":0}]}"#;
+        assert!(parse_influxql_results(body.as_bytes()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn influxql_errors_fail_the_query() {
+        let body = r#"{"results":[{"statement_id":0,"error":"database not found: precip"}]}"#;
+        let error = parse_influxql_results(body.as_bytes())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("database not found"), error);
+
+        let body = r#"{"error":"error parsing query: found EOF, expected FROM at line 1, char 9"}"#;
+        let error = parse_influxql_results(body.as_bytes())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("error parsing query"), error);
+    }
+}
@@ -0,0 +1,249 @@
+//! Building InfluxQL queries without splicing unescaped values into them.
+
+use std::fmt;
+
+use chrono;
+
+/// Something to select, like a field or an aggregate of a field.
+#[derive(Clone, Debug)]
+pub enum Expr {
+    // every field and tag
+    All,
+    Field(String),
+    Min(String),
+    Max(String),
+    Last(String),
+    Percentile(String, u8),
+}
+
+#[derive(Clone, Debug)]
+pub enum Condition {
+    TagEquals(String, String),
+    TimeAtLeast(chrono::DateTime<chrono::Utc>),
+    TimeBefore(chrono::DateTime<chrono::Utc>),
+    // relative to the time the query runs
+    TimeWithin(chrono::Duration),
+}
+
+/// A `SELECT` statement; it is rendered with `to_string`.
+#[derive(Clone, Debug)]
+pub struct Select {
+    fields: Vec<(Expr, Option<String>)>,
+    into: Option<String>,
+    policy: Option<String>,
+    from: String,
+    conditions: Vec<Condition>,
+    group_by: Vec<String>,
+}
+
+impl Select {
+    pub fn new(measurement: &str) -> Self {
+        Select {
+            fields: Vec::new(),
+            into: None,
+            policy: None,
+            from: measurement.to_owned(),
+            conditions: Vec::new(),
+            group_by: Vec::new(),
+        }
+    }
+
+    pub fn field(mut self, expr: Expr) -> Self {
+        self.fields.push((expr, None));
+        self
+    }
+
+    pub fn field_as(mut self, expr: Expr, alias: &str) -> Self {
+        self.fields.push((expr, Some(alias.to_owned())));
+        self
+    }
+
+    pub fn into_measurement(mut self, measurement: &str) -> Self {
+        self.into = Some(measurement.to_owned());
+        self
+    }
+
+    /// Selects from a retention policy other than the default one.
+    pub fn policy(mut self, policy: &str) -> Self {
+        self.policy = Some(policy.to_owned());
+        self
+    }
+
+    /// Adds a condition; all conditions have to hold.
+    pub fn filter(mut self, condition: Condition) -> Self {
+        self.conditions.push(condition);
+        self
+    }
+
+    pub fn group_by(mut self, tag: &str) -> Self {
+        self.group_by.push(tag.to_owned());
+        self
+    }
+}
+
+impl fmt::Display for Select {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SELECT ")?;
+        for (i, &(ref expr, ref alias)) in self.fields.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", expr)?;
+            if let Some(ref alias) = *alias {
+                write!(f, " AS {}", Identifier(alias))?;
+            }
+        }
+
+        if let Some(ref into) = self.into {
+            write!(f, " INTO {}", Identifier(into))?;
+        }
+        write!(f, " FROM ")?;
+        if let Some(ref policy) = self.policy {
+            write!(f, "{}.", Identifier(policy))?;
+        }
+        write!(f, "{}", Identifier(&self.from))?;
+
+        for (i, condition) in self.conditions.iter().enumerate() {
+            write!(
+                f,
+                "{}{}",
+                if i == 0 { " WHERE " } else { " AND " },
+                condition
+            )?;
+        }
+
+        for (i, tag) in self.group_by.iter().enumerate() {
+            write!(
+                f,
+                "{}{}",
+                if i == 0 { " GROUP BY " } else { ", " },
+                Identifier(tag)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expr::All => write!(f, "*"),
+            Expr::Field(ref field) => write!(f, "{}", Identifier(field)),
+            Expr::Min(ref field) => write!(f, "min({})", Identifier(field)),
+            Expr::Max(ref field) => write!(f, "max({})", Identifier(field)),
+            Expr::Last(ref field) => write!(f, "last({})", Identifier(field)),
+            Expr::Percentile(ref field, n) => write!(f, "percentile({}, {})", Identifier(field), n),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Condition::TagEquals(ref tag, ref value) => {
+                write!(f, "{} = {}", Identifier(tag), StringLiteral(value))
+            }
+            Condition::TimeAtLeast(t) => write!(f, "time >= {}", timestamp(t)),
+            Condition::TimeBefore(t) => write!(f, "time < {}", timestamp(t)),
+            Condition::TimeWithin(d) => write!(f, "time > now() - {}s", d.num_seconds()),
+        }
+    }
+}
+
+/// Quotes a name, like a database or retention policy, for statements not built with `Select`.
+pub fn identifier(name: &str) -> String {
+    Identifier(name).to_string()
+}
+
+struct Identifier<'a>(&'a str);
+
+struct StringLiteral<'a>(&'a str);
+
+impl<'a> fmt::Display for Identifier<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        quote(f, self.0, '"')
+    }
+}
+
+impl<'a> fmt::Display for StringLiteral<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        quote(f, self.0, '\'')
+    }
+}
+
+// InfluxQL doesn't allow line breaks in quotes, so they are escaped like in Go
+fn quote(f: &mut fmt::Formatter, s: &str, quote: char) -> fmt::Result {
+    write!(f, "{}", quote)?;
+    for c in s.chars() {
+        match c {
+            '\n' => write!(f, "\\n")?,
+            c if c == quote || c == '\\' => write!(f, "\\{}", c)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "{}", quote)
+}
+
+// in nanoseconds, which InfluxQL takes as is
+fn timestamp(t: chrono::DateTime<chrono::Utc>) -> i64 {
+    t.timestamp() * 1_000_000_000 + i64::from(t.timestamp_subsec_nanos())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    #[test]
+    fn identifiers_are_escaped() {
+        assert_eq!(Identifier("plant").to_string(), r#""plant""#);
+        assert_eq!(Identifier(r#"say "hi""#).to_string(), r#""say \"hi\"""#);
+        assert_eq!(Identifier(r"back\slash").to_string(), r#""back\\slash""#);
+        assert_eq!(Identifier("it's").to_string(), r#""it's""#);
+        assert_eq!(Identifier("two\nlines").to_string(), r#""two\nlines""#);
+    }
+
+    #[test]
+    fn string_literals_are_escaped() {
+        assert_eq!(StringLiteral("basil").to_string(), "'basil'");
+        assert_eq!(StringLiteral("it's").to_string(), r"'it\'s'");
+        assert_eq!(StringLiteral(r"back\slash").to_string(), r"'back\\slash'");
+        assert_eq!(StringLiteral(r#"say "hi""#).to_string(), r#"'say "hi"'"#);
+        assert_eq!(StringLiteral("two\nlines").to_string(), r"'two\nlines'");
+        // An escaped quote can't end the literal early
+        assert_eq!(StringLiteral(r"\' OR 1=1").to_string(), r"'\\\' OR 1=1'");
+    }
+
+    #[test]
+    fn selects_quote_everything_they_splice_in() {
+        let query = Select::new("plant")
+            .field(Expr::Percentile("moisture".to_owned(), 90))
+            .field_as(Expr::Last("voltage".to_owned()), "last voltage")
+            .into_measurement("plant_index")
+            .filter(Condition::TagEquals(
+                "name".to_owned(),
+                "basil's".to_owned(),
+            ))
+            .filter(Condition::TimeAtLeast(chrono::Utc.timestamp(1, 500)))
+            .filter(Condition::TimeWithin(chrono::Duration::hours(1)))
+            .group_by("uuid")
+            .to_string();
+        assert_eq!(
+            query,
+            "SELECT percentile(\"moisture\", 90), last(\"voltage\") AS \"last voltage\" \
+             INTO \"plant_index\" FROM \"plant\" \
+             WHERE \"name\" = 'basil\\'s' AND time >= 1000000500 AND time > now() - 3600s \
+             GROUP BY \"uuid\""
+        );
+    }
+
+    #[test]
+    fn selects_can_be_from_another_policy() {
+        let query = Select::new("pump")
+            .field(Expr::Field("running".to_owned()))
+            .policy("events")
+            .to_string();
+        assert_eq!(query, "SELECT \"running\" FROM \"events\".\"pump\"");
+    }
+}
@@ -1,77 +1,281 @@
 use chrono;
 use failure;
-use influent;
-use serde_json;
+use futures;
 use slog;
 use uuid;
 
+use std::collections;
+use std::time;
+
+use config;
+use light;
+use rollup;
+
+use futures::Future;
+
+pub mod influx;
+pub mod influxql;
 pub mod model;
+pub mod postgres;
+mod retention;
+
+pub struct Db {
+    backend: Backend,
+    store_raw: bool,
+    // the InfluxDB retention policy that events are kept in, if precip manages the policies
+    events_policy: Option<&'static str>,
+}
 
-pub struct Db<'a> {
-    client: influent::client::http::HttpClient<'a>,
+enum Backend {
+    Influx(influx::Client),
+    Postgres(postgres::Store),
+    // accepts every write and finds nothing
+    Discard,
 }
 
-impl<'a> Db<'a> {
-    pub fn connect(
-        _log: slog::Logger,
-        credentials: influent::client::Credentials<'a>,
-        hosts: Vec<String>,
-    ) -> Result<Self, failure::Error> {
-        let serializer = influent::serializer::line::LineSerializer::new();
-        let mut client = influent::client::http::HttpClient::new(
-            credentials,
-            Box::new(serializer),
-            Box::new(influent::hurl::hyper::HyperHurl::new()),
-        );
-
-        for host in hosts {
-            client.add_host(leak_static_str(host));
+type Query<A> = Box<futures::Future<Item = A, Error = failure::Error> + Send>;
+
+impl Db {
+    /// Connects to the configured hosts; the connection can be recreated at any time, for example
+    /// after the config changed.
+    pub fn connect(_log: slog::Logger, config: &config::Db) -> Result<Self, failure::Error> {
+        let auth = match (&config.credentials, &config.v2, &config.postgres) {
+            (&Some(ref credentials), &None, &None) => influx::Auth::V1 {
+                username: credentials.username.clone(),
+                password: credentials.password.clone(),
+                database: credentials.database.clone(),
+            },
+            (&None, &Some(ref v2), &None) => influx::Auth::V2 {
+                token: v2.token.clone(),
+                org: v2.org.clone(),
+                bucket: v2.bucket.clone(),
+            },
+            (&None, &None, &Some(ref postgres)) => {
+                return Ok(Db {
+                    backend: Backend::Postgres(postgres::Store::connect(postgres)?),
+                    store_raw: config.retention.store_raw,
+                    events_policy: None,
+                });
+            }
+            _ => {
+                bail!("The database config needs exactly one of 'credentials', 'v2' and 'postgres'")
+            }
+        };
+        let events_policy = match auth {
+            influx::Auth::V1 { .. } if config.retention.manage => Some(retention::EVENTS_POLICY),
+            _ => None,
+        };
+        let client = influx::Client::new(
+            auth,
+            config.mode,
+            config.hosts.clone(),
+            time::Duration::from_secs(config.timeout_seconds),
+        )?;
+
+        Ok(Db {
+            backend: Backend::Influx(client),
+            store_raw: config.retention.store_raw,
+            events_policy,
+        })
+    }
+
+    /// Doesn't store anything, for replays that mustn't write to the database of the installation.
+    pub fn discard(config: &config::Db) -> Self {
+        Db {
+            backend: Backend::Discard,
+            store_raw: config.retention.store_raw,
+            events_policy: None,
         }
+    }
 
-        Ok(Db { client })
+    /// Whether every sample is stored, and not only the rollups of them.
+    pub fn stores_raw(&self) -> bool {
+        self.store_raw
     }
 
-    pub fn insert_global_measurement(
-        &self,
-        now: chrono::DateTime<chrono::Utc>,
-        temperature: f64,
-        pressure: f64,
-    ) -> Result<(), failure::Error> {
-        use influent::client::Client;
+    /// Request and error counts of every host.
+    pub fn host_stats(&self) -> Vec<influx::HostStats> {
+        match self.backend {
+            Backend::Influx(ref client) => client.stats(),
+            Backend::Postgres(_) | Backend::Discard => Vec::new(),
+        }
+    }
 
-        let mut measurement = influent::measurement::Measurement::new("global");
-        measurement.set_timestamp(to_influx_timestamp(now));
-        measurement.add_field(
-            "temperature",
-            influent::measurement::Value::Float(temperature),
-        );
-        measurement.add_field("pressure", influent::measurement::Value::Float(pressure));
+    pub fn check_hosts(&self) -> Query<()> {
+        match self.backend {
+            Backend::Influx(ref client) => client.check_health(),
+            Backend::Postgres(ref store) => store.check(),
+            Backend::Discard => discarded(),
+        }
+    }
 
-        self.client
-            .write_one(measurement, Some(influent::client::Precision::Nanoseconds))
-            .map_err(from_influent_error)?;
+    /// Creates or migrates the retention policies and continuous queries, or the tables on
+    /// PostgreSQL.  The plant indices are never kept up to date by the database itself, so
+    /// `update_plant_indices` has to be called regularly.
+    pub fn ensure_schema(&self, log: slog::Logger, retention: &config::Retention) -> Query<()> {
+        use futures::future;
+
+        let client = match self.backend {
+            Backend::Influx(ref client) => client,
+            Backend::Discard => return discarded(),
+            Backend::Postgres(ref store) => return store.ensure_schema(log),
+        };
+        match *client.auth() {
+            influx::Auth::V1 { ref database, .. } if retention.manage => Box::new(
+                retention::ensure(log, client.clone(), database.clone(), retention.clone()),
+            ),
+            influx::Auth::V1 { .. } => {
+                info!(
+                    log,
+                    "not managing retention policies and continuous queries"
+                );
+                Box::new(future::ok(()))
+            }
+            influx::Auth::V2 { .. } => {
+                info!(log, "retention is up to the bucket on InfluxDB 2.x");
+                Box::new(future::ok(()))
+            }
+        }
+    }
 
-        Ok(())
+    /// Records the names of the configured plants, for querying the database directly.
+    pub fn register_plants(
+        &self,
+        plants: &collections::HashMap<uuid::Uuid, config::Plant>,
+    ) -> Query<()> {
+        match self.backend {
+            // Plants are only known by the uuid tag in InfluxDB
+            Backend::Influx(_) => Box::new(futures::future::ok(())),
+            Backend::Discard => discarded(),
+            Backend::Postgres(ref store) => store.register_plants(
+                plants
+                    .iter()
+                    .map(|(uuid, plant)| (*uuid, plant.name.clone(), plant.description.clone()))
+                    .collect(),
+            ),
+        }
     }
 
-    pub fn insert_plant_measurement(
+    pub fn insert_measurement(
         &self,
         now: chrono::DateTime<chrono::Utc>,
-        uuid: uuid::Uuid,
-        moisture: f64,
-    ) -> Result<(), failure::Error> {
-        use influent::client::Client;
+        name: &str,
+        tags: &[(String, String)],
+        fields: &[(String, f64)],
+    ) -> Query<()> {
+        let client = match self.backend {
+            Backend::Influx(ref client) => client,
+            Backend::Discard => return discarded(),
+            Backend::Postgres(ref store) => {
+                return store.insert_measurement(now, name, tags, fields)
+            }
+        };
+        let mut point = influx::Point::new(name).timestamp(to_influx_timestamp(now));
+        for &(ref tag, ref value) in tags {
+            point = point.tag(tag, value.clone());
+        }
+        for &(ref field, value) in fields {
+            point = point.field(field, influx::Value::Float(value));
+        }
 
-        let mut measurement = influent::measurement::Measurement::new("plant");
-        measurement.set_timestamp(to_influx_timestamp(now));
-        measurement.add_tag("uuid", uuid.to_hyphenated().to_string());
-        measurement.add_field("moisture", influent::measurement::Value::Float(moisture));
+        self.write(client, name, &[point])
+    }
 
-        self.client
-            .write_one(measurement, Some(influent::client::Precision::Nanoseconds))
-            .map_err(from_influent_error)?;
+    /// Stores a rollup as the measurement of the sensor with a suffix, at the start of its interval.
+    pub fn insert_rollup(
+        &self,
+        measurement: &str,
+        tags: &[(String, String)],
+        rollup: &rollup::Rollup,
+    ) -> Query<()> {
+        self.insert_measurement(
+            rollup.start,
+            &format!("{}{}", measurement, rollup::MEASUREMENT_SUFFIX),
+            tags,
+            &rollup.fields(),
+        )
+    }
 
-        Ok(())
+    /// Writes the points in one batch, and the events among them in another, like when importing.
+    pub fn insert_points(&self, points: Vec<model::Point>) -> Query<()> {
+        let client = match self.backend {
+            Backend::Influx(ref client) => client,
+            Backend::Discard => return discarded(),
+            Backend::Postgres(ref store) => return store.insert_points(points),
+        };
+        let points = points
+            .iter()
+            .map(|p| {
+                let mut point =
+                    influx::Point::new(&p.measurement).timestamp(to_influx_timestamp(p.time));
+                for &(ref tag, ref value) in &p.tags {
+                    point = point.tag(tag, value.clone());
+                }
+                for &(ref field, value) in &p.fields {
+                    point = point.field(
+                        field,
+                        match value {
+                            model::Value::Float(v) => influx::Value::Float(v),
+                            model::Value::Boolean(b) => influx::Value::Boolean(b),
+                        },
+                    );
+                }
+                (p.measurement.clone(), point)
+            })
+            .collect::<Vec<_>>();
+
+        let (events, samples): (Vec<_>, Vec<_>) = points
+            .into_iter()
+            .partition(|&(ref measurement, _)| retention::is_event(measurement));
+        let events = events
+            .into_iter()
+            .map(|(_, point)| point)
+            .collect::<Vec<_>>();
+        let samples = samples
+            .into_iter()
+            .map(|(_, point)| point)
+            .collect::<Vec<_>>();
+        match (events.is_empty(), samples.is_empty()) {
+            (true, _) => client.write(&samples),
+            (false, true) => client.write_to(&events, self.events_policy),
+            (false, false) => Box::new(
+                client
+                    .write(&samples)
+                    .join(client.write_to(&events, self.events_policy))
+                    .map(|_| ()),
+            ),
+        }
+    }
+
+    pub fn insert_daily_light_integral(
+        &self,
+        tags: &[(String, String)],
+        dli: &light::DailyLightIntegral,
+    ) -> Query<()> {
+        let client = match self.backend {
+            Backend::Influx(ref client) => client,
+            Backend::Discard => return discarded(),
+            Backend::Postgres(ref store) => {
+                return store.insert_measurement(
+                    dli.start,
+                    "daily_light_integral",
+                    tags,
+                    &[
+                        ("integral".to_owned(), dli.integral),
+                        ("coverage".to_owned(), dli.coverage),
+                    ],
+                )
+            }
+        };
+        let mut point = influx::Point::new("daily_light_integral")
+            .timestamp(to_influx_timestamp(dli.start))
+            .field("integral", influx::Value::Float(dli.integral))
+            .field("coverage", influx::Value::Float(dli.coverage));
+        for &(ref tag, ref value) in tags {
+            point = point.tag(tag, value.clone());
+        }
+
+        self.write(client, "daily_light_integral", &[point])
     }
 
     pub fn insert_pump_measurement(
@@ -79,154 +283,714 @@ impl<'a> Db<'a> {
         now: chrono::DateTime<chrono::Utc>,
         uuid: uuid::Uuid,
         running: bool,
-    ) -> Result<(), failure::Error> {
-        use influent::client::Client;
-
-        let mut measurement = influent::measurement::Measurement::new("pump");
-        measurement.set_timestamp(to_influx_timestamp(now));
-        measurement.add_tag("uuid", uuid.to_hyphenated().to_string());
-        measurement.add_field("running", influent::measurement::Value::Boolean(running));
+    ) -> Query<()> {
+        let client = match self.backend {
+            Backend::Influx(ref client) => client,
+            Backend::Discard => return discarded(),
+            Backend::Postgres(ref store) => return store.insert_pump_event(now, uuid, running),
+        };
+        let point = influx::Point::new("pump")
+            .timestamp(to_influx_timestamp(now))
+            .tag("uuid", uuid.to_hyphenated().to_string())
+            .field("running", influx::Value::Boolean(running));
+
+        self.write(client, "pump", &[point])
+    }
 
-        self.client
-            .write_one(measurement, Some(influent::client::Precision::Nanoseconds))
-            .map_err(from_influent_error)?;
+    pub fn insert_watering_failed_event(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+        uuid: uuid::Uuid,
+        attempt: u32,
+        moisture_before: f64,
+        moisture_after: f64,
+    ) -> Query<()> {
+        let client = match self.backend {
+            Backend::Influx(ref client) => client,
+            Backend::Discard => return discarded(),
+            Backend::Postgres(ref store) => {
+                return store.insert_measurement(
+                    now,
+                    "watering_failed",
+                    &[("uuid".to_owned(), uuid.to_hyphenated().to_string())],
+                    &[
+                        ("attempt".to_owned(), f64::from(attempt)),
+                        ("moisture_before".to_owned(), moisture_before),
+                        ("moisture_after".to_owned(), moisture_after),
+                    ],
+                )
+            }
+        };
+        let point = influx::Point::new("watering_failed")
+            .timestamp(to_influx_timestamp(now))
+            .tag("uuid", uuid.to_hyphenated().to_string())
+            .field("attempt", influx::Value::Integer(i64::from(attempt)))
+            .field("moisture_before", influx::Value::Float(moisture_before))
+            .field("moisture_after", influx::Value::Float(moisture_after));
+
+        self.write(client, "watering_failed", &[point])
+    }
 
-        Ok(())
+    // Writes events to their own policy, where they don't expire along with the raw samples
+    fn write(
+        &self,
+        client: &influx::Client,
+        measurement: &str,
+        points: &[influx::Point],
+    ) -> Query<()> {
+        if retention::is_event(measurement) {
+            client.write_to(points, self.events_policy)
+        } else {
+            client.write(points)
+        }
     }
 
-    pub fn update_plant_indices(&self) -> Result<(), failure::Error> {
-        use influent::client::Client;
+    fn select_from(&self, measurement: &str) -> influxql::Select {
+        let select = influxql::Select::new(measurement);
+        match self.events_policy {
+            Some(policy) if retention::is_event(measurement) => select.policy(policy),
+            _ => select,
+        }
+    }
 
-        self.client
-            .query(
-                "select \
-                 percentile(moisture, 5) as moisture_p05, \
-                 percentile(moisture, 95) as moisture_p95 \
-                 into plant_index from plant where time > now() - 1w group by uuid"
-                    .to_owned(),
-                Some(influent::client::Precision::Nanoseconds),
+    pub fn update_plant_indices(&self) -> Query<()> {
+        let (measurement, field) = plant_index_source(self.store_raw);
+        let client = match self.backend {
+            Backend::Influx(ref client) => client,
+            Backend::Discard => return discarded(),
+            Backend::Postgres(ref store) => return store.update_plant_indices(measurement, field),
+        };
+        Box::new(
+            select(
+                client,
+                influxql::Select::new(&measurement)
+                    .field_as(influxql::Expr::Percentile(field.clone(), 5), "moisture_p05")
+                    .field_as(
+                        influxql::Expr::Percentile(field.clone(), 95),
+                        "moisture_p95",
+                    )
+                    .into_measurement("plant_index")
+                    .filter(influxql::Condition::TimeWithin(chrono::Duration::weeks(1)))
+                    .group_by("uuid")
+                    .to_string(),
+                |bucket, org| {
+                    let percentile = |q: f64, field: &str| {
+                        format!(
+                            "moisture \
+                             |> quantile(q: {}, method: \"exact_selector\") \
+                             |> duplicate(column: \"_stop\", as: \"_time\") \
+                             |> set(key: \"_measurement\", value: \"plant_index\") \
+                             |> set(key: \"_field\", value: \"{}\") \
+                             |> to(bucket: {}, org: {})\n",
+                            q, field, bucket, org
+                        )
+                    };
+                    format!(
+                        "moisture = from(bucket: {}) \
+                         |> range(start: -1w) \
+                         |> filter(fn: (r) => r._measurement == {} and r._field == {}) \
+                         |> group(columns: [\"uuid\"])\n\
+                         {}{}",
+                        bucket,
+                        influx::flux_string(&measurement),
+                        influx::flux_string(&field),
+                        percentile(0.05, "moisture_p05"),
+                        percentile(0.95, "moisture_p95")
+                    )
+                },
             )
-            .map_err(from_influent_error)?;
-
-        Ok(())
+            .map(|_| ()),
+        )
     }
 
     pub fn fetch_module_moisture_voltage_range(
         &self,
         m_id: uuid::Uuid,
-    ) -> Result<(Option<f64>, Option<f64>), failure::Error> {
-        use influent::client::Client;
-
-        let results = self
-            .client
-            .query(
-                format!(
-                    "select moisture_p05 as lo, moisture_p95 as hi from plant_index where uuid = '{}'",
-                    m_id
-                ),
-                Some(influent::client::Precision::Nanoseconds),
+    ) -> Query<(Option<f64>, Option<f64>)> {
+        let client = match self.backend {
+            Backend::Influx(ref client) => client,
+            Backend::Discard => return discarded(),
+            Backend::Postgres(ref store) => return store.fetch_moisture_voltage_range(m_id),
+        };
+        Box::new(
+            select(
+                client,
+                influxql::Select::new("plant_index")
+                    .field_as(influxql::Expr::Field("moisture_p05".to_owned()), "lo")
+                    .field_as(influxql::Expr::Field("moisture_p95".to_owned()), "hi")
+                    .filter(influxql::Condition::TagEquals(
+                        "uuid".to_owned(),
+                        m_id.to_hyphenated().to_string(),
+                    ))
+                    .to_string(),
+                |bucket, _| {
+                    format!(
+                        "from(bucket: {}) \
+                         |> range(start: 0) \
+                         |> filter(fn: (r) => r._measurement == \"plant_index\" and r.uuid == {}) \
+                         |> last() \
+                         |> pivot(rowKey: [\"_time\"], columnKey: [\"_field\"], valueColumn: \"_value\") \
+                         |> rename(columns: {{moisture_p05: \"lo\", moisture_p95: \"hi\"}})",
+                        bucket,
+                        influx::flux_string(&m_id.to_hyphenated().to_string())
+                    )
+                },
             )
-            .map_err(from_influent_error)?;
+            .and_then(|results| {
+                match results.iter().flat_map(|series| series.rows()).last() {
+                    Some(row) => Ok((row.f64("lo")?, row.f64("hi")?)),
+                    None => Ok((None, None)),
+                }
+            }),
+        )
+    }
 
-        let results: QueryResults = serde_json::de::from_str(&results)?;
+    /// The range of raw moisture voltages of every plant in the given time range.
+    pub fn collect_samples_range(
+        &self,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+    ) -> Query<Vec<model::SampleRange>> {
+        let fields = if self.store_raw {
+            PlantFields::raw("voltage")
+        } else {
+            PlantFields::rollup("voltage")
+        };
+        Box::new(self.collect_plant_ranges(fields, from, to).map(|ranges| {
+            ranges
+                .into_iter()
+                .filter_map(|range| match range {
+                    (module_uuid, Some(min_raw_voltage), Some(max_raw_voltage), _) => {
+                        Some(model::SampleRange {
+                            module_uuid,
+                            min_raw_voltage,
+                            max_raw_voltage,
+                        })
+                    }
+                    _ => None,
+                })
+                .collect()
+        }))
+    }
 
-        Ok(results
-            .results
-            .into_iter()
-            .find(|r| r.statement_id == Some(0))
-            .and_then(|result| result.series.into_iter().find(|s| s.name == "plant"))
-            .map_or_else(
-                || (None, None),
-                |series| {
-                    (
-                        series
-                            .columns
-                            .iter()
-                            .position(|c| c == "lo")
-                            .map(|i| series.values[0][i]),
-                        series
-                            .columns
-                            .iter()
-                            .position(|c| c == "hi")
-                            .map(|i| series.values[0][i]),
+    /// The moisture rollups of all plants from the given time range, by time.
+    pub fn collect_samples_timeseries(
+        &self,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+    ) -> Query<Vec<model::SampleTimeseries>> {
+        let measurement = format!("plant{}", rollup::MEASUREMENT_SUFFIX);
+        let client = match self.backend {
+            Backend::Influx(ref client) => client,
+            Backend::Discard => return discarded(),
+            Backend::Postgres(ref store) => {
+                return Box::new(store.collect_rollups(measurement, from, to).map(|rows| {
+                    let mut samples: Vec<(
+                        uuid::Uuid,
+                        chrono::DateTime<chrono::Utc>,
+                        [Option<f64>; 6],
+                    )> = Vec::new();
+                    for (module_uuid, slice, field, value) in rows {
+                        let i = match ROLLUP_FIELDS.iter().position(|f| *f == field) {
+                            Some(i) => i,
+                            None => continue,
+                        };
+                        // The rows are ordered by plant and time
+                        let new = samples
+                            .last()
+                            .map_or(true, |&(u, t, _)| u != module_uuid || t != slice);
+                        if new {
+                            samples.push((module_uuid, slice, [None; 6]));
+                        }
+                        if let Some(sample) = samples.last_mut() {
+                            sample.2[i] = Some(value);
+                        }
+                    }
+
+                    let mut samples = samples
+                        .into_iter()
+                        .filter_map(|(module_uuid, slice, values)| {
+                            sample_timeseries(module_uuid, slice, &values)
+                        })
+                        .collect::<Vec<_>>();
+                    samples.sort_by_key(|s| s.slice);
+                    samples
+                }));
+            }
+        };
+
+        let mut query = influxql::Select::new(&measurement);
+        for field in &ROLLUP_FIELDS {
+            query = query.field(influxql::Expr::Field((*field).to_owned()));
+        }
+
+        Box::new(
+            select(
+                client,
+                query
+                    .filter(influxql::Condition::TimeAtLeast(from))
+                    .filter(influxql::Condition::TimeBefore(to))
+                    .group_by("uuid")
+                    .to_string(),
+                |bucket, _| {
+                    format!(
+                        "from(bucket: {}) \
+                         |> range(start: {}, stop: {}) \
+                         |> filter(fn: (r) => r._measurement == {}) \
+                         |> pivot(rowKey: [\"_time\"], columnKey: [\"_field\"], valueColumn: \"_value\") \
+                         |> group(columns: [\"uuid\"]) \
+                         |> rename(columns: {{_time: \"time\"}})",
+                        bucket,
+                        from.to_rfc3339(),
+                        to.to_rfc3339(),
+                        influx::flux_string(&measurement)
                     )
                 },
-            ))
+            )
+            .and_then(|results| {
+                let mut samples = Vec::new();
+                for series in results {
+                    let module_uuid = match series.tags.get("uuid").and_then(|u| u.parse().ok()) {
+                        Some(uuid) => uuid,
+                        None => continue,
+                    };
+
+                    for row in series.rows() {
+                        let slice = match row.time("time")? {
+                            Some(slice) => slice,
+                            None => continue,
+                        };
+                        let mut values = [None; 6];
+                        for (value, field) in values.iter_mut().zip(&ROLLUP_FIELDS) {
+                            *value = row.f64(field)?;
+                        }
+                        samples.extend(sample_timeseries(module_uuid, slice, &values));
+                    }
+                }
+
+                samples.sort_by_key(|s| s.slice);
+                Ok(samples)
+            }),
+        )
     }
 
-    pub fn collect_samples_range(&self) -> Result<Vec<model::SampleRange>, failure::Error> {
-        Ok(Vec::new())
+    /// Every numeric field of a measurement in the given time range, by time.
+    pub fn collect_fields(
+        &self,
+        measurement: &str,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+    ) -> Query<Vec<model::FieldValue>> {
+        let client = match self.backend {
+            Backend::Influx(ref client) => client,
+            Backend::Discard => return discarded(),
+            // Pump runs have a table of their own
+            Backend::Postgres(ref store) if measurement == "pump" => {
+                return Box::new(store.collect_pump_events(from, to).map(|events| {
+                    events
+                        .into_iter()
+                        .map(|event| model::FieldValue {
+                            time: event.created,
+                            plant_uuid: Some(event.module_uuid),
+                            field: "running".to_owned(),
+                            value: if event.pump_running { 1.0 } else { 0.0 },
+                        })
+                        .collect()
+                }));
+            }
+            Backend::Postgres(ref store) => {
+                return store.collect_fields(measurement.to_owned(), from, to)
+            }
+        };
+        let measurement = measurement.to_owned();
+        Box::new(
+            select(
+                client,
+                self.select_from(&measurement)
+                    .field(influxql::Expr::All)
+                    .filter(influxql::Condition::TimeAtLeast(from))
+                    .filter(influxql::Condition::TimeBefore(to))
+                    .group_by("uuid")
+                    .to_string(),
+                |bucket, _| {
+                    format!(
+                        "from(bucket: {}) \
+                         |> range(start: {}, stop: {}) \
+                         |> filter(fn: (r) => r._measurement == {}) \
+                         |> pivot(rowKey: [\"_time\"], columnKey: [\"_field\"], valueColumn: \"_value\") \
+                         |> group(columns: [\"uuid\"]) \
+                         |> drop(columns: [\"_start\", \"_stop\", \"_measurement\"]) \
+                         |> rename(columns: {{_time: \"time\"}})",
+                        bucket,
+                        from.to_rfc3339(),
+                        to.to_rfc3339(),
+                        influx::flux_string(&measurement)
+                    )
+                },
+            )
+            .and_then(|results| {
+                let mut values = Vec::new();
+                for series in results {
+                    let plant_uuid = series.tags.get("uuid").and_then(|u| u.parse().ok());
+                    for row in series.rows() {
+                        let time = match row.time("time")? {
+                            Some(time) => time,
+                            None => continue,
+                        };
+                        for column in series.columns.iter().filter(|c| *c != "time" && *c != "uuid") {
+                            if let Some(value) = row.numeric(column) {
+                                values.push(model::FieldValue {
+                                    time,
+                                    plant_uuid,
+                                    field: column.clone(),
+                                    value,
+                                });
+                            }
+                        }
+                    }
+                }
+
+                values.sort_by_key(|v| v.time);
+                Ok(values)
+            }),
+        )
     }
 
-    pub fn collect_samples_timeseries(
+    pub fn collect_pump_events(
         &self,
-    ) -> Result<Vec<model::SampleTimeseries>, failure::Error> {
-        Ok(Vec::new())
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+    ) -> Query<Vec<model::PumpEvent>> {
+        let client = match self.backend {
+            Backend::Influx(ref client) => client,
+            Backend::Discard => return discarded(),
+            Backend::Postgres(ref store) => return store.collect_pump_events(from, to),
+        };
+        Box::new(
+            select(
+                client,
+                self.select_from("pump")
+                    .field(influxql::Expr::Field("running".to_owned()))
+                    .filter(influxql::Condition::TimeAtLeast(from))
+                    .filter(influxql::Condition::TimeBefore(to))
+                    .group_by("uuid")
+                    .to_string(),
+                |bucket, _| {
+                    format!(
+                        "from(bucket: {}) \
+                         |> range(start: {}, stop: {}) \
+                         |> filter(fn: (r) => r._measurement == \"pump\" and r._field == \"running\") \
+                         |> keep(columns: [\"_time\", \"_value\", \"uuid\"]) \
+                         |> rename(columns: {{_time: \"time\", _value: \"running\"}})",
+                        bucket,
+                        from.to_rfc3339(),
+                        to.to_rfc3339()
+                    )
+                },
+            )
+            .and_then(|results| {
+                let mut events = Vec::new();
+                for series in results {
+                    let module_uuid = match series.tags.get("uuid").and_then(|u| u.parse().ok()) {
+                        Some(uuid) => uuid,
+                        None => continue,
+                    };
+
+                    for row in series.rows() {
+                        if let (Some(created), Some(pump_running)) =
+                            (row.time("time")?, row.bool("running")?)
+                        {
+                            events.push(model::PumpEvent {
+                                created,
+                                module_uuid,
+                                pump_running,
+                            });
+                        }
+                    }
+                }
+
+                events.sort_by_key(|e| e.created);
+                Ok(events)
+            }),
+        )
     }
 
-    pub fn collect_pump_events(&self) -> Result<Vec<model::PumpEvent>, failure::Error> {
-        Ok(Vec::new())
+    /// The lowest, highest and last moisture of every plant in the given time range.
+    pub fn collect_stats(
+        &self,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+    ) -> Query<Vec<model::Stats>> {
+        let fields = if self.store_raw {
+            PlantFields::raw("moisture")
+        } else {
+            PlantFields::rollup("moisture")
+        };
+        Box::new(self.collect_plant_ranges(fields, from, to).map(|ranges| {
+            ranges
+                .into_iter()
+                .filter_map(|range| match range {
+                    (module_uuid, Some(min_moisture), Some(max_moisture), Some(last_moisture)) => {
+                        Some(model::Stats {
+                            module_uuid,
+                            min_moisture,
+                            max_moisture,
+                            last_moisture,
+                        })
+                    }
+                    _ => None,
+                })
+                .collect()
+        }))
     }
 
-    pub fn collect_stats(&self) -> Result<Vec<model::Stats>, failure::Error> {
-        Ok(Vec::new())
+    // The min, max and last value of every plant, each of a field of its own
+    fn collect_plant_ranges(
+        &self,
+        fields: PlantFields,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+    ) -> Query<Vec<PlantRange>> {
+        let client = match self.backend {
+            Backend::Influx(ref client) => client,
+            Backend::Discard => return discarded(),
+            Backend::Postgres(ref store) => {
+                return store.collect_plant_ranges(
+                    fields.measurement,
+                    fields.min,
+                    fields.max,
+                    fields.last,
+                    from,
+                    to,
+                )
+            }
+        };
+        Box::new(
+            select(
+                client,
+                influxql::Select::new(&fields.measurement)
+                    .field_as(influxql::Expr::Min(fields.min.clone()), "min")
+                    .field_as(influxql::Expr::Max(fields.max.clone()), "max")
+                    .field_as(influxql::Expr::Last(fields.last.clone()), "last")
+                    .filter(influxql::Condition::TimeAtLeast(from))
+                    .filter(influxql::Condition::TimeBefore(to))
+                    .group_by("uuid")
+                    .to_string(),
+                |bucket, _| {
+                    let aggregate = |field: &str, function: &str| {
+                        format!(
+                            "data |> filter(fn: (r) => r._field == {}) |> {}() \
+                             |> set(key: \"_field\", value: \"{}\")",
+                            influx::flux_string(field),
+                            function,
+                            function
+                        )
+                    };
+                    format!(
+                        "data = from(bucket: {}) \
+                         |> range(start: {}, stop: {}) \
+                         |> filter(fn: (r) => r._measurement == {}) \
+                         |> group(columns: [\"uuid\"])\n\
+                         union(tables: [{}, {}, {}]) \
+                         |> keep(columns: [\"uuid\", \"_field\", \"_value\"]) \
+                         |> pivot(rowKey: [\"uuid\"], columnKey: [\"_field\"], valueColumn: \"_value\")",
+                        bucket,
+                        from.to_rfc3339(),
+                        to.to_rfc3339(),
+                        influx::flux_string(&fields.measurement),
+                        aggregate(&fields.min, "min"),
+                        aggregate(&fields.max, "max"),
+                        aggregate(&fields.last, "last")
+                    )
+                },
+            )
+            .and_then(|results| {
+                let mut ranges = Vec::new();
+                for series in results {
+                    let module_uuid = match series.tags.get("uuid").and_then(|u| u.parse().ok()) {
+                        Some(uuid) => uuid,
+                        None => continue,
+                    };
+                    for row in series.rows() {
+                        ranges.push((
+                            module_uuid,
+                            row.f64("min")?,
+                            row.f64("max")?,
+                            row.f64("last")?,
+                        ));
+                    }
+                }
+                Ok(ranges)
+            }),
+        )
     }
 
-    pub fn collect_global_stats(&self) -> Result<model::GlobalStats, failure::Error> {
-        Ok(model::GlobalStats { temperature: 0.0 })
+    pub fn collect_global_stats(&self) -> Query<model::GlobalStats> {
+        let client = match self.backend {
+            Backend::Influx(ref client) => client,
+            Backend::Discard => return discarded(),
+            Backend::Postgres(ref store) => return store.collect_global_stats(),
+        };
+        Box::new(
+            select(
+                client,
+                influxql::Select::new("global")
+                    .field_as(influxql::Expr::Last("temperature".to_owned()), "temperature")
+                    .field_as(influxql::Expr::Last("pressure".to_owned()), "pressure")
+                    .field_as(influxql::Expr::Last("humidity".to_owned()), "humidity")
+                    .to_string(),
+                |bucket, _| {
+                    format!(
+                        "from(bucket: {}) \
+                         |> range(start: 0) \
+                         |> filter(fn: (r) => r._measurement == \"global\") \
+                         |> filter(fn: (r) => r._field == \"temperature\" or r._field == \"pressure\" or r._field == \"humidity\") \
+                         |> last() \
+                         |> group(columns: [\"_measurement\"]) \
+                         |> pivot(rowKey: [\"_measurement\"], columnKey: [\"_field\"], valueColumn: \"_value\")",
+                        bucket
+                    )
+                },
+            )
+            .and_then(|results| {
+                match results
+                    .iter()
+                    .filter(|s| s.name == "global")
+                    .flat_map(|s| s.rows())
+                    .next()
+                {
+                    Some(row) => Ok(model::GlobalStats {
+                        temperature: row.f64("temperature")?,
+                        pressure: row.f64("pressure")?,
+                        humidity: row.f64("humidity")?,
+                    }),
+                    None => Ok(model::GlobalStats {
+                        temperature: None,
+                        pressure: None,
+                        humidity: None,
+                    }),
+                }
+            }),
+        )
     }
 }
 
-fn to_influx_timestamp<Tz>(t: chrono::DateTime<Tz>) -> i64
+// What the discarding backend finds
+fn discarded<A>() -> Query<A>
 where
-    Tz: chrono::TimeZone,
+    A: Default + Send + 'static,
 {
-    t.timestamp() * 1_000_000_000 + t.timestamp_subsec_nanos() as i64
+    Box::new(futures::future::ok(A::default()))
 }
 
-fn from_influent_error(err: influent::client::ClientError) -> failure::Error {
-    match err {
-        influent::client::ClientError::CouldNotComplete(m) => {
-            failure::err_msg(format!("could not complete: {}", m))
-        }
-        influent::client::ClientError::Communication(m) => {
-            failure::err_msg(format!("communication error: {}", m))
-        }
-        influent::client::ClientError::Syntax(m) => {
-            failure::err_msg(format!("syntax error: {}", m))
-        }
-        influent::client::ClientError::Unexpected(m) => {
-            failure::err_msg(format!("unexpected error: {}", m))
-        }
-        influent::client::ClientError::Unknown => failure::err_msg("unknown error"),
+/// Runs the InfluxQL query on InfluxDB 1.x, or the Flux query built from the quoted bucket and
+/// org on InfluxDB 2.x; both should result in series of the same shape.
+fn select<F>(client: &influx::Client, influxql: String, flux: F) -> Query<Vec<influx::Series>>
+where
+    F: FnOnce(&str, &str) -> String,
+{
+    match *client.auth() {
+        influx::Auth::V1 { .. } => client.influxql(influxql),
+        influx::Auth::V2 {
+            ref bucket,
+            ref org,
+            ..
+        } => client.flux(flux(
+            &influx::flux_string(bucket),
+            &influx::flux_string(org),
+        )),
     }
 }
 
-fn leak_static_str(s: String) -> &'static str {
-    // TODO: this is a hack due to an annoyance in the influent API.  With async/await in std, this
-    // should be avoidable, due to better lifetime support in async code.
-    unsafe {
-        let ret = ::std::mem::transmute(&s as &str);
-        ::std::mem::forget(s);
-        ret
+/// The min, max and last value of a plant.
+type PlantRange = (uuid::Uuid, Option<f64>, Option<f64>, Option<f64>);
+
+/// Where to find the min, max and last value of a quantity of the plants.
+struct PlantFields {
+    measurement: String,
+    min: String,
+    max: String,
+    last: String,
+}
+
+impl PlantFields {
+    fn raw(field: &str) -> Self {
+        PlantFields {
+            measurement: config::PLANT_MEASUREMENT.to_owned(),
+            min: field.to_owned(),
+            max: field.to_owned(),
+            last: field.to_owned(),
+        }
+    }
+
+    // Without raw samples, the min and max are those of the rollups, and the last value is the
+    // mean of the last rollup
+    fn rollup(field: &str) -> Self {
+        PlantFields {
+            measurement: format!(
+                "{}{}",
+                config::PLANT_MEASUREMENT,
+                rollup::MEASUREMENT_SUFFIX
+            ),
+            min: format!("{}_min", field),
+            max: format!("{}_max", field),
+            last: format!("{}_mean", field),
+        }
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
-struct QueryResults {
-    results: Vec<QueryResult>,
+/// The fields of the moisture rollups of a plant, in the order of `sample_timeseries`.
+const ROLLUP_FIELDS: [&str; 6] = [
+    "moisture_min",
+    "moisture_max",
+    "moisture_mean",
+    "moisture_p25",
+    "moisture_p50",
+    "moisture_p75",
+];
+
+fn sample_timeseries(
+    module_uuid: uuid::Uuid,
+    slice: chrono::DateTime<chrono::Utc>,
+    values: &[Option<f64>; 6],
+) -> Option<model::SampleTimeseries> {
+    match *values {
+        [Some(min), Some(max), Some(mean), Some(p25), Some(p50), Some(p75)] => {
+            Some(model::SampleTimeseries {
+                module_uuid,
+                slice,
+                min_raw_voltage: min,
+                max_raw_voltage: max,
+                mean_raw_voltage: mean,
+                p25_raw_voltage: p25,
+                p50_raw_voltage: p50,
+                p75_raw_voltage: p75,
+            })
+        }
+        _ => None,
+    }
 }
 
-#[derive(Clone, Debug, Deserialize)]
-struct QueryResult {
-    statement_id: Option<u32>,
-    #[serde(default)]
-    series: Vec<QuerySeries>,
+/// The measurement and field that the moisture indices of the plants are computed from; without
+/// raw samples, these are the means of the rollups.
+fn plant_index_source(store_raw: bool) -> (String, String) {
+    if store_raw {
+        (config::PLANT_MEASUREMENT.to_owned(), "moisture".to_owned())
+    } else {
+        (
+            format!(
+                "{}{}",
+                config::PLANT_MEASUREMENT,
+                rollup::MEASUREMENT_SUFFIX
+            ),
+            "moisture_mean".to_owned(),
+        )
+    }
 }
 
-#[derive(Clone, Debug, Deserialize)]
-struct QuerySeries {
-    name: String,
-    columns: Vec<String>,
-    values: Vec<Vec<f64>>,
+fn to_influx_timestamp<Tz>(t: chrono::DateTime<Tz>) -> i64
+where
+    Tz: chrono::TimeZone,
+{
+    t.timestamp() * 1_000_000_000 + t.timestamp_subsec_nanos() as i64
 }
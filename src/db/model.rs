@@ -7,6 +7,7 @@ pub struct SampleTimeseries {
     pub slice: chrono::DateTime<chrono::Utc>,
     pub min_raw_voltage: f64,
     pub max_raw_voltage: f64,
+    pub mean_raw_voltage: f64,
     pub p25_raw_voltage: f64,
     pub p50_raw_voltage: f64,
     pub p75_raw_voltage: f64,
@@ -27,9 +28,57 @@ pub struct Stats {
     pub last_moisture: f64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default, Serialize)]
 pub struct GlobalStats {
-    pub temperature: f64,
+    pub temperature: Option<f64>,
+    pub pressure: Option<f64>,
+    pub humidity: Option<f64>,
+}
+
+/// A measurement with its original time, like one being imported.
+#[derive(Clone, Debug)]
+pub struct Point {
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(String, Value)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Float(f64),
+    Boolean(bool),
+}
+
+impl Value {
+    pub fn as_f64(self) -> f64 {
+        match self {
+            Value::Float(v) => v,
+            Value::Boolean(b) => {
+                if b {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+
+    pub fn as_bool(self) -> bool {
+        match self {
+            Value::Float(v) => v != 0.0,
+            Value::Boolean(b) => b,
+        }
+    }
+}
+
+/// The value of one field of a measurement; booleans are 0 or 1.
+#[derive(Debug)]
+pub struct FieldValue {
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub plant_uuid: Option<uuid::Uuid>,
+    pub field: String,
+    pub value: f64,
 }
 
 #[derive(Debug)]
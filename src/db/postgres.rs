@@ -0,0 +1,653 @@
+//! Storage in PostgreSQL, optionally with the TimescaleDB extension, through diesel.
+//!
+//! Diesel is synchronous, so every query runs on a blocking thread of the runtime.
+
+use std::collections;
+
+use chrono;
+use diesel;
+use failure;
+use futures;
+use serde_json;
+use slog;
+use tokio_threadpool;
+use uuid;
+
+use diesel::prelude::*;
+use diesel::r2d2;
+use futures::Future;
+
+use config;
+use schema::calibrations;
+use schema::measurements;
+use schema::plants;
+use schema::pump_events;
+
+use super::model;
+use super::Query;
+
+embed_migrations!();
+
+type Pool = r2d2::Pool<r2d2::ConnectionManager<diesel::PgConnection>>;
+
+#[derive(Clone)]
+pub struct Store {
+    pool: Pool,
+    timescale: bool,
+}
+
+#[derive(Insertable)]
+#[table_name = "measurements"]
+struct NewMeasurement<'a> {
+    time: chrono::DateTime<chrono::Utc>,
+    measurement: &'a str,
+    tags: serde_json::Value,
+    field: &'a str,
+    value: f64,
+    plant_uuid: Option<uuid::Uuid>,
+}
+
+#[derive(Insertable)]
+#[table_name = "plants"]
+struct NewPlant<'a> {
+    uuid: uuid::Uuid,
+    name: &'a str,
+    description: &'a str,
+}
+
+#[derive(QueryableByName)]
+struct PlantRange {
+    #[sql_type = "diesel::sql_types::Uuid"]
+    plant_uuid: uuid::Uuid,
+    #[sql_type = "diesel::sql_types::Nullable<diesel::sql_types::Double>"]
+    min: Option<f64>,
+    #[sql_type = "diesel::sql_types::Nullable<diesel::sql_types::Double>"]
+    max: Option<f64>,
+    #[sql_type = "diesel::sql_types::Nullable<diesel::sql_types::Double>"]
+    last: Option<f64>,
+}
+
+impl Store {
+    /// Connections are only opened once they are needed, like for InfluxDB.
+    pub fn connect(config: &config::DbPostgres) -> Result<Self, failure::Error> {
+        let manager = r2d2::ConnectionManager::new(config.url.clone());
+        Ok(Store {
+            pool: r2d2::Pool::builder().build_unchecked(manager),
+            timescale: config.timescale,
+        })
+    }
+
+    /// Runs the pending migrations, and turns the time series tables into hypertables if
+    /// TimescaleDB is enabled.
+    pub fn ensure_schema(&self, log: slog::Logger) -> Query<()> {
+        let timescale = self.timescale;
+        self.blocking(move |conn| {
+            embedded_migrations::run(conn)?;
+            if timescale {
+                diesel::sql_query("CREATE EXTENSION IF NOT EXISTS timescaledb").execute(conn)?;
+                for table in &["measurements", "pump_events"] {
+                    info!(log, "ensuring hypertable table={:?}", table);
+                    diesel::sql_query(
+                        "SELECT create_hypertable($1, 'time', if_not_exists => TRUE, \
+                         migrate_data => TRUE)",
+                    )
+                    .bind::<diesel::sql_types::Text, _>(*table)
+                    .execute(conn)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    pub fn check(&self) -> Query<()> {
+        self.blocking(|conn| {
+            diesel::sql_query("SELECT 1").execute(conn)?;
+            Ok(())
+        })
+    }
+
+    /// Creates or updates the plants, by UUID, name and description.
+    pub fn register_plants(&self, plants: Vec<(uuid::Uuid, String, String)>) -> Query<()> {
+        self.blocking(move |conn| {
+            use diesel::pg::upsert::excluded;
+
+            let rows = plants
+                .iter()
+                .map(|&(uuid, ref name, ref description)| NewPlant {
+                    uuid,
+                    name,
+                    description,
+                })
+                .collect::<Vec<_>>();
+            diesel::insert_into(plants::table)
+                .values(&rows)
+                .on_conflict(plants::uuid)
+                .do_update()
+                .set((
+                    plants::name.eq(excluded(plants::name)),
+                    plants::description.eq(excluded(plants::description)),
+                    plants::updated_at.eq(diesel::dsl::now),
+                ))
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    pub fn insert_measurement(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+        name: &str,
+        tags: &[(String, String)],
+        fields: &[(String, f64)],
+    ) -> Query<()> {
+        let name = name.to_owned();
+        let tags = tags.to_vec();
+        let fields = fields.to_vec();
+        self.blocking(move |conn| write_measurement(conn, now, &name, &tags, &fields))
+    }
+
+    pub fn insert_pump_event(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+        uuid: uuid::Uuid,
+        running: bool,
+    ) -> Query<()> {
+        self.blocking(move |conn| write_pump_event(conn, now, uuid, running))
+    }
+
+    /// Inserts the points in one transaction; pump points become pump events.
+    pub fn insert_points(&self, points: Vec<model::Point>) -> Query<()> {
+        self.blocking(move |conn| {
+            conn.transaction(|| {
+                for point in &points {
+                    if point.measurement == "pump" {
+                        let uuid = point
+                            .tags
+                            .iter()
+                            .find(|&&(ref tag, _)| tag == "uuid")
+                            .and_then(|&(_, ref uuid)| uuid.parse().ok());
+                        let running = point
+                            .fields
+                            .iter()
+                            .find(|&&(ref field, _)| field == "running")
+                            .map(|&(_, value)| value.as_bool());
+                        match (uuid, running) {
+                            (Some(uuid), Some(running)) => {
+                                write_pump_event(conn, point.time, uuid, running)?
+                            }
+                            _ => bail!("Pump points need a uuid tag and a running field"),
+                        }
+                    } else {
+                        let fields = point
+                            .fields
+                            .iter()
+                            .map(|&(ref field, value)| (field.clone(), value.as_f64()))
+                            .collect::<Vec<_>>();
+                        write_measurement(
+                            conn,
+                            point.time,
+                            &point.measurement,
+                            &point.tags,
+                            &fields,
+                        )?;
+                    }
+                }
+                Ok(())
+            })
+        })
+    }
+
+    /// Computes the moisture range of every plant during the last week from the given field.
+    pub fn update_plant_indices(&self, measurement: String, field: String) -> Query<()> {
+        self.blocking(move |conn| {
+            diesel::sql_query(
+                "INSERT INTO calibrations (plant_uuid, moisture_p05, moisture_p95, updated_at) \
+                 SELECT plant_uuid, \
+                 percentile_disc(0.05) WITHIN GROUP (ORDER BY value), \
+                 percentile_disc(0.95) WITHIN GROUP (ORDER BY value), \
+                 now() \
+                 FROM measurements \
+                 WHERE plant_uuid IS NOT NULL AND measurement = $1 AND field = $2 \
+                 AND time > now() - interval '1 week' \
+                 GROUP BY plant_uuid \
+                 ON CONFLICT (plant_uuid) DO UPDATE SET \
+                 moisture_p05 = excluded.moisture_p05, \
+                 moisture_p95 = excluded.moisture_p95, \
+                 updated_at = excluded.updated_at",
+            )
+            .bind::<diesel::sql_types::Text, _>(&measurement)
+            .bind::<diesel::sql_types::Text, _>(&field)
+            .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    pub fn fetch_moisture_voltage_range(
+        &self,
+        uuid: uuid::Uuid,
+    ) -> Query<(Option<f64>, Option<f64>)> {
+        self.blocking(move |conn| {
+            let range = calibrations::table
+                .find(uuid)
+                .select((calibrations::moisture_p05, calibrations::moisture_p95))
+                .first::<(Option<f64>, Option<f64>)>(conn)
+                .optional()?;
+            Ok(range.unwrap_or((None, None)))
+        })
+    }
+
+    /// The fields of the rollups of all plants in a measurement, by plant, time and field.
+    pub fn collect_rollups(
+        &self,
+        measurement: String,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+    ) -> Query<Vec<(uuid::Uuid, chrono::DateTime<chrono::Utc>, String, f64)>> {
+        self.blocking(move |conn| {
+            let rows = measurements::table
+                .filter(measurements::measurement.eq(&measurement))
+                .filter(measurements::plant_uuid.is_not_null())
+                .filter(measurements::time.ge(from))
+                .filter(measurements::time.lt(to))
+                .order((measurements::plant_uuid, measurements::time))
+                .select((
+                    measurements::plant_uuid,
+                    measurements::time,
+                    measurements::field,
+                    measurements::value,
+                ))
+                .load::<(
+                    Option<uuid::Uuid>,
+                    chrono::DateTime<chrono::Utc>,
+                    String,
+                    f64,
+                )>(conn)?;
+            Ok(rows
+                .into_iter()
+                .filter_map(|(uuid, time, field, value)| {
+                    uuid.map(|uuid| (uuid, time, field, value))
+                })
+                .collect())
+        })
+    }
+
+    /// The min, max and last value of every plant in a measurement, each of a field of its own.
+    pub fn collect_plant_ranges(
+        &self,
+        measurement: String,
+        min_field: String,
+        max_field: String,
+        last_field: String,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+    ) -> Query<Vec<(uuid::Uuid, Option<f64>, Option<f64>, Option<f64>)>> {
+        self.blocking(move |conn| {
+            let rows = diesel::sql_query(
+                "SELECT plant_uuid, \
+                 min(value) FILTER (WHERE field = $2) AS min, \
+                 max(value) FILTER (WHERE field = $3) AS max, \
+                 (array_agg(value ORDER BY time DESC) FILTER (WHERE field = $4))[1] AS last \
+                 FROM measurements \
+                 WHERE plant_uuid IS NOT NULL AND measurement = $1 \
+                 AND field IN ($2, $3, $4) AND time >= $5 AND time < $6 \
+                 GROUP BY plant_uuid",
+            )
+            .bind::<diesel::sql_types::Text, _>(&measurement)
+            .bind::<diesel::sql_types::Text, _>(&min_field)
+            .bind::<diesel::sql_types::Text, _>(&max_field)
+            .bind::<diesel::sql_types::Text, _>(&last_field)
+            .bind::<diesel::sql_types::Timestamptz, _>(from)
+            .bind::<diesel::sql_types::Timestamptz, _>(to)
+            .load::<PlantRange>(conn)?;
+            Ok(rows
+                .into_iter()
+                .map(|r| (r.plant_uuid, r.min, r.max, r.last))
+                .collect())
+        })
+    }
+
+    pub fn collect_fields(
+        &self,
+        measurement: String,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+    ) -> Query<Vec<model::FieldValue>> {
+        self.blocking(move |conn| {
+            let rows = measurements::table
+                .filter(measurements::measurement.eq(&measurement))
+                .filter(measurements::time.ge(from))
+                .filter(measurements::time.lt(to))
+                .order(measurements::time)
+                .select((
+                    measurements::time,
+                    measurements::plant_uuid,
+                    measurements::field,
+                    measurements::value,
+                ))
+                .load::<(
+                    chrono::DateTime<chrono::Utc>,
+                    Option<uuid::Uuid>,
+                    String,
+                    f64,
+                )>(conn)?;
+            Ok(rows
+                .into_iter()
+                .map(|(time, plant_uuid, field, value)| model::FieldValue {
+                    time,
+                    plant_uuid,
+                    field,
+                    value,
+                })
+                .collect())
+        })
+    }
+
+    pub fn collect_pump_events(
+        &self,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+    ) -> Query<Vec<model::PumpEvent>> {
+        self.blocking(move |conn| {
+            let rows = pump_events::table
+                .filter(pump_events::time.ge(from))
+                .filter(pump_events::time.lt(to))
+                .order(pump_events::time)
+                .select((
+                    pump_events::time,
+                    pump_events::plant_uuid,
+                    pump_events::running,
+                ))
+                .load::<(chrono::DateTime<chrono::Utc>, uuid::Uuid, bool)>(conn)?;
+            Ok(rows
+                .into_iter()
+                .map(|(created, module_uuid, pump_running)| model::PumpEvent {
+                    created,
+                    module_uuid,
+                    pump_running,
+                })
+                .collect())
+        })
+    }
+
+    pub fn collect_global_stats(&self) -> Query<model::GlobalStats> {
+        self.blocking(|conn| {
+            let latest = measurements::table
+                .filter(measurements::measurement.eq("global"))
+                .filter(measurements::field.eq_any(vec!["temperature", "pressure", "humidity"]))
+                .distinct_on(measurements::field)
+                .order((measurements::field, measurements::time.desc()))
+                .select((measurements::field, measurements::value))
+                .load::<(String, f64)>(conn)?
+                .into_iter()
+                .collect::<collections::HashMap<_, _>>();
+            Ok(model::GlobalStats {
+                temperature: latest.get("temperature").cloned(),
+                pressure: latest.get("pressure").cloned(),
+                humidity: latest.get("humidity").cloned(),
+            })
+        })
+    }
+
+    /// Runs the query with a pooled connection on a blocking thread.
+    fn blocking<A, F>(&self, query: F) -> Query<A>
+    where
+        A: Send + 'static,
+        F: Fn(&diesel::PgConnection) -> Result<A, failure::Error> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        Box::new(
+            futures::future::poll_fn(move || {
+                tokio_threadpool::blocking(|| {
+                    let conn = pool.get()?;
+                    query(&conn)
+                })
+            })
+            .map_err(failure::Error::from)
+            .and_then(|result| result),
+        )
+    }
+}
+
+fn write_measurement(
+    conn: &diesel::PgConnection,
+    now: chrono::DateTime<chrono::Utc>,
+    name: &str,
+    tags: &[(String, String)],
+    fields: &[(String, f64)],
+) -> Result<(), failure::Error> {
+    use diesel::pg::upsert::excluded;
+
+    let tags = tags
+        .iter()
+        .cloned()
+        .collect::<collections::BTreeMap<_, _>>();
+    let plant_uuid = tags.get("uuid").and_then(|u| u.parse().ok());
+    let tags = serde_json::to_value(&tags)?;
+    let rows = fields
+        .iter()
+        .map(|&(ref field, value)| NewMeasurement {
+            time: now,
+            measurement: name,
+            tags: tags.clone(),
+            field,
+            value,
+            plant_uuid,
+        })
+        .collect::<Vec<_>>();
+
+    // Writing the same point again replaces it, like in InfluxDB
+    diesel::insert_into(measurements::table)
+        .values(&rows)
+        .on_conflict((
+            measurements::measurement,
+            measurements::tags,
+            measurements::field,
+            measurements::time,
+        ))
+        .do_update()
+        .set(measurements::value.eq(excluded(measurements::value)))
+        .execute(conn)?;
+    Ok(())
+}
+
+fn write_pump_event(
+    conn: &diesel::PgConnection,
+    now: chrono::DateTime<chrono::Utc>,
+    uuid: uuid::Uuid,
+    running: bool,
+) -> Result<(), failure::Error> {
+    diesel::insert_into(pump_events::table)
+        .values((
+            pump_events::time.eq(now),
+            pump_events::plant_uuid.eq(uuid),
+            pump_events::running.eq(running),
+        ))
+        .on_conflict((pump_events::plant_uuid, pump_events::time))
+        .do_update()
+        .set(pump_events::running.eq(running))
+        .execute(conn)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    use tokio;
+
+    // The tests need a database that they may write to, like
+    // `PRECIP_TEST_POSTGRES_URL=postgres://localhost/precip_test`; without one, they are skipped
+    fn store() -> Option<(tokio::runtime::Runtime, Store)> {
+        let url = env::var("PRECIP_TEST_POSTGRES_URL").ok()?;
+        let store = Store::connect(&config::DbPostgres {
+            url,
+            timescale: false,
+        })
+        .unwrap();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(store.ensure_schema(log())).unwrap();
+        Some((runtime, store))
+    }
+
+    fn log() -> slog::Logger {
+        slog::Logger::root(slog::Discard, o!())
+    }
+
+    // Every test has plants of its own, since the tests share the database
+    fn plant(runtime: &mut tokio::runtime::Runtime, store: &Store, uuid: &str) -> uuid::Uuid {
+        let uuid = uuid.parse::<uuid::Uuid>().unwrap();
+        runtime
+            .block_on(store.blocking(move |conn| {
+                diesel::delete(measurements::table.filter(measurements::plant_uuid.eq(uuid)))
+                    .execute(conn)?;
+                diesel::delete(calibrations::table.find(uuid)).execute(conn)?;
+                Ok(())
+            }))
+            .unwrap();
+        uuid
+    }
+
+    fn insert(
+        runtime: &mut tokio::runtime::Runtime,
+        store: &Store,
+        time: chrono::DateTime<chrono::Utc>,
+        uuid: uuid::Uuid,
+        fields: &[(&str, f64)],
+    ) {
+        let fields = fields
+            .iter()
+            .map(|&(field, value)| (field.to_owned(), value))
+            .collect::<Vec<_>>();
+        runtime
+            .block_on(store.insert_measurement(
+                time,
+                "plant",
+                &[("uuid".to_owned(), uuid.to_string())],
+                &fields,
+            ))
+            .unwrap();
+    }
+
+    #[test]
+    fn migrations_can_run_again() {
+        let (mut runtime, store) = match store() {
+            Some(store) => store,
+            None => return,
+        };
+        runtime.block_on(store.ensure_schema(log())).unwrap();
+        runtime.block_on(store.check()).unwrap();
+
+        let uuid = plant(&mut runtime, &store, "5f0e5a48-3f2a-4c1e-9a43-0d1c64f3a101");
+        assert_eq!(
+            runtime
+                .block_on(store.fetch_moisture_voltage_range(uuid))
+                .unwrap(),
+            (None, None)
+        );
+    }
+
+    #[test]
+    fn plant_indices_are_upserted_from_the_last_week() {
+        let (mut runtime, store) = match store() {
+            Some(store) => store,
+            None => return,
+        };
+        let uuid = plant(&mut runtime, &store, "5f0e5a48-3f2a-4c1e-9a43-0d1c64f3a102");
+        let now = chrono::Utc::now();
+
+        insert(
+            &mut runtime,
+            &store,
+            now - chrono::Duration::days(8),
+            uuid,
+            &[("moisture", 100.0)],
+        );
+        for i in 1..21 {
+            let time = now - chrono::Duration::minutes(i);
+            insert(&mut runtime, &store, time, uuid, &[("moisture", i as f64)]);
+        }
+        runtime
+            .block_on(store.update_plant_indices("plant".to_owned(), "moisture".to_owned()))
+            .unwrap();
+        // percentile_disc picks actual readings, like InfluxDB does
+        assert_eq!(
+            runtime
+                .block_on(store.fetch_moisture_voltage_range(uuid))
+                .unwrap(),
+            (Some(1.0), Some(19.0))
+        );
+
+        for i in 21..41 {
+            let time = now - chrono::Duration::minutes(i);
+            insert(&mut runtime, &store, time, uuid, &[("moisture", i as f64)]);
+        }
+        runtime
+            .block_on(store.update_plant_indices("plant".to_owned(), "moisture".to_owned()))
+            .unwrap();
+        assert_eq!(
+            runtime
+                .block_on(store.fetch_moisture_voltage_range(uuid))
+                .unwrap(),
+            (Some(2.0), Some(38.0))
+        );
+    }
+
+    #[test]
+    fn writing_a_point_again_replaces_it() {
+        let (mut runtime, store) = match store() {
+            Some(store) => store,
+            None => return,
+        };
+        let uuid = plant(&mut runtime, &store, "5f0e5a48-3f2a-4c1e-9a43-0d1c64f3a103");
+        let time = chrono::Utc::now() - chrono::Duration::minutes(1);
+
+        insert(&mut runtime, &store, time, uuid, &[("moisture", 1.0)]);
+        insert(&mut runtime, &store, time, uuid, &[("moisture", 2.0)]);
+        let values = runtime
+            .block_on(store.collect_fields(
+                "plant".to_owned(),
+                time - chrono::Duration::minutes(1),
+                time + chrono::Duration::minutes(1),
+            ))
+            .unwrap()
+            .into_iter()
+            .filter(|v| v.plant_uuid == Some(uuid))
+            .map(|v| v.value)
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![2.0]);
+    }
+
+    #[test]
+    fn plant_ranges_are_of_their_own_fields() {
+        let (mut runtime, store) = match store() {
+            Some(store) => store,
+            None => return,
+        };
+        let uuid = plant(&mut runtime, &store, "5f0e5a48-3f2a-4c1e-9a43-0d1c64f3a104");
+        let now = chrono::Utc::now();
+
+        for &(minutes, voltage, moisture) in &[(3, 1.6, 0.4), (2, 2.1, 0.9), (1, 1.9, 0.5)] {
+            insert(
+                &mut runtime,
+                &store,
+                now - chrono::Duration::minutes(minutes),
+                uuid,
+                &[("voltage", voltage), ("moisture", moisture)],
+            );
+        }
+        let ranges = runtime
+            .block_on(store.collect_plant_ranges(
+                "plant".to_owned(),
+                "voltage".to_owned(),
+                "voltage".to_owned(),
+                "moisture".to_owned(),
+                now - chrono::Duration::hours(1),
+                now,
+            ))
+            .unwrap();
+        let range = ranges.into_iter().find(|r| r.0 == uuid).unwrap();
+        assert_eq!(range, (uuid, Some(1.6), Some(2.1), Some(0.5)));
+    }
+}
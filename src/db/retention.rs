@@ -0,0 +1,239 @@
+//! Retention policies and continuous queries that precip keeps up to date in InfluxDB 1.x.
+//!
+//! Raw samples are kept for a limited time in the default policy, and a downsampled copy of them
+//! is kept forever.  The default policy is altered rather than replaced, so that the samples that
+//! are already in it stay visible.  Events like pump runs are neither samples nor averaged, so they
+//! are kept forever in a policy of their own.  The continuous queries that precip owns are recreated on every
+//! start, so that they follow changes to the config.
+
+use failure;
+use slog;
+
+use futures::prelude::async;
+use futures::prelude::await;
+
+use config;
+
+use super::influx;
+use super::influxql;
+
+// the default policy, if the database doesn't have one yet
+const RAW_POLICY: &str = "raw";
+const DOWNSAMPLED_POLICY: &str = "downsampled";
+pub const EVENTS_POLICY: &str = "events";
+// the measurements that are written to the events policy
+const EVENT_MEASUREMENTS: &[&str] = &[
+    "pump",
+    "watering_failed",
+    "sensor_health",
+    "daily_light_integral",
+];
+// continuous queries with this prefix are owned by precip, and dropped when no longer configured
+const QUERY_PREFIX: &str = "precip_";
+
+#[async]
+pub fn ensure(
+    log: slog::Logger,
+    client: influx::Client,
+    database: String,
+    retention: config::Retention,
+) -> Result<(), failure::Error> {
+    let db = influxql::identifier(&database);
+
+    let existing = await!(client.influxql(format!("SHOW RETENTION POLICIES ON {}", db)))?;
+    let mut policies = Vec::new();
+    for series in &existing {
+        for row in series.rows() {
+            if let Some(name) = row.str("name")? {
+                policies.push((name.to_owned(), row.bool("default")?.unwrap_or(false)));
+            }
+        }
+    }
+
+    let raw_policy = raw_policy(&policies);
+    for (name, duration, shard_duration, default) in vec![
+        (
+            raw_policy.clone(),
+            format!("{}d", retention.raw_days),
+            Some(shard_duration(retention.raw_days)),
+            true,
+        ),
+        (DOWNSAMPLED_POLICY.to_owned(), "INF".to_owned(), None, false),
+        (EVENTS_POLICY.to_owned(), "INF".to_owned(), None, false),
+    ] {
+        let statement = format!(
+            "{} RETENTION POLICY {} ON {} DURATION {} REPLICATION 1{}{}",
+            if policies.iter().any(|&(ref p, _)| *p == name) {
+                "ALTER"
+            } else {
+                "CREATE"
+            },
+            influxql::identifier(&name),
+            db,
+            duration,
+            shard_duration.map_or_else(String::new, |d| format!(" SHARD DURATION {}", d)),
+            if default { " DEFAULT" } else { "" }
+        );
+        info!(
+            log,
+            "ensuring retention policy name={:?} duration={}", name, duration
+        );
+        await!(client.influxql(statement))?;
+    }
+
+    // Events used to be written to the raw policy, and would expire there
+    info!(
+        log,
+        "copying events to their policy name={:?}", EVENTS_POLICY
+    );
+    await!(client.influxql(copy_events(&database, &raw_policy)))?;
+
+    let existing = await!(client.influxql("SHOW CONTINUOUS QUERIES".to_owned()))?;
+    let mut stale = Vec::new();
+    for series in existing.iter().filter(|s| s.name == database) {
+        for row in series.rows() {
+            if let Some(name) = row.str("name")? {
+                if name.starts_with(QUERY_PREFIX) {
+                    stale.push(name.to_owned());
+                }
+            }
+        }
+    }
+    for name in stale {
+        await!(client.influxql(format!(
+            "DROP CONTINUOUS QUERY {} ON {}",
+            influxql::identifier(&name),
+            db
+        )))?;
+    }
+
+    for (name, query) in continuous_queries(&database, &raw_policy, &retention) {
+        info!(log, "creating continuous query name={:?}", name);
+        await!(client.influxql(query))?;
+    }
+
+    Ok(())
+}
+
+/// The policy to keep raw samples in: the current default one, like `autogen`, so that the samples
+/// written before precip managed the policies stay in it.
+fn raw_policy(policies: &[(String, bool)]) -> String {
+    policies
+        .iter()
+        .find(|&&(ref name, default)| default && name != DOWNSAMPLED_POLICY)
+        .map_or_else(|| RAW_POLICY.to_owned(), |&(ref name, _)| name.clone())
+}
+
+/// Whether points of the measurement are events rather than samples.
+pub fn is_event(measurement: &str) -> bool {
+    EVENT_MEASUREMENTS.contains(&measurement)
+}
+
+// Copying again overwrites the points with themselves
+fn copy_events(database: &str, raw_policy: &str) -> String {
+    let db = influxql::identifier(database);
+    format!(
+        "SELECT * INTO {}.{}.:MEASUREMENT FROM {} GROUP BY *",
+        db,
+        influxql::identifier(EVENTS_POLICY),
+        EVENT_MEASUREMENTS
+            .iter()
+            .map(|measurement| format!(
+                "{}.{}.{}",
+                db,
+                influxql::identifier(raw_policy),
+                influxql::identifier(measurement)
+            ))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+/// The shard group duration that InfluxDB would pick for a new policy of the duration.  Altering
+/// the duration of a policy like `autogen`, whose shards last 7 days, to less than that fails
+/// unless the shard duration changes too.
+fn shard_duration(days: u32) -> &'static str {
+    match days {
+        0...1 => "1h",
+        2...180 => "1d",
+        _ => "7d",
+    }
+}
+
+// The plant indices aren't kept up to date by a continuous query, since those only aggregate over
+// intervals aligned to the epoch, and the indices are over the last 7 days
+fn continuous_queries(
+    database: &str,
+    raw_policy: &str,
+    retention: &config::Retention,
+) -> Vec<(String, String)> {
+    let db = influxql::identifier(database);
+    let name = |suffix: &str| format!("{}{}", QUERY_PREFIX, suffix);
+
+    vec![(
+        name("downsample"),
+        format!(
+            "CREATE CONTINUOUS QUERY {} ON {} BEGIN \
+             SELECT mean(*) INTO {}.{}.:MEASUREMENT FROM {}.{}./.*/ \
+             GROUP BY time({}m), * \
+             END",
+            influxql::identifier(&name("downsample")),
+            db,
+            db,
+            influxql::identifier(DOWNSAMPLED_POLICY),
+            db,
+            influxql::identifier(raw_policy),
+            retention.downsample_interval_minutes
+        ),
+    )]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_samples_stay_in_the_default_policy() {
+        let policies = vec![
+            ("autogen".to_owned(), true),
+            (DOWNSAMPLED_POLICY.to_owned(), false),
+        ];
+        assert_eq!(raw_policy(&policies), "autogen");
+
+        let policies = vec![("autogen".to_owned(), false), (RAW_POLICY.to_owned(), true)];
+        assert_eq!(raw_policy(&policies), RAW_POLICY);
+    }
+
+    #[test]
+    fn events_are_copied_out_of_the_raw_policy() {
+        assert_eq!(
+            copy_events("precip", "autogen"),
+            "SELECT * INTO \"precip\".\"events\".:MEASUREMENT FROM \
+             \"precip\".\"autogen\".\"pump\", \
+             \"precip\".\"autogen\".\"watering_failed\", \
+             \"precip\".\"autogen\".\"sensor_health\", \
+             \"precip\".\"autogen\".\"daily_light_integral\" \
+             GROUP BY *"
+        );
+        assert!(is_event("pump"));
+        assert!(!is_event("plant"));
+    }
+
+    #[test]
+    fn shards_are_never_longer_than_the_policy() {
+        assert_eq!(shard_duration(1), "1h");
+        assert_eq!(shard_duration(2), "1d");
+        assert_eq!(shard_duration(6), "1d");
+        assert_eq!(shard_duration(180), "1d");
+        assert_eq!(shard_duration(365), "7d");
+    }
+
+    #[test]
+    fn raw_samples_need_a_policy_other_than_the_downsampled_one() {
+        assert_eq!(raw_policy(&[]), RAW_POLICY);
+        assert_eq!(
+            raw_policy(&[(DOWNSAMPLED_POLICY.to_owned(), true)]),
+            RAW_POLICY
+        );
+    }
+}
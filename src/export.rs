@@ -0,0 +1,368 @@
+//! Export of the stored plant, climate and pump data, for spreadsheets and data analysis tools.
+
+use std::cmp;
+use std::collections;
+use std::env;
+use std::fs;
+use std::process;
+use std::rc;
+use std::str;
+use std::sync;
+use std::thread;
+use std::time;
+
+use chrono;
+use chrono_tz;
+use csv;
+use failure;
+use futures;
+use parquet;
+use uuid;
+
+use futures::Future;
+use futures::Stream;
+
+use db;
+use schedule;
+
+const MEASUREMENTS: &[&str] = &["plant", "global", "pump"];
+// Data is queried one day at a time, so that long exports don't have to fit in memory
+const CHUNK_DAYS: i64 = 1;
+// how much of a finished file to send at a time
+const BLOCK_BYTES: usize = 64 * 1024;
+
+const PARQUET_SCHEMA: &str = "
+message export {
+    REQUIRED INT64 time (TIMESTAMP_MILLIS);
+    REQUIRED BYTE_ARRAY measurement (UTF8);
+    OPTIONAL BYTE_ARRAY plant_uuid (UTF8);
+    OPTIONAL BYTE_ARRAY plant_name (UTF8);
+    REQUIRED BYTE_ARRAY field (UTF8);
+    REQUIRED DOUBLE value;
+}
+";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    Csv,
+    Parquet,
+}
+
+/// One value of one field, with the plant it belongs to if any.
+#[derive(Clone, Debug, Serialize)]
+pub struct Row {
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub measurement: String,
+    pub plant_uuid: Option<uuid::Uuid>,
+    pub plant_name: Option<String>,
+    pub field: String,
+    pub value: f64,
+}
+
+pub struct Exporter {
+    db: sync::Arc<db::Db>,
+    plant_names: collections::HashMap<uuid::Uuid, String>,
+    timezone: chrono_tz::Tz,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Parquet => "parquet",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv",
+            Format::Parquet => "application/octet-stream",
+        }
+    }
+}
+
+impl str::FromStr for Format {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "parquet" => Ok(Format::Parquet),
+            _ => bail!("Unknown export format {:?}; use csv or parquet", s),
+        }
+    }
+}
+
+impl Exporter {
+    pub fn new(
+        db: sync::Arc<db::Db>,
+        plant_names: collections::HashMap<uuid::Uuid, String>,
+        timezone: chrono_tz::Tz,
+    ) -> Self {
+        Exporter {
+            db,
+            plant_names,
+            timezone,
+        }
+    }
+
+    /// The current day in the timezone of the location.
+    pub fn today(&self) -> chrono::NaiveDate {
+        chrono::Utc::now()
+            .with_timezone(&self.timezone)
+            .date()
+            .naive_local()
+    }
+
+    /// The time range from the start of the first day to the end of the last day, in the
+    /// timezone of the location.
+    pub fn days(
+        &self,
+        first: chrono::NaiveDate,
+        last: chrono::NaiveDate,
+    ) -> (chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>) {
+        (
+            schedule::resolve_wall_clock(self.timezone, first.and_hms(0, 0, 0)),
+            schedule::resolve_wall_clock(
+                self.timezone,
+                (last + chrono::Duration::days(1)).and_hms(0, 0, 0),
+            ),
+        )
+    }
+
+    /// The rows from the time range, one chunk at a time.  Only the given plants are included if
+    /// there are any; data that doesn't belong to a plant, like the climate, is always included.
+    pub fn rows(
+        &self,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+        plants: Vec<uuid::Uuid>,
+    ) -> impl Stream<Item = Vec<Row>, Error = failure::Error> + Send {
+        let db = self.db.clone();
+        let plant_names = self.plant_names.clone();
+
+        let mut chunks = Vec::new();
+        let mut start = from;
+        while start < to {
+            let end = cmp::min(start + chrono::Duration::days(CHUNK_DAYS), to);
+            for measurement in MEASUREMENTS {
+                chunks.push((*measurement, start, end));
+            }
+            start = end;
+        }
+
+        futures::stream::iter_ok(chunks).and_then(move |(measurement, start, end)| {
+            let plants = plants.clone();
+            let plant_names = plant_names.clone();
+            db.collect_fields(measurement, start, end)
+                .map(move |values| {
+                    values
+                        .into_iter()
+                        .filter(|v| {
+                            plants.is_empty() || v.plant_uuid.map_or(true, |u| plants.contains(&u))
+                        })
+                        .map(|v| Row {
+                            time: v.time,
+                            measurement: measurement.to_owned(),
+                            plant_uuid: v.plant_uuid,
+                            plant_name: v.plant_uuid.and_then(|u| plant_names.get(&u).cloned()),
+                            field: v.field,
+                            value: v.value,
+                        })
+                        .collect()
+                })
+        })
+    }
+
+    /// The rows as CSV, with the header in the first chunk.
+    pub fn csv(
+        &self,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+        plants: Vec<uuid::Uuid>,
+    ) -> impl Stream<Item = Vec<u8>, Error = failure::Error> + Send {
+        let mut header = true;
+        self.rows(from, to, plants).and_then(move |rows| {
+            let chunk = to_csv(&rows, header);
+            header = false;
+            chunk
+        })
+    }
+
+    /// Writes the rows to a Parquet file, with a row group for every chunk, so that only one
+    /// chunk is in memory at a time.  The Parquet writer can't move between threads, so it runs on
+    /// a thread of its own.
+    pub fn parquet(
+        &self,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+        plants: Vec<uuid::Uuid>,
+        file: fs::File,
+    ) -> impl Future<Item = (), Error = failure::Error> + Send {
+        use futures::Sink;
+
+        // One chunk can wait while the writer writes the previous one
+        let (sender, receiver) = futures::sync::mpsc::channel::<Vec<Row>>(1);
+        let (done, written) = futures::sync::oneshot::channel();
+        thread::spawn(move || {
+            let result = (|| {
+                let mut writer = ParquetWriter::new(file)?;
+                for rows in receiver.wait() {
+                    let rows = rows.map_err(|()| format_err!("the export was aborted"))?;
+                    writer.write(&rows)?;
+                }
+                writer.close()
+            })();
+            let _ = done.send(result);
+        });
+
+        self.rows(from, to, plants)
+            .forward(sender.sink_map_err(|e| format_err!("the Parquet writer stopped: {}", e)))
+            // Dropping the sender lets the writer finish the file
+            .map(|_| ())
+            .then(move |sent| {
+                // An error of the writer explains why sending to it failed
+                written.then(move |written| match (sent, written) {
+                    (_, Ok(Err(e))) => Err(e),
+                    (Err(e), _) => Err(e),
+                    (Ok(()), Ok(Ok(()))) => Ok(()),
+                    (Ok(()), Err(_)) => Err(format_err!("the Parquet writer panicked")),
+                })
+            })
+    }
+}
+
+pub fn to_csv(rows: &[Row], header: bool) -> Result<Vec<u8>, failure::Error> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(header)
+        .from_writer(Vec::new());
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    // A header is only written with the first row
+    if header && rows.is_empty() {
+        writer.write_record(&[
+            "time",
+            "measurement",
+            "plant_uuid",
+            "plant_name",
+            "field",
+            "value",
+        ])?;
+    }
+    Ok(writer.into_inner().map_err(|e| e.into_error())?)
+}
+
+/// Writes a Parquet file with a row group for every non-empty chunk.
+struct ParquetWriter {
+    writer: parquet::file::writer::SerializedFileWriter<fs::File>,
+}
+
+impl ParquetWriter {
+    fn new(file: fs::File) -> Result<Self, failure::Error> {
+        let schema = rc::Rc::new(parquet::schema::parser::parse_message_type(PARQUET_SCHEMA)?);
+        let properties =
+            rc::Rc::new(parquet::file::properties::WriterProperties::builder().build());
+        Ok(ParquetWriter {
+            writer: parquet::file::writer::SerializedFileWriter::new(file, schema, properties)?,
+        })
+    }
+
+    fn write(&mut self, rows: &[Row]) -> Result<(), failure::Error> {
+        use parquet::column::writer::ColumnWriter;
+        use parquet::data_type::ByteArray;
+        use parquet::file::writer::FileWriter;
+        use parquet::file::writer::RowGroupWriter;
+
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        let mut row_group = self.writer.next_row_group()?;
+        let mut column = 0;
+        while let Some(mut column_writer) = row_group.next_column()? {
+            match (column, &mut column_writer) {
+                (0, &mut ColumnWriter::Int64ColumnWriter(ref mut w)) => {
+                    let values = rows
+                        .iter()
+                        .map(|r| r.time.timestamp_millis())
+                        .collect::<Vec<_>>();
+                    w.write_batch(&values, None, None)?;
+                }
+                (5, &mut ColumnWriter::DoubleColumnWriter(ref mut w)) => {
+                    let values = rows.iter().map(|r| r.value).collect::<Vec<_>>();
+                    w.write_batch(&values, None, None)?;
+                }
+                (_, &mut ColumnWriter::ByteArrayColumnWriter(ref mut w)) => {
+                    let strings = rows
+                        .iter()
+                        .map(|r| match column {
+                            1 => Some(r.measurement.clone()),
+                            2 => r.plant_uuid.map(|u| u.to_hyphenated().to_string()),
+                            3 => r.plant_name.clone(),
+                            _ => Some(r.field.clone()),
+                        })
+                        .collect::<Vec<_>>();
+                    let values = strings
+                        .iter()
+                        .filter_map(|s| s.as_ref().map(|s| ByteArray::from(s.as_str())))
+                        .collect::<Vec<_>>();
+                    // Only the plant columns are optional
+                    if column == 2 || column == 3 {
+                        let definitions = strings
+                            .iter()
+                            .map(|s| if s.is_some() { 1 } else { 0 })
+                            .collect::<Vec<_>>();
+                        w.write_batch(&values, Some(&definitions), None)?;
+                    } else {
+                        w.write_batch(&values, None, None)?;
+                    }
+                }
+                _ => bail!("Unexpected type of Parquet column {}", column),
+            }
+            row_group.close_column(column_writer)?;
+            column += 1;
+        }
+        self.writer.close_row_group(row_group)?;
+        Ok(())
+    }
+
+    fn close(mut self) -> Result<(), failure::Error> {
+        use parquet::file::writer::FileWriter;
+
+        self.writer.close()?;
+        Ok(())
+    }
+}
+
+/// Reads a file in blocks from where it is at, like a Parquet file that was just written.
+pub fn file_blocks(file: fs::File) -> impl Stream<Item = Vec<u8>, Error = failure::Error> + Send {
+    use std::io::Read;
+
+    futures::stream::unfold(file, |mut file| {
+        let mut block = vec![0; BLOCK_BYTES];
+        match file.read(&mut block) {
+            Ok(0) => None,
+            Ok(n) => {
+                block.truncate(n);
+                Some(futures::future::ok((block, file)))
+            }
+            Err(e) => Some(futures::future::err(e.into())),
+        }
+    })
+}
+
+/// An anonymous file, deleted when closed.
+pub fn tempfile() -> Result<fs::File, failure::Error> {
+    let nanos = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)?
+        .subsec_nanos();
+    let path = env::temp_dir().join(format!("precip-export-{}-{}.parquet", process::id(), nanos));
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    fs::remove_file(&path)?;
+    Ok(file)
+}
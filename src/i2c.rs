@@ -0,0 +1,135 @@
+//! Health of the devices on the I2C buses, and scanning buses for devices.
+
+use std::collections;
+use std::fmt;
+use std::io;
+use std::path;
+use std::sync;
+
+use failure;
+use i2cdev;
+
+/// How many consecutive failures of a device to tolerate before reopening it.
+const RECOVERY_THRESHOLD: u64 = 3;
+// The errno that selecting an address that a kernel driver has claimed fails with, on Linux
+const EBUSY: i32 = 16;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeviceHealth {
+    pub bus: String,
+    pub address: u16,
+    pub errors: u64,
+    pub consecutive_errors: u64,
+    pub recoveries: u64,
+    pub last_error: Option<String>,
+}
+
+/// Error counts of every I2C device, shared between everything that talks to them.
+pub struct Health {
+    devices: sync::Mutex<collections::HashMap<(String, u16), DeviceHealth>>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Health {
+            devices: sync::Mutex::new(collections::HashMap::new()),
+        }
+    }
+
+    /// Records a successful transfer, and returns how many transfers failed before it.
+    pub fn record_success(&self, bus: &str, address: u16) -> u64 {
+        let mut devices = self.devices.lock().unwrap();
+        let device = entry(&mut devices, bus, address);
+        let failed = device.consecutive_errors;
+        device.consecutive_errors = 0;
+        failed
+    }
+
+    /// Records a failed transfer, and returns whether the device should be reopened.
+    pub fn record_failure<E>(&self, bus: &str, address: u16, error: &E) -> bool
+    where
+        E: fmt::Display,
+    {
+        let mut devices = self.devices.lock().unwrap();
+        let device = entry(&mut devices, bus, address);
+        device.errors += 1;
+        device.consecutive_errors += 1;
+        device.last_error = Some(error.to_string());
+
+        if device.consecutive_errors % RECOVERY_THRESHOLD == 0 {
+            device.recoveries += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn status(&self) -> Vec<DeviceHealth> {
+        let mut result = self
+            .devices
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        result.sort_by(|a, b| (&a.bus, a.address).cmp(&(&b.bus, b.address)));
+        result
+    }
+}
+
+fn entry<'a>(
+    devices: &'a mut collections::HashMap<(String, u16), DeviceHealth>,
+    bus: &str,
+    address: u16,
+) -> &'a mut DeviceHealth {
+    devices
+        .entry((bus.to_owned(), address))
+        .or_insert_with(|| DeviceHealth {
+            bus: bus.to_owned(),
+            address,
+            errors: 0,
+            consecutive_errors: 0,
+            recoveries: 0,
+            last_error: None,
+        })
+}
+
+/// Lists the addresses on a bus that respond, like `i2cdetect` does.
+pub fn scan(bus: &str) -> Result<Vec<u16>, failure::Error> {
+    use i2cdev::core::I2CDevice;
+
+    if !path::Path::new(bus).exists() {
+        bail!("No such I2C bus: {}", bus);
+    }
+
+    let mut result = Vec::new();
+    // The reserved addresses are left out
+    for address in 0x03..0x78 {
+        let mut device = match i2cdev::linux::LinuxI2CDevice::new(bus, address) {
+            Ok(device) => device,
+            Err(e) => {
+                let e = io::Error::from(e);
+                // The address is in use by a kernel driver
+                if e.raw_os_error() == Some(EBUSY) {
+                    result.push(address);
+                    continue;
+                }
+                bail!(
+                    "Failed to select address 0x{:02x} on {}: {}",
+                    address,
+                    bus,
+                    e
+                );
+            }
+        };
+        // Quick writes can confuse EEPROMs, and some sensors ignore reads
+        let responds = match address {
+            0x30...0x37 | 0x50...0x5f => device.smbus_read_byte().is_ok(),
+            _ => device.smbus_write_quick(false).is_ok(),
+        };
+        if responds {
+            result.push(address);
+        }
+    }
+    Ok(result)
+}
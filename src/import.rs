@@ -0,0 +1,579 @@
+//! Import of historical data from CSV, like the files written by `precip export`, or from InfluxDB
+//! line protocol, for backfilling a new database.
+
+use std::collections;
+use std::fs;
+use std::io;
+use std::path;
+use std::str;
+
+use chrono;
+use csv;
+use failure;
+use uuid;
+
+use chrono::TimeZone;
+
+use db::model;
+
+// The measurements that precip writes and knows how to import
+const MEASUREMENTS: &[&str] = &["plant", "global", "pump"];
+// CSV columns that aren't fields
+const CSV_KEY_COLUMNS: &[&str] = &["time", "measurement", "uuid", "plant_uuid", "plant_name"];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    Csv,
+    LineProtocol,
+}
+
+/// The unit of the timestamps in line protocol, and of numeric times in CSV.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Precision {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+}
+
+/// A point read from a file, with the line it came from.
+pub type Read = Result<(u64, model::Point), failure::Error>;
+
+impl Format {
+    /// Guesses the format from the file extension.
+    pub fn from_path(path: &path::Path) -> Option<Self> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => Some(Format::Csv),
+            Some("lp") | Some("line") => Some(Format::LineProtocol),
+            _ => None,
+        }
+    }
+}
+
+impl str::FromStr for Format {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "line-protocol" => Ok(Format::LineProtocol),
+            _ => bail!("Unknown import format {:?}; use csv or line-protocol", s),
+        }
+    }
+}
+
+impl Precision {
+    fn nanoseconds(self) -> i64 {
+        match self {
+            Precision::Nanoseconds => 1,
+            Precision::Microseconds => 1_000,
+            Precision::Milliseconds => 1_000_000,
+            Precision::Seconds => 1_000_000_000,
+        }
+    }
+
+    fn time(self, timestamp: i64) -> Result<chrono::DateTime<chrono::Utc>, failure::Error> {
+        if timestamp < 0 {
+            bail!("Timestamps before 1970 are not supported: {}", timestamp);
+        }
+        let ns = timestamp
+            .checked_mul(self.nanoseconds())
+            .ok_or_else(|| format_err!("Timestamp out of range: {}", timestamp))?;
+        Ok(chrono::Utc.timestamp(ns / 1_000_000_000, (ns % 1_000_000_000) as u32))
+    }
+}
+
+impl str::FromStr for Precision {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ns" => Ok(Precision::Nanoseconds),
+            "us" => Ok(Precision::Microseconds),
+            "ms" => Ok(Precision::Milliseconds),
+            "s" => Ok(Precision::Seconds),
+            _ => bail!("Unknown precision {:?}; use ns, us, ms or s", s),
+        }
+    }
+}
+
+/// Reads the points of a file lazily.  CSV files need a time column, and either field and value
+/// columns with one field per row, or a column per field; the measurement comes from a
+/// measurement column if there is one.
+pub fn read(
+    path: &path::Path,
+    format: Format,
+    precision: Precision,
+    measurement: Option<String>,
+) -> Result<Box<Iterator<Item = Read>>, failure::Error> {
+    match format {
+        Format::Csv => read_csv(path, precision, measurement),
+        Format::LineProtocol => {
+            use std::io::BufRead;
+
+            let lines = io::BufReader::new(fs::File::open(path)?).lines();
+            Ok(Box::new(lines.enumerate().filter_map(move |(i, line)| {
+                let line_number = i as u64 + 1;
+                let point = line
+                    .map_err(failure::Error::from)
+                    .and_then(|line| parse_line(&line, precision));
+                match point {
+                    Ok(Some(point)) => Some(Ok((line_number, point))),
+                    Ok(None) => None,
+                    Err(e) => Some(Err(format_err!("Line {}: {}", line_number, e))),
+                }
+            })))
+        }
+    }
+}
+
+/// Checks that the point is something that precip writes, for a plant in the config.
+pub fn validate(
+    point: model::Point,
+    plants: &collections::HashSet<uuid::Uuid>,
+) -> Result<model::Point, failure::Error> {
+    if !MEASUREMENTS.contains(&point.measurement.as_str()) {
+        bail!(
+            "Unknown measurement {:?}; expected one of {}",
+            point.measurement,
+            MEASUREMENTS.join(", ")
+        );
+    }
+
+    let plant = point
+        .tags
+        .iter()
+        .find(|&&(ref tag, _)| tag == "uuid")
+        .map(|&(_, ref uuid)| uuid.parse::<uuid::Uuid>())
+        .map_or(Ok(None), |r| r.map(Some))?;
+    match plant {
+        Some(plant) if !plants.contains(&plant) => bail!("Unknown plant {}", plant),
+        None if point.measurement != "global" => {
+            bail!("{} measurements need a uuid tag", point.measurement)
+        }
+        _ => {}
+    }
+
+    if point.fields.is_empty() {
+        bail!("No fields");
+    }
+    if let Some(&(ref field, value)) = point.fields.iter().find(|&&(_, value)| match value {
+        model::Value::Float(v) => !v.is_finite(),
+        model::Value::Boolean(_) => false,
+    }) {
+        bail!(
+            "Field {:?} is not a finite number: {:?}",
+            field,
+            value.as_f64()
+        );
+    }
+    if point.measurement == "pump" {
+        if point
+            .fields
+            .iter()
+            .any(|&(ref field, _)| field != "running")
+        {
+            bail!("pump measurements only have a running field");
+        }
+        // Pump states exported as numbers become booleans again
+        let mut point = point;
+        for field in &mut point.fields {
+            field.1 = model::Value::Boolean(field.1.as_bool());
+        }
+        Ok(point)
+    } else {
+        if let Some(&(ref field, _)) = point.fields.iter().find(|&&(_, value)| match value {
+            model::Value::Boolean(_) => true,
+            model::Value::Float(_) => false,
+        }) {
+            bail!(
+                "Field {:?} of {} measurements has to be a number",
+                field,
+                point.measurement
+            );
+        }
+        Ok(point)
+    }
+}
+
+fn read_csv(
+    path: &path::Path,
+    precision: Precision,
+    measurement: Option<String>,
+) -> Result<Box<Iterator<Item = Read>>, failure::Error> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|h| h == name);
+
+    let time = column("time").ok_or_else(|| format_err!("There is no time column"))?;
+    let measurement_column = column("measurement");
+    if measurement_column.is_none() && measurement.is_none() {
+        bail!("There is no measurement column; specify the measurement");
+    }
+    let uuid = column("plant_uuid").or_else(|| column("uuid"));
+    // Either one field per row, like exports, or one column per field
+    let fields = match (column("field"), column("value")) {
+        (Some(field), Some(value)) => CsvFields::Long { field, value },
+        _ => CsvFields::Wide(
+            headers
+                .iter()
+                .enumerate()
+                .filter(|&(_, h)| !CSV_KEY_COLUMNS.contains(&h))
+                .map(|(i, h)| (i, h.to_owned()))
+                .collect(),
+        ),
+    };
+
+    Ok(Box::new(reader.into_records().map(move |record| {
+        let record = record?;
+        let line = record.position().map_or(0, |p| p.line());
+        let point = (|| -> Result<model::Point, failure::Error> {
+            let get = |i: usize| record.get(i).unwrap_or("").trim();
+
+            let measurement = match measurement_column.map(&get) {
+                Some(m) if !m.is_empty() => m.to_owned(),
+                _ => measurement
+                    .clone()
+                    .ok_or_else(|| format_err!("The measurement is missing"))?,
+            };
+            let tags = match uuid.map(&get) {
+                Some(uuid) if !uuid.is_empty() => vec![("uuid".to_owned(), uuid.to_owned())],
+                _ => Vec::new(),
+            };
+            let fields = match fields {
+                CsvFields::Long { field, value } => {
+                    vec![(get(field).to_owned(), parse_value(get(value))?)]
+                }
+                CsvFields::Wide(ref columns) => columns
+                    .iter()
+                    .filter(|&&(i, _)| !get(i).is_empty())
+                    .map(|&(i, ref name)| Ok((name.clone(), parse_value(get(i))?)))
+                    .collect::<Result<Vec<_>, failure::Error>>()?,
+            };
+
+            Ok(model::Point {
+                time: parse_time(get(time), precision)?,
+                measurement,
+                tags,
+                fields,
+            })
+        })();
+        point
+            .map(|point| (line, point))
+            .map_err(|e| format_err!("Line {}: {}", line, e))
+    })))
+}
+
+enum CsvFields {
+    Long { field: usize, value: usize },
+    Wide(Vec<(usize, String)>),
+}
+
+fn parse_time(
+    s: &str,
+    precision: Precision,
+) -> Result<chrono::DateTime<chrono::Utc>, failure::Error> {
+    match chrono::DateTime::parse_from_rfc3339(s) {
+        Ok(time) => Ok(time.with_timezone(&chrono::Utc)),
+        Err(_) => match s.parse::<i64>() {
+            Ok(timestamp) => precision.time(timestamp),
+            Err(_) => bail!("Invalid time {:?}", s),
+        },
+    }
+}
+
+fn parse_value(s: &str) -> Result<model::Value, failure::Error> {
+    match s {
+        "t" | "T" | "true" | "True" | "TRUE" => Ok(model::Value::Boolean(true)),
+        "f" | "F" | "false" | "False" | "FALSE" => Ok(model::Value::Boolean(false)),
+        _ if s.starts_with('"') => bail!("String fields can't be imported: {}", s),
+        // Integers in line protocol
+        _ if s.ends_with('i') => Ok(model::Value::Float(s[..s.len() - 1].parse::<i64>()? as f64)),
+        _ => Ok(model::Value::Float(s.parse::<f64>()?)),
+    }
+}
+
+/// Parses a line like `plant,uuid=... moisture=1.5 1541000000000000000`; empty lines and comments
+/// result in None.
+fn parse_line(line: &str, precision: Precision) -> Result<Option<model::Point>, failure::Error> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let sections = split_unescaped(line, ' ');
+    let (key, fields, timestamp) = match sections.as_slice() {
+        [key, fields, timestamp] => (key, fields, timestamp),
+        [_, _] => bail!("The timestamp is missing"),
+        _ => bail!("Expected a measurement, fields and a timestamp"),
+    };
+
+    let mut key = split_unescaped(key, ',').into_iter();
+    let measurement = unescape(&key.next().unwrap_or_default());
+    let tags = key
+        .map(|tag| key_value(&tag).map(|(k, v)| (k, unescape(&v))))
+        .collect::<Result<Vec<_>, _>>()?;
+    let fields = split_unescaped(fields, ',')
+        .iter()
+        .map(|field| key_value(field).and_then(|(k, v)| Ok((k, parse_value(&v)?))))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Some(model::Point {
+        time: precision.time(timestamp.parse()?)?,
+        measurement,
+        tags,
+        fields,
+    }))
+}
+
+fn key_value(s: &str) -> Result<(String, String), failure::Error> {
+    let parts = split_unescaped(s, '=');
+    match parts.as_slice() {
+        [key, value] => Ok((unescape(key), value.clone())),
+        _ => bail!("Expected a key and a value: {}", s),
+    }
+}
+
+// Splits on the separator where it isn't escaped with a backslash or in a quoted string; the
+// parts keep their escapes
+fn split_unescaped(s: &str, separator: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut escaped = false;
+    let mut quoted = false;
+    for c in s.chars() {
+        if c == separator && !escaped && !quoted {
+            parts.push(String::new());
+            continue;
+        }
+        if let Some(part) = parts.last_mut() {
+            part.push(c);
+        }
+        if c == '"' && !escaped {
+            quoted = !quoted;
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    parts
+}
+
+fn unescape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut escaped = false;
+    for c in s.chars() {
+        if c == '\\' && !escaped {
+            escaped = true;
+        } else {
+            result.push(c);
+            escaped = false;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    use export;
+
+    const PLANT: &str = "1c4a8f5e-7d8b-4f2a-9c3e-5b6d7e8f9a0b";
+
+    fn plants() -> collections::HashSet<uuid::Uuid> {
+        vec![PLANT.parse().unwrap()].into_iter().collect()
+    }
+
+    fn line(line: &str) -> model::Point {
+        parse_line(line, Precision::Nanoseconds).unwrap().unwrap()
+    }
+
+    // Written to a file of its own, since the CSV reader reads from a path
+    fn read_csv_content(name: &str, content: &[u8]) -> Vec<model::Point> {
+        let path = env::temp_dir().join(format!("precip-import-{}-{}.csv", process::id(), name));
+        fs::write(&path, content).unwrap();
+        let points = read(&path, Format::Csv, Precision::Nanoseconds, None)
+            .unwrap()
+            .map(|read| read.map(|(_, point)| point))
+            .collect::<Result<Vec<_>, _>>();
+        fs::remove_file(&path).unwrap();
+        points.unwrap()
+    }
+
+    #[test]
+    fn line_protocol_escapes() {
+        let point = line(
+            r"plant\ data,uuid=abc,room=living\ room\,east,label=a\=b moisture=1.5 1541000000000000000",
+        );
+        assert_eq!(point.measurement, "plant data");
+        assert_eq!(
+            point.tags,
+            vec![
+                ("uuid".to_owned(), "abc".to_owned()),
+                ("room".to_owned(), "living room,east".to_owned()),
+                ("label".to_owned(), "a=b".to_owned()),
+            ]
+        );
+        assert_eq!(
+            point.fields,
+            vec![("moisture".to_owned(), model::Value::Float(1.5))]
+        );
+        assert_eq!(point.time, chrono::Utc.timestamp(1_541_000_000, 0));
+    }
+
+    #[test]
+    fn line_protocol_field_keys_can_be_escaped() {
+        let point = line(r"global soil\ temperature=12.5,a\,b=1 1");
+        assert_eq!(
+            point.fields,
+            vec![
+                ("soil temperature".to_owned(), model::Value::Float(12.5)),
+                ("a,b".to_owned(), model::Value::Float(1.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn line_protocol_quoted_strings_are_rejected_as_a_whole() {
+        // The space and comma in the string don't split the line
+        let error = parse_line(
+            r#"plant,uuid=abc note="dry, again",moisture=1.5 1"#,
+            Precision::Nanoseconds,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("String fields"), error);
+    }
+
+    #[test]
+    fn line_protocol_integers_and_booleans() {
+        let point = line("pump,uuid=abc running=t,count=3i,offset=-2i 1");
+        assert_eq!(
+            point.fields,
+            vec![
+                ("running".to_owned(), model::Value::Boolean(true)),
+                ("count".to_owned(), model::Value::Float(3.0)),
+                ("offset".to_owned(), model::Value::Float(-2.0)),
+            ]
+        );
+        assert!(parse_line("plant moisture=1.5i 1", Precision::Nanoseconds).is_err());
+    }
+
+    #[test]
+    fn line_protocol_precision_and_skipped_lines() {
+        let point = parse_line("global temperature=21 1541000000", Precision::Seconds)
+            .unwrap()
+            .unwrap();
+        assert_eq!(point.time, chrono::Utc.timestamp(1_541_000_000, 0));
+
+        assert!(parse_line("", Precision::Nanoseconds).unwrap().is_none());
+        assert!(parse_line("# a comment", Precision::Nanoseconds)
+            .unwrap()
+            .is_none());
+        assert!(parse_line("global temperature=21", Precision::Nanoseconds).is_err());
+        assert!(parse_line("global temperature=21 -1", Precision::Nanoseconds).is_err());
+    }
+
+    #[test]
+    fn exported_csv_can_be_imported() {
+        let uuid = PLANT.parse::<uuid::Uuid>().unwrap();
+        let time = chrono::Utc.ymd(2018, 11, 20).and_hms_milli(6, 0, 0, 500);
+        let rows = vec![
+            export::Row {
+                time,
+                measurement: "plant".to_owned(),
+                plant_uuid: Some(uuid),
+                plant_name: Some("Basil, \"the big one\"".to_owned()),
+                field: "moisture".to_owned(),
+                value: 1.5,
+            },
+            export::Row {
+                time,
+                measurement: "global".to_owned(),
+                plant_uuid: None,
+                plant_name: None,
+                field: "temperature".to_owned(),
+                value: 21.25,
+            },
+            export::Row {
+                time,
+                measurement: "pump".to_owned(),
+                plant_uuid: Some(uuid),
+                plant_name: Some("Basil, \"the big one\"".to_owned()),
+                field: "running".to_owned(),
+                value: 1.0,
+            },
+        ];
+        let csv = export::to_csv(&rows, true).unwrap();
+
+        let points = read_csv_content("round-trip", &csv)
+            .into_iter()
+            .map(|point| validate(point, &plants()))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(points.len(), 3);
+        for point in &points {
+            assert_eq!(point.time, time);
+        }
+
+        assert_eq!(points[0].measurement, "plant");
+        assert_eq!(points[0].tags, vec![("uuid".to_owned(), PLANT.to_owned())]);
+        assert_eq!(
+            points[0].fields,
+            vec![("moisture".to_owned(), model::Value::Float(1.5))]
+        );
+        assert_eq!(points[1].measurement, "global");
+        assert!(points[1].tags.is_empty());
+        assert_eq!(
+            points[1].fields,
+            vec![("temperature".to_owned(), model::Value::Float(21.25))]
+        );
+        assert_eq!(points[2].measurement, "pump");
+        assert_eq!(
+            points[2].fields,
+            vec![("running".to_owned(), model::Value::Boolean(true))]
+        );
+    }
+
+    #[test]
+    fn wide_csv_has_a_column_per_field() {
+        let points = read_csv_content(
+            "wide",
+            b"time,measurement,temperature,humidity\n1541000000000000000,global,21.5,\n",
+        );
+        assert_eq!(points.len(), 1);
+        assert_eq!(
+            points[0].fields,
+            vec![("temperature".to_owned(), model::Value::Float(21.5))]
+        );
+    }
+
+    #[test]
+    fn validation_rejects_values_that_are_not_finite() {
+        for value in &["NaN", "inf", "-inf"] {
+            let point = line(&format!("global temperature={} 1", value));
+            let error = validate(point, &plants()).unwrap_err().to_string();
+            assert!(error.contains("finite"), error);
+        }
+
+        let point = line(&format!("pump,uuid={} running=NaN 1", PLANT));
+        assert!(validate(point, &plants()).is_err());
+    }
+
+    #[test]
+    fn validation_checks_plants_and_measurements() {
+        let point = line(&format!("plant,uuid={} moisture=1.5 1", PLANT));
+        assert!(validate(point, &plants()).is_ok());
+
+        let point = line("plant,uuid=0a8c5b26-3f6e-4b9c-8f21-6d7e2c4b1a09 moisture=1.5 1");
+        assert!(validate(point, &plants()).is_err());
+        assert!(validate(line("plant moisture=1.5 1"), &plants()).is_err());
+        assert!(validate(line("weather temperature=1.5 1"), &plants()).is_err());
+
+        let point = line(&format!("plant,uuid={} dry=t 1", PLANT));
+        assert!(validate(point, &plants()).is_err());
+        let point = line(&format!("pump,uuid={} volume=1 1", PLANT));
+        assert!(validate(point, &plants()).is_err());
+    }
+}
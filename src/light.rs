@@ -0,0 +1,214 @@
+//! Accumulation of the daily light integral (DLI) from lux readings.
+
+use std::time;
+
+use chrono;
+use chrono_tz;
+
+use schedule;
+
+// Sunlight has roughly 54 lux per µmol/m²/s of photosynthetically active radiation
+const PPFD_PER_LUX: f64 = 1.0 / 54.0;
+// Gaps between readings longer than this, or than two sampling intervals if that is longer, are
+// not integrated over
+const MAX_GAP_SECONDS: f64 = 60.0;
+
+/// The light received during one day, in the timezone of the location.
+#[derive(Clone, Copy, Debug)]
+pub struct DailyLightIntegral {
+    pub date: chrono::NaiveDate,
+    pub start: chrono::DateTime<chrono::Utc>,
+    // in mol/m²/day
+    pub integral: f64,
+    // the fraction of the day that was covered by readings
+    pub coverage: f64,
+}
+
+pub struct Integrator {
+    timezone: chrono_tz::Tz,
+    max_gap_seconds: f64,
+    date: Option<chrono::NaiveDate>,
+    // in µmol/m²
+    micromoles: f64,
+    covered_seconds: f64,
+    last: Option<(chrono::DateTime<chrono::Utc>, f64)>,
+}
+
+impl Integrator {
+    /// Integrates the readings of a sensor that is sampled at the specified interval.
+    pub fn new(timezone: chrono_tz::Tz, interval: time::Duration) -> Self {
+        let interval_seconds =
+            interval.as_secs() as f64 + f64::from(interval.subsec_nanos()) * 1e-9;
+        Integrator {
+            timezone,
+            max_gap_seconds: MAX_GAP_SECONDS.max(2.0 * interval_seconds),
+            date: None,
+            micromoles: 0.0,
+            covered_seconds: 0.0,
+            last: None,
+        }
+    }
+
+    /// Adds a reading, and returns the integral of the previous day if this reading started a new
+    /// day.
+    pub fn add(
+        &mut self,
+        now: chrono::DateTime<chrono::Utc>,
+        lux: f64,
+    ) -> Option<DailyLightIntegral> {
+        let date = now.with_timezone(&self.timezone).date().naive_local();
+
+        if let Some((last_time, last_lux)) = self.last {
+            let seconds = (now - last_time).num_milliseconds() as f64 / 1000.0;
+            if seconds > 0.0 && seconds <= self.max_gap_seconds {
+                self.micromoles += last_lux * PPFD_PER_LUX * seconds;
+                self.covered_seconds += seconds;
+            }
+        }
+        self.last = Some((now, lux));
+
+        match self.date {
+            Some(current) if current != date => {
+                let start = schedule::resolve_wall_clock(self.timezone, current.and_hms(0, 0, 0));
+                let end = schedule::resolve_wall_clock(self.timezone, date.and_hms(0, 0, 0));
+                let day_seconds = (end - start).num_seconds() as f64;
+
+                let result = DailyLightIntegral {
+                    date: current,
+                    start,
+                    integral: self.micromoles / 1_000_000.0,
+                    coverage: (self.covered_seconds / day_seconds).min(1.0),
+                };
+
+                self.date = Some(date);
+                self.micromoles = 0.0;
+                self.covered_seconds = 0.0;
+                Some(result)
+            }
+            Some(_) => None,
+            None => {
+                self.date = Some(date);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time;
+
+    use chrono;
+    use chrono_tz;
+
+    use schedule;
+
+    use super::*;
+
+    // 100 µmol/m²/s
+    const LUX: f64 = 5400.0;
+
+    fn local(s: &str) -> chrono::DateTime<chrono::Utc> {
+        schedule::resolve_wall_clock(
+            chrono_tz::Europe::Stockholm,
+            chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap(),
+        )
+    }
+
+    // Adds a reading every interval from `from` up to and including `to`, and returns the
+    // integrals that were completed
+    fn add_every(
+        integrator: &mut Integrator,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+        interval: chrono::Duration,
+    ) -> Vec<DailyLightIntegral> {
+        let mut integrals = Vec::new();
+        let mut time = from;
+        while time <= to {
+            integrals.extend(integrator.add(time, LUX));
+            time = time + interval;
+        }
+        integrals
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn a_day_is_completed_by_the_first_reading_after_midnight() {
+        let mut integrator =
+            Integrator::new(chrono_tz::Europe::Stockholm, time::Duration::from_secs(60));
+        let integrals = add_every(
+            &mut integrator,
+            local("2018-06-01 00:00"),
+            local("2018-06-01 23:59"),
+            chrono::Duration::minutes(1),
+        );
+        assert!(integrals.is_empty());
+
+        let dli = integrator.add(local("2018-06-02 00:00"), LUX).unwrap();
+        assert_eq!(dli.date, chrono::NaiveDate::from_ymd(2018, 6, 1));
+        assert_eq!(dli.start, local("2018-06-01 00:00"));
+        assert_close(dli.integral, 100.0 * 86_400.0 / 1e6);
+        assert_close(dli.coverage, 1.0);
+    }
+
+    #[test]
+    fn days_on_which_dst_starts_have_23_hours() {
+        let mut integrator =
+            Integrator::new(chrono_tz::Europe::Stockholm, time::Duration::from_secs(60));
+        let integrals = add_every(
+            &mut integrator,
+            local("2018-03-25 00:00"),
+            local("2018-03-26 00:00"),
+            chrono::Duration::minutes(1),
+        );
+
+        assert_eq!(integrals.len(), 1);
+        assert_eq!(integrals[0].date, chrono::NaiveDate::from_ymd(2018, 3, 25));
+        assert_close(integrals[0].integral, 100.0 * 23.0 * 3600.0 / 1e6);
+        assert_close(integrals[0].coverage, 1.0);
+    }
+
+    #[test]
+    fn days_on_which_dst_ends_have_25_hours() {
+        let mut integrator =
+            Integrator::new(chrono_tz::Europe::Stockholm, time::Duration::from_secs(60));
+        let integrals = add_every(
+            &mut integrator,
+            local("2018-10-28 00:00"),
+            local("2018-10-29 00:00"),
+            chrono::Duration::minutes(1),
+        );
+
+        assert_eq!(integrals.len(), 1);
+        assert_close(integrals[0].integral, 100.0 * 25.0 * 3600.0 / 1e6);
+        assert_close(integrals[0].coverage, 1.0);
+    }
+
+    #[test]
+    fn gaps_are_not_integrated_over() {
+        // Sampled every 10 minutes, which is more than the minimum gap
+        let mut integrator =
+            Integrator::new(chrono_tz::Europe::Stockholm, time::Duration::from_secs(600));
+        let mut integrals = add_every(
+            &mut integrator,
+            local("2018-06-01 00:00"),
+            local("2018-06-01 12:00"),
+            chrono::Duration::minutes(10),
+        );
+        // Nothing until the next day
+        integrals.extend(integrator.add(local("2018-06-02 00:00"), LUX));
+
+        assert_eq!(integrals.len(), 1);
+        assert_close(integrals[0].integral, 100.0 * 12.0 * 3600.0 / 1e6);
+        assert_close(integrals[0].coverage, 0.5);
+    }
+}
@@ -0,0 +1,65 @@
+use std::fs;
+use std::io;
+use std::path;
+
+use failure;
+use uuid;
+
+/// Plants for which automatic watering has been disabled until a human acknowledges the problem.
+///
+/// Each lockout is a file in the state directory, so that it survives restarts and can be cleared
+/// by a separate `precip acknowledge` invocation while the daemon is running.
+pub struct Lockouts {
+    dir: path::PathBuf,
+}
+
+impl Lockouts {
+    pub fn open<P>(state_dir: P) -> Result<Self, failure::Error>
+    where
+        P: AsRef<path::Path>,
+    {
+        let dir = state_dir.as_ref().join("lockout");
+        fs::create_dir_all(&dir)
+            .map_err(|e| format_err!("failed to create {}: {}", dir.display(), e))?;
+        Ok(Lockouts { dir })
+    }
+
+    pub fn is_locked(&self, uuid: uuid::Uuid) -> bool {
+        self.path(uuid).exists()
+    }
+
+    pub fn lock(&self, uuid: uuid::Uuid, reason: &str) -> Result<(), failure::Error> {
+        fs::write(self.path(uuid), reason)?;
+        Ok(())
+    }
+
+    /// Clears the lockout of the specified plant, returning whether there was one.
+    pub fn acknowledge(&self, uuid: uuid::Uuid) -> Result<bool, failure::Error> {
+        match fs::remove_file(self.path(uuid)) {
+            Ok(()) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn list(&self) -> Result<Vec<(uuid::Uuid, String)>, failure::Error> {
+        let mut result = Vec::new();
+
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if let Some(uuid) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse::<uuid::Uuid>().ok())
+            {
+                result.push((uuid, fs::read_to_string(entry.path())?));
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn path(&self, uuid: uuid::Uuid) -> path::PathBuf {
+        self.dir.join(uuid.to_hyphenated().to_string())
+    }
+}
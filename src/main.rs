@@ -2,16 +2,24 @@
 
 extern crate ads1x15;
 extern crate chrono;
+extern crate chrono_tz;
 extern crate config as config_rs;
 extern crate cron;
+extern crate csv;
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
 #[macro_use]
 extern crate failure;
 extern crate futures_await as futures;
+extern crate gpio_cdev;
+extern crate hyper;
 extern crate i2cdev;
 extern crate i2cdev_bmp280;
 extern crate i2csensors;
-extern crate influent;
 extern crate itertools;
+extern crate parquet;
 #[macro_use]
 extern crate slog;
 extern crate serde;
@@ -28,26 +36,49 @@ extern crate serde_json;
 extern crate structopt;
 extern crate sysfs_gpio;
 extern crate tokio;
+extern crate tokio_threadpool;
+extern crate url;
 extern crate uuid;
 
+use std::cmp;
 use std::collections;
 use std::env;
+use std::fs;
+use std::io;
+use std::process;
 use std::sync;
 use std::time;
 
 use futures::prelude::async;
 use futures::prelude::await;
 
+pub mod api;
+pub mod blackout;
+pub mod clock;
 pub mod config;
 pub mod db;
+pub mod export;
+pub mod i2c;
+pub mod import;
+pub mod light;
+pub mod lockout;
 pub mod model;
 pub mod options;
 pub mod pumps;
+pub mod queue;
+pub mod readings;
+pub mod replay;
+pub mod rollup;
+pub mod schedule;
+pub mod schema;
 pub mod sensors;
+pub mod sun;
 pub mod util;
+pub mod vacation;
+pub mod watering;
 
 fn main() -> Result<(), failure::Error> {
-    use itertools::Itertools;
+    use futures::Future;
     use structopt::StructOpt;
 
     let options = options::Options::from_args();
@@ -55,68 +86,547 @@ fn main() -> Result<(), failure::Error> {
     let _log_scope = slog_scope::set_global_logger(log.clone());
     slog_stdlog::init()?;
 
-    let config = config::Config::load()?;
+    let mut config = config::Config::load()?;
 
-    let db = sync::Arc::new(db::Db::connect(
-        log.clone(),
-        config.db.credentials.into(),
-        config.db.hosts,
+    let replaying = match options.command {
+        Some(options::Command::Replay { path, speed }) => {
+            if let Some(speed) = speed {
+                if !speed.is_finite() || speed <= 0.0 {
+                    bail!("The replay speed has to be a positive number: {}", speed);
+                }
+            }
+            let recording = replay::Recording::open(&path)?;
+            // Replays never touch the pumps, nor the lockouts of the installation
+            config.gpio.backend = pumps::Backend::Simulated;
+            config.state_dir = env::temp_dir()
+                .join(format!("precip-replay-{}", process::id()))
+                .to_string_lossy()
+                .into_owned();
+            info!(
+                log,
+                "replaying recording path={:?} start={} end={} speed={:?} state_dir={:?}",
+                path,
+                recording.start(),
+                recording.end(),
+                speed,
+                config.state_dir
+            );
+            Some((recording, speed))
+        }
+        Some(command) => {
+            let lockouts = sync::Arc::new(lockout::Lockouts::open(&config.state_dir)?);
+            return run_command(log, config, lockouts, command);
+        }
+        None => None,
+    };
+    let lockouts = sync::Arc::new(lockout::Lockouts::open(&config.state_dir)?);
+    let recorder = match options.record {
+        Some(ref path) => Some(sync::Arc::new(replay::Recorder::create(log.clone(), path)?)),
+        None => None,
+    };
+
+    let budget = sync::Arc::new(vacation::Budget::new());
+
+    // Replays don't store anything
+    let db = sync::Arc::new(match replaying {
+        Some(_) => db::Db::discard(&config.db),
+        None => db::Db::connect(log.clone(), &config.db)?,
+    });
+    let mut runtime = tokio::runtime::Runtime::new()?;
+
+    let index_interval = time::Duration::from_secs(config.sampling.index_interval_seconds);
+    // The database may be down or read-only for now; writes will fail and be logged anyway
+    if let Err(e) = runtime.block_on(db.ensure_schema(log.clone(), &config.db.retention)) {
+        warn!(log, "failed to set up the database schema: {}", e);
+    }
+    if let Err(e) = runtime.block_on(db.register_plants(&config.plant)) {
+        warn!(log, "failed to register the plants: {}", e);
+    }
+    let exporter = sync::Arc::new(export::Exporter::new(
+        db.clone(),
+        plant_names(&config),
+        config.location.timezone,
+    ));
+
+    let loaded_modules = sync::Arc::new(load_modules(
+        &config.location,
+        &config.gpio,
+        &config.blackout,
+        &config.power_group,
+        &config.sampling,
+        config.plant,
     )?);
 
-    let loaded_modules = sync::Arc::new(load_modules(config.plant)?);
+    let readings = sync::Arc::new(readings::Readings::new(readings_retention(&loaded_modules)));
 
-    let dacs = (*loaded_modules)
+    let run_queue = sync::Arc::new(queue::RunQueue::new(
+        config
+            .power_group
+            .iter()
+            .map(|(name, group)| (name.clone(), group.max_running))
+            .collect(),
+    ));
+
+    let timeline = match replaying {
+        Some((ref recording, _)) => Some(sync::Arc::new(clock::Timeline::new(recording.start()))),
+        None => None,
+    };
+    let clock = match timeline {
+        Some(ref timeline) => clock::Clock::Virtual(timeline.clone()),
+        None => clock::Clock::System,
+    };
+
+    let health = sync::Arc::new(i2c::Health::new());
+    let registry = match replaying {
+        Some((ref recording, _)) => sensors::registry::replay(
+            &log,
+            &config.sampling,
+            &config.sensor,
+            &loaded_modules,
+            recording,
+            clock.clone(),
+        )?,
+        None => sensors::registry::open(
+            log.clone(),
+            &config.sampling,
+            &config.sensor,
+            &loaded_modules,
+            &health,
+        )?,
+    };
+    let bus_futures = registry.buses;
+    let (stop, stopping) = util::stop();
+    let sample_futures = registry
+        .sensors
+        .into_iter()
+        .map(|mut sensor| {
+            if let Some(ref recorder) = recorder {
+                sensor.sensor = replay::Recorder::sensor(
+                    recorder.clone(),
+                    sensor.measurement.clone(),
+                    sensor.name.clone(),
+                    sensor.sensor,
+                );
+            }
+            Box::new(sample_sensor_job(
+                log.clone(),
+                sensor,
+                readings.clone(),
+                time::Duration::from_secs(config.sampling.report_interval_seconds),
+                time::Duration::from_secs(config.sampling.rollup_interval_seconds),
+                config.location.timezone,
+                db.clone(),
+                clock.clone(),
+                stopping.clone(),
+            )) as Box<futures::Future<Item = _, Error = _> + Send>
+        })
+        .collect::<Vec<_>>();
+    let update_indices_future: Box<futures::Future<Item = _, Error = _> + Send> = Box::new(
+        update_indices_job(log.clone(), index_interval, db.clone(), clock.clone()),
+    );
+    let db_health_future: Box<futures::Future<Item = _, Error = _> + Send> =
+        Box::new(db_health_job(
+            log.clone(),
+            time::Duration::from_secs(config.db.health_check_interval_seconds),
+            time::Duration::from_secs(config.sampling.report_interval_seconds),
+            db.clone(),
+            clock.clone(),
+        ));
+    let i2c_health_future: Box<futures::Future<Item = _, Error = _> + Send> =
+        Box::new(i2c_health_job(
+            log.clone(),
+            time::Duration::from_secs(config.sampling.report_interval_seconds),
+            health.clone(),
+            db.clone(),
+            clock.clone(),
+        ));
+
+    let waterers = loaded_modules
         .iter()
-        .map(|m| m.moisture_i2c_address)
-        .unique()
-        .map(|addr| {
-            let i2c_dev = i2cdev::linux::LinuxI2CDevice::new("/dev/i2c-1", addr)?;
-            Ok((addr, sync::Arc::new(ads1x15::Ads1x15::new_ads1115(i2c_dev))))
+        .filter(|module| module.pump_enabled)
+        .map(|module| {
+            Ok((
+                module.uuid,
+                sync::Arc::new(watering::Waterer::new(
+                    log.clone(),
+                    module.clone(),
+                    readings.clone(),
+                    lockouts.clone(),
+                    run_queue.clone(),
+                    budget.clone(),
+                    db.clone(),
+                    clock.clone(),
+                    recorder.clone(),
+                )?),
+            ))
         })
         .collect::<Result<collections::HashMap<_, _>, failure::Error>>()?;
 
-    let bmp280_i2c_dev = i2cdev_bmp280::get_linux_bmp280_i2c_device().unwrap();
-    let bmp280 = sync::Arc::new(sync::Mutex::new(i2cdev_bmp280::BMP280::new(
-        bmp280_i2c_dev,
-        i2cdev_bmp280::BMP280Settings {
-            compensation: i2cdev_bmp280::BMP280CompensationAlgorithm::Float,
-            t_sb: i2cdev_bmp280::BMP280Timing::ms0_5,
-            iir_filter_coeff: i2cdev_bmp280::BMP280FilterCoefficient::UltraHigh,
-            osrs_t: i2cdev_bmp280::BMP280TemperatureOversampling::x16,
-            osrs_p: i2cdev_bmp280::BMP280PressureOversampling::UltraHighResolution,
-            power_mode: i2cdev_bmp280::BMP280PowerMode::NormalMode,
+    let run_pump_futures = waterers
+        .values()
+        .map(|waterer| {
+            Box::new(run_pump_job(log.clone(), waterer.clone()))
+                as Box<futures::Future<Item = _, Error = _> + Send>
+        })
+        .collect::<Vec<_>>();
+
+    // Moves the virtual time through the recording, and ends the replay at the end of it
+    let replay_futures = match (&replaying, &timeline) {
+        (&Some((ref recording, speed)), &Some(ref timeline)) => vec![Box::new(replay::run(
+            log.clone(),
+            timeline.clone(),
+            recording.end(),
+            recording.pumps().to_vec(),
+            speed,
+        ))
+            as Box<futures::Future<Item = _, Error = _> + Send>],
+        _ => Vec::new(),
+    };
+
+    let vacation_future: Box<futures::Future<Item = _, Error = _> + Send> = Box::new(vacation_job(
+        log.clone(),
+        config.vacation.clone(),
+        config.location.timezone,
+        loaded_modules.clone(),
+        budget,
+        db.clone(),
+        clock,
+    ));
+
+    // Replays can't be watered by hand, and mustn't take the port of the installation
+    let api_futures = match replaying {
+        Some(_) => Vec::new(),
+        None => vec![Box::new(
+            api::Api::new(
+                log.clone(),
+                waterers,
+                run_queue,
+                health,
+                db.clone(),
+                exporter,
+            )
+            .serve(config.api.listen)?,
+        )
+            as Box<futures::Future<Item = _, Error = _> + Send>],
+    };
+
+    let jobs = futures::future::select_all(
+        vec![
+            i2c_health_future,
+            db_health_future,
+            vacation_future,
+            update_indices_future,
+        ]
+        .into_iter()
+        .chain(api_futures)
+        .chain(replay_futures)
+        .chain(run_pump_futures)
+        .chain(bus_futures),
+    )
+    .map(|r| r.0)
+    .map_err(|r| r.0);
+    let samples = futures::future::join_all(sample_futures).map(|_| ());
+
+    // When any other job ends, the sampling is stopped rather than dropped, so that it can store
+    // the rollups of the samples it has taken
+    runtime.block_on(jobs.select2(samples).then(
+        move |result| -> Box<futures::Future<Item = (), Error = failure::Error> + Send> {
+            let _ = stop.send(());
+            match result {
+                Ok(futures::future::Either::A(((), samples))) => Box::new(samples),
+                Err(futures::future::Either::A((e, samples))) => {
+                    Box::new(samples.then(move |_| Err(e)))
+                }
+                // Only without any sensors to sample
+                Ok(futures::future::Either::B(((), jobs))) => Box::new(jobs),
+                Err(futures::future::Either::B((e, _))) => Box::new(futures::future::err(e)),
+            }
         },
-    )?));
+    ))
+}
 
-    let sampler = sync::Arc::new(sensors::Ads1x15Sampler::start(dacs)?);
+fn run_command(
+    log: slog::Logger,
+    config: config::Config,
+    lockouts: sync::Arc<lockout::Lockouts>,
+    command: options::Command,
+) -> Result<(), failure::Error> {
+    match command {
+        options::Command::Lockouts => {
+            for (uuid, reason) in lockouts.list()? {
+                let name = config
+                    .plant
+                    .get(&uuid)
+                    .map(|p| p.name.as_str())
+                    .unwrap_or("<unknown>");
+                println!("{} {:?}: {}", uuid, name, reason);
+            }
+        }
+        options::Command::Water { plant, force } => {
+            let response = api::call(
+                config.api.listen,
+                hyper::Method::POST,
+                &format!("/plants/{}/water?force={}", plant, force),
+            )?;
+            println!("{}", serde_json::to_string_pretty(&response)?);
+        }
+        options::Command::Queue => {
+            let response = api::call(config.api.listen, hyper::Method::GET, "/queue")?;
+            let name = |uuid: &uuid::Uuid| {
+                config
+                    .plant
+                    .get(uuid)
+                    .map_or_else(|| uuid.to_string(), |p| p.name.clone())
+            };
 
-    let sample_global_future: Box<futures::Future<Item = _, Error = _> + Send> =
-        Box::new(sample_global_job(log.clone(), bmp280, db.clone()));
-    let update_indices_future: Box<futures::Future<Item = _, Error = _> + Send> =
-        Box::new(update_indices_job(log.clone(), db.clone()));
-    let sample_futures = loaded_modules.iter().map(|module| {
-        Box::new(sample_module_job(
-            log.clone(),
-            module.clone(),
-            sampler.clone(),
-            db.clone(),
-        )) as Box<futures::Future<Item = _, Error = _> + Send>
-    });
-    let run_pump_futures = loaded_modules.iter().map(|module| {
-        Box::new(run_pump_job(log.clone(), module.clone(), db.clone()))
-            as Box<futures::Future<Item = _, Error = _> + Send>
-    });
+            for group in serde_json::from_value::<Vec<queue::GroupStatus>>(response)? {
+                println!(
+                    "{} ({}/{} running)",
+                    group.name,
+                    group.running.len(),
+                    group.max_running
+                );
+                for uuid in &group.running {
+                    println!("  running: {}", name(uuid));
+                }
+                for uuid in &group.waiting {
+                    println!("  waiting: {}", name(uuid));
+                }
+            }
+        }
+        options::Command::Vacation {
+            from,
+            to,
+            reservoir_liters,
+        } => {
+            let vacation = match (config.vacation.clone(), from, to, reservoir_liters) {
+                (_, Some(from), Some(to), Some(reservoir_liters)) => config::Vacation {
+                    from,
+                    to,
+                    reservoir_liters,
+                    history_days: config.vacation.as_ref().map_or(14, |v| v.history_days),
+                },
+                (Some(vacation), from, to, reservoir_liters) => config::Vacation {
+                    from: from.unwrap_or(vacation.from),
+                    to: to.unwrap_or(vacation.to),
+                    reservoir_liters: reservoir_liters.unwrap_or(vacation.reservoir_liters),
+                    history_days: vacation.history_days,
+                },
+                _ => {
+                    bail!("No vacation is configured; specify --from, --to and --reservoir-liters")
+                }
+            };
 
-    let mut runtime = tokio::runtime::Runtime::new().unwrap();
-    runtime
-        .block_on(futures::future::select_all(
-            vec![sample_global_future, update_indices_future]
-                .into_iter()
-                .chain(run_pump_futures)
-                .chain(sample_futures),
-        ))
-        .map(|r| r.0)
-        .map_err(|r| r.0)
+            let db = sync::Arc::new(db::Db::connect(log.clone(), &config.db)?);
+            let modules = sync::Arc::new(load_modules(
+                &config.location,
+                &config.gpio,
+                &config.blackout,
+                &config.power_group,
+                &config.sampling,
+                config.plant.clone(),
+            )?);
+
+            // Only checked by the config when the vacation is configured there
+            if let Some(module) = modules
+                .iter()
+                .find(|m| m.pump_flow_ml_per_second.map_or(true, |f| f <= 0.0))
+            {
+                bail!(
+                    "The pump of plant {:?} needs a positive flow_ml_per_second for the vacation",
+                    module.name
+                );
+            }
+
+            let mut runtime = tokio::runtime::Runtime::new()?;
+            let plan = runtime.block_on(vacation::plan_remaining(
+                db,
+                modules,
+                vacation.clone(),
+                config.location.timezone,
+                chrono::Utc::now(),
+            ))?;
+
+            println!(
+                "Vacation {} to {} ({:.1} days left), reservoir {:.0} ml",
+                vacation.from, vacation.to, plan.days, plan.reservoir_ml
+            );
+            for allocation in &plan.allocations {
+                println!(
+                    "  {:<20} {:<8} usual {:>6.0} ml/day, budget {:>6.0} ml/day ({:.0}%)",
+                    allocation.demand.name,
+                    if allocation.demand.thirsty {
+                        "thirsty"
+                    } else {
+                        ""
+                    },
+                    allocation.demand.daily_ml,
+                    allocation.daily_ml,
+                    allocation.scale * 100.0
+                );
+            }
+            println!(
+                "Water lasts {:.1} days at usual consumption, {:.1} days with rationing",
+                plan.days_unrationed, plan.days_rationed
+            );
+        }
+        options::Command::Acknowledge { plant } => {
+            if lockouts.acknowledge(plant)? {
+                info!(log, "re-enabled automatic watering uuid={}", plant);
+            } else {
+                warn!(log, "automatic watering was not disabled uuid={}", plant);
+            }
+        }
+        options::Command::I2cScan { bus } => {
+            // Every device that the config expects, by bus and address
+            let mut expected = collections::BTreeMap::<(String, u16), Vec<String>>::new();
+            for (name, sensor) in &config.sensor {
+                let address = sensor
+                    .i2c_address
+                    .or_else(|| sensors::registry::default_address(sensor.kind));
+                if let Some(address) = address {
+                    expected
+                        .entry((sensor.bus.clone(), address))
+                        .or_default()
+                        .push(format!("{} ({:?})", name, sensor.kind));
+                }
+            }
+
+            let buses = match bus {
+                Some(bus) => vec![bus],
+                None => {
+                    let mut buses = expected
+                        .keys()
+                        .map(|&(ref bus, _)| bus.clone())
+                        .collect::<Vec<_>>();
+                    buses.push(sensors::registry::DEFAULT_BUS.to_owned());
+                    buses.sort();
+                    buses.dedup();
+                    buses
+                }
+            };
+
+            for bus in buses {
+                println!("{}", bus);
+                let found = i2c::scan(&bus)?;
+                for &address in &found {
+                    match expected.get(&(bus.clone(), address)) {
+                        Some(names) => println!("  0x{:02x} {}", address, names.join(", ")),
+                        None => println!("  0x{:02x} unknown", address),
+                    }
+                }
+                for (&(_, address), names) in expected
+                    .iter()
+                    .filter(|&(&(ref b, a), _)| *b == bus && !found.contains(&a))
+                {
+                    println!("  0x{:02x} missing: {}", address, names.join(", "));
+                }
+            }
+        }
+        options::Command::Export {
+            from,
+            to,
+            plants,
+            format,
+            output,
+        } => {
+            use futures::Stream;
+            use std::io::Write;
+
+            let db = sync::Arc::new(db::Db::connect(log.clone(), &config.db)?);
+            let exporter =
+                export::Exporter::new(db, plant_names(&config), config.location.timezone);
+            let to = to.unwrap_or_else(|| exporter.today());
+            let (from, to) = exporter.days(from, to);
+
+            let mut runtime = tokio::runtime::Runtime::new()?;
+            match (format, output) {
+                (export::Format::Csv, output) => {
+                    let mut out: Box<io::Write + Send> = match output {
+                        Some(path) => Box::new(fs::File::create(path)?),
+                        None => Box::new(io::stdout()),
+                    };
+                    runtime.block_on(exporter.csv(from, to, plants).for_each(move |chunk| {
+                        out.write_all(&chunk)?;
+                        Ok(())
+                    }))?;
+                }
+                (export::Format::Parquet, Some(path)) => {
+                    runtime.block_on(exporter.parquet(
+                        from,
+                        to,
+                        plants,
+                        fs::File::create(path)?,
+                    ))?;
+                }
+                (export::Format::Parquet, None) => {
+                    bail!("Parquet can only be written to a file; specify --output")
+                }
+            }
+        }
+        options::Command::Import {
+            path,
+            format,
+            precision,
+            measurement,
+            batch_size,
+            skip_invalid,
+        } => {
+            let format = match format.or_else(|| import::Format::from_path(&path)) {
+                Some(format) => format,
+                None => bail!("Can't tell the format of {:?}; specify --format", path),
+            };
+            let plants = config
+                .plant
+                .keys()
+                .cloned()
+                .collect::<collections::HashSet<_>>();
+            let db = db::Db::connect(log.clone(), &config.db)?;
+            let mut runtime = tokio::runtime::Runtime::new()?;
+
+            let mut batch = Vec::with_capacity(batch_size);
+            let mut imported = 0;
+            let mut skipped = 0;
+            for read in import::read(&path, format, precision, measurement)? {
+                let point = read.and_then(|(line, point)| {
+                    import::validate(point, &plants)
+                        .map_err(|e| format_err!("Line {}: {}", line, e))
+                });
+                match point {
+                    Ok(point) => batch.push(point),
+                    Err(ref e) if skip_invalid => {
+                        warn!(log, "skipping invalid point: {}", e);
+                        skipped += 1;
+                    }
+                    Err(e) => return Err(e),
+                }
+
+                if batch.len() >= batch_size {
+                    imported += batch.len();
+                    runtime.block_on(db.insert_points(batch.split_off(0)))?;
+                    info!(log, "imported points count={}", imported);
+                }
+            }
+            if !batch.is_empty() {
+                imported += batch.len();
+                runtime.block_on(db.insert_points(batch))?;
+            }
+            info!(
+                log,
+                "import finished imported={} skipped={}", imported, skipped
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn plant_names(config: &config::Config) -> collections::HashMap<uuid::Uuid, String> {
+    config
+        .plant
+        .iter()
+        .map(|(uuid, plant)| (*uuid, plant.name.clone()))
+        .collect()
 }
 
 fn init_log(options: &options::Options) -> Result<slog::Logger, failure::Error> {
@@ -169,45 +679,203 @@ fn init_log(options: &options::Options) -> Result<slog::Logger, failure::Error>
 }
 
 #[async]
-fn sample_global_job<D>(
+fn sample_sensor_job(
     log: slog::Logger,
-    bmp280: sync::Arc<sync::Mutex<i2cdev_bmp280::BMP280<D>>>,
-    db: sync::Arc<db::Db<'static>>,
-) -> Result<(), failure::Error>
-where
-    D: i2cdev::core::I2CDevice + Sized + 'static,
-    D::Error: Send + Sync + 'static,
-{
+    sensor: sensors::registry::Registered,
+    readings: sync::Arc<readings::Readings>,
+    report_interval: time::Duration,
+    rollup_interval: time::Duration,
+    timezone: chrono_tz::Tz,
+    db: sync::Arc<db::Db>,
+    clock: clock::Clock,
+    stop: util::Stop,
+) -> Result<(), failure::Error> {
+    let mut sensor = sensor;
+    let mut integrator = light::Integrator::new(timezone, sensor.interval);
+    let mut accumulator = rollup::Accumulator::new(rollup_interval);
+    let report_interval = chrono::Duration::from_std(report_interval)?;
+    let mut last_report = clock.now();
+    let mut last_health = sensors::fault::Health::Ok;
+
+    // Tick at the burst rate if there is one, but only sample at the normal rate outside of bursts
+    let tick = sensor
+        .burst_interval
+        .map_or(sensor.interval, |burst| cmp::min(burst, sensor.interval));
+    let nanos = |d: time::Duration| d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos());
+    let ticks_per_sample = cmp::max(1, nanos(sensor.interval) / cmp::max(1, nanos(tick)));
+    let mut ticks = 0;
+
     #[async]
-    for _ in util::every(
-        log.clone(),
-        "measure temperature".to_owned(),
-        time::Duration::from_secs(1),
+    for _ in util::until(
+        util::every(
+            log.clone(),
+            format!("sample {}", sensor.name),
+            clock.clone(),
+            tick,
+        ),
+        stop,
     ) {
-        let now = chrono::Utc::now();
-        let temperature =
-            i2csensors::Thermometer::temperature_celsius(&mut *bmp280.lock().unwrap())? as f64;
-        let pressure = i2csensors::Barometer::pressure_kpa(&mut *bmp280.lock().unwrap())? as f64;
+        // Without raw samples, the rollups are all that is stored, so they mustn't wait for the
+        // next sample when sampling fails or pauses
+        if let Some(rollup) = accumulator.flush(clock.now()) {
+            let insert = insert_rollup(&log, &db, &sensor, &rollup);
+            await!(insert)?;
+        }
+
+        let due = ticks % ticks_per_sample == 0;
+        ticks += 1;
+        let bursting = sensor.burst_interval.is_some()
+            && sensor
+                .plant
+                .map_or(false, |uuid| readings.in_burst(uuid, clock.now()));
+        if !due && !bursting {
+            continue;
+        }
+
+        let sample_future = sensor.sensor.sample();
+        let sample = match await!(sample_future) {
+            Ok(sample) => sample,
+            Err(e) => {
+                warn!(log, "failed to sample sensor name={:?}: {}", sensor.name, e);
+                continue;
+            }
+        };
+
+        let now = sample.time;
+        // Only the declared moisture probes of plants have a detector
+        let moisture = match sensor.detector {
+            Some(ref mut detector) => sample
+                .value(sensors::Quantity::Moisture)
+                .map(|value| (value, detector.check(now, value))),
+            None => None,
+        };
+        if let Some((value, health)) = moisture {
+            if health != last_health {
+                if health.is_ok() {
+                    info!(log, "sensor recovered name={:?}", sensor.name);
+                } else {
+                    warn!(
+                        log,
+                        "sensor is faulty name={:?} fault={:?} voltage={}",
+                        sensor.name,
+                        health,
+                        value
+                    );
+                }
+                let mut tags = sensor.tags.clone();
+                tags.push(("sensor".to_owned(), sensor.name.clone()));
+                tags.push(("health".to_owned(), health.name().to_owned()));
+                let fields = [("voltage".to_owned(), value)];
+                let insert = db.insert_measurement(now, "sensor_health", &tags, &fields);
+                if let Err(e) = await!(insert) {
+                    warn!(log, "failed to insert sensor health: {}", e);
+                }
+                last_health = health;
+            }
+
+            if let Some(uuid) = sensor.plant {
+                readings.record(
+                    uuid,
+                    readings::Reading {
+                        time: now,
+                        moisture_voltage: value,
+                        health,
+                    },
+                );
+            }
+        }
 
-        if let Err(e) = db.insert_global_measurement(now, temperature, pressure) {
-            warn!(log, "failed to insert plant measurement: {}", e);
+        if let Some(lux) = sample.value(sensors::Quantity::Illuminance) {
+            if let Some(dli) = integrator.add(now, lux) {
+                info!(
+                    log,
+                    "daily light integral name={:?} date={} dli={:.2}mol/m²/d coverage={:.0}%",
+                    sensor.name,
+                    dli.date,
+                    dli.integral,
+                    dli.coverage * 100.0
+                );
+                let mut tags = sensor.tags.clone();
+                tags.push(("sensor".to_owned(), sensor.name.clone()));
+                let insert = db.insert_daily_light_integral(&tags, &dli);
+                if let Err(e) = await!(insert) {
+                    warn!(log, "failed to insert daily light integral: {}", e);
+                }
+            }
+        }
+
+        let fields = sample
+            .values
+            .iter()
+            .map(|&(quantity, value)| (sensor.field(quantity).to_owned(), value))
+            .collect::<Vec<_>>();
+
+        if db.stores_raw() {
+            let insert = db.insert_measurement(now, &sensor.measurement, &sensor.tags, &fields);
+            if let Err(e) = await!(insert) {
+                warn!(
+                    log,
+                    "failed to insert {} measurement: {}", sensor.measurement, e
+                );
+            }
+        }
+
+        if let Some(rollup) = accumulator.add(now, &fields) {
+            let insert = insert_rollup(&log, &db, &sensor, &rollup);
+            await!(insert)?;
+        }
+
+        if clock.now() - last_report > report_interval {
+            info!(
+                log,
+                "sensor reading name={:?} {}",
+                sensor.name,
+                fields
+                    .iter()
+                    .map(|&(ref field, value)| format!("{}={}", field, value))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            );
+            last_report = clock.now();
         }
     }
+
+    if let Some(rollup) = accumulator.finish() {
+        let insert = insert_rollup(&log, &db, &sensor, &rollup);
+        await!(insert)?;
+    }
     Ok(())
 }
 
+// Only logs failures, since a sensor keeps being sampled without its rollups
+fn insert_rollup(
+    log: &slog::Logger,
+    db: &db::Db,
+    sensor: &sensors::registry::Registered,
+    rollup: &rollup::Rollup,
+) -> impl futures::Future<Item = (), Error = failure::Error> {
+    use futures::Future;
+
+    let log = log.clone();
+    let measurement = sensor.measurement.clone();
+    db.insert_rollup(&sensor.measurement, &sensor.tags, rollup)
+        .or_else(move |e| {
+            warn!(log, "failed to insert {} rollup: {}", measurement, e);
+            Ok(())
+        })
+}
+
 #[async]
 fn update_indices_job(
     log: slog::Logger,
-    db: sync::Arc<db::Db<'static>>,
+    interval: time::Duration,
+    db: sync::Arc<db::Db>,
+    clock: clock::Clock,
 ) -> Result<(), failure::Error> {
     #[async]
-    for _ in util::every(
-        log.clone(),
-        "update indices".to_owned(),
-        time::Duration::from_secs(60),
-    ) {
-        if let Err(e) = db.update_plant_indices() {
+    for _ in util::every(log.clone(), "update indices".to_owned(), clock, interval) {
+        let update = db.update_plant_indices();
+        if let Err(e) = await!(update) {
             warn!(log, "failed to update plant indices: {}", e);
         }
     }
@@ -215,125 +883,293 @@ fn update_indices_job(
 }
 
 #[async]
-fn sample_module_job<D>(
+fn db_health_job(
     log: slog::Logger,
-    module: sync::Arc<model::ModuleConfig>,
-    sampler: sync::Arc<sensors::Ads1x15Sampler<D>>,
-    db: sync::Arc<db::Db<'static>>,
-) -> Result<(), failure::Error>
-where
-    D: i2cdev::core::I2CDevice + Send + 'static,
-    <D as i2cdev::core::I2CDevice>::Error: Send + Sync + 'static,
-{
-    let mut last_report = time::Instant::now();
+    interval: time::Duration,
+    report_interval: time::Duration,
+    db: sync::Arc<db::Db>,
+    clock: clock::Clock,
+) -> Result<(), failure::Error> {
+    let mut healthy = collections::HashMap::new();
+    let report_interval = chrono::Duration::from_std(report_interval)?;
+    let mut last_report = clock.now();
 
     #[async]
     for _ in util::every(
         log.clone(),
-        format!("sample {}", module.uuid),
-        time::Duration::from_secs(1),
+        "check database hosts".to_owned(),
+        clock.clone(),
+        interval,
     ) {
-        let now = chrono::Utc::now();
+        let check = db.check_hosts();
+        if let Err(e) = await!(check) {
+            warn!(log, "failed to check database hosts: {}", e);
+        }
 
-        let moisture_voltage =
-            await!(sampler.sample(module.moisture_i2c_address, module.moisture_channel))? as f64;
+        let stats = db.host_stats();
+        for host in &stats {
+            if healthy.insert(host.host.clone(), host.healthy) == Some(!host.healthy) {
+                if host.healthy {
+                    info!(log, "database host is back host={:?}", host.host);
+                } else {
+                    warn!(
+                        log,
+                        "database host failed host={:?}: {}",
+                        host.host,
+                        host.last_error.as_ref().map_or("", |e| e.as_str())
+                    );
+                }
+            }
+        }
 
-        if let Err(e) = db.insert_plant_measurement(now, module.uuid, moisture_voltage) {
-            warn!(log, "failed to insert plant measurement: {}", e);
+        let now = clock.now();
+        if now - last_report > report_interval {
+            for host in stats {
+                let tags = [("host".to_owned(), host.host.clone())];
+                let mut fields = vec![
+                    ("healthy".to_owned(), if host.healthy { 1.0 } else { 0.0 }),
+                    ("requests".to_owned(), host.requests as f64),
+                    ("errors".to_owned(), host.errors as f64),
+                    ("probe_errors".to_owned(), host.probe_errors as f64),
+                    ("backlog".to_owned(), host.backlog as f64),
+                    ("dropped".to_owned(), host.dropped as f64),
+                ];
+                fields.extend(
+                    host.mean_latency_ms
+                        .map(|ms| ("mean_latency_ms".to_owned(), ms)),
+                );
+                fields.extend(
+                    host.last_probe_latency_ms
+                        .map(|ms| ("probe_latency_ms".to_owned(), ms)),
+                );
+                let insert = db.insert_measurement(now, "db_host", &tags, &fields);
+                if let Err(e) = await!(insert) {
+                    warn!(log, "failed to insert database host stats: {}", e);
+                }
+            }
+            last_report = now;
         }
+    }
+    Ok(())
+}
 
-        if last_report.elapsed() > time::Duration::from_secs(60) {
-            info!(
-                log,
-                "sensor reading name={:?} moisture={}V uuid={}",
-                module.name,
-                moisture_voltage,
-                module.uuid
-            );
-            last_report = time::Instant::now();
+#[async]
+fn i2c_health_job(
+    log: slog::Logger,
+    interval: time::Duration,
+    health: sync::Arc<i2c::Health>,
+    db: sync::Arc<db::Db>,
+    clock: clock::Clock,
+) -> Result<(), failure::Error> {
+    #[async]
+    for _ in util::every(
+        log.clone(),
+        "record i2c health".to_owned(),
+        clock.clone(),
+        interval,
+    ) {
+        let now = clock.now();
+        for device in health.status() {
+            let tags = [
+                ("bus".to_owned(), device.bus.clone()),
+                ("address".to_owned(), format!("0x{:02x}", device.address)),
+            ];
+            let fields = [
+                ("errors".to_owned(), device.errors as f64),
+                ("recoveries".to_owned(), device.recoveries as f64),
+            ];
+            let insert = db.insert_measurement(now, "i2c", &tags, &fields);
+            if let Err(e) = await!(insert) {
+                warn!(log, "failed to insert i2c health: {}", e);
+            }
         }
     }
+    Ok(())
+}
+
+#[async]
+fn vacation_job(
+    log: slog::Logger,
+    vacation: Option<config::Vacation>,
+    timezone: chrono_tz::Tz,
+    modules: sync::Arc<Vec<sync::Arc<model::ModuleConfig>>>,
+    budget: sync::Arc<vacation::Budget>,
+    db: sync::Arc<db::Db>,
+    clock: clock::Clock,
+) -> Result<(), failure::Error> {
+    #[async]
+    for _ in util::every(
+        log.clone(),
+        "plan vacation".to_owned(),
+        clock.clone(),
+        time::Duration::from_secs(3600),
+    ) {
+        let vacation = match vacation {
+            Some(ref vacation) => vacation.clone(),
+            None => continue,
+        };
+        let now = clock.now();
+        let (from, to) = vacation::period(&vacation, timezone);
 
+        if from <= now && now < to {
+            let plan =
+                vacation::plan_remaining(db.clone(), modules.clone(), vacation, timezone, now);
+            match await!(plan) {
+                Ok(plan) => {
+                    info!(
+                        log,
+                        "rationing water days_left={:.1} reservoir_ml={:.0} days_rationed={:.1}",
+                        plan.days,
+                        plan.reservoir_ml,
+                        plan.days_rationed
+                    );
+                    budget.apply(&plan);
+                }
+                Err(e) => warn!(log, "failed to plan vacation: {}", e),
+            }
+        } else {
+            budget.clear();
+        }
+    }
     Ok(())
 }
 
 #[async]
 fn run_pump_job(
     log: slog::Logger,
-    module: sync::Arc<model::ModuleConfig>,
-    db: sync::Arc<db::Db<'static>>,
+    waterer: sync::Arc<watering::Waterer>,
 ) -> Result<(), failure::Error> {
-    if module.pump_enabled {
-        let pump = pumps::Pump::new(log.clone(), module.pump_channel)?;
-        while let Some(now) = module
-            .pump_schedule
-            .as_ref()
-            .and_then(|schedule| schedule.upcoming(chrono::Local).next())
-        {
-            await!(tokio::timer::Delay::new(
-                time::Instant::now() + (now - chrono::Local::now()).to_std()?
-            ));
-
-            info!(
-                log,
-                "running turning pump on name={:?} uuid={}", module.name, module.uuid
-            );
-            pump.set_running(true)?;
-            db.insert_pump_measurement(chrono::Utc::now(), module.uuid, true)?;
+    let module = waterer.module().clone();
+    let clock = waterer.clock();
+    let mut last_run = clock.now();
 
-            await!(tokio::timer::Delay::new(
-                time::Instant::now() + module.pump_duration.unwrap_or(time::Duration::new(0, 0))
-            ));
+    while let Some(next_run) = module
+        .pump_schedule
+        .as_ref()
+        .and_then(|schedule| schedule.next_after(cmp::max(last_run, clock.now())))
+    {
+        await!(clock.sleep_until(next_run))?;
+        last_run = next_run;
 
-            info!(
+        // A failed run mustn't stop the schedule, or every other job
+        if let Err(e) = await!(watering::run(waterer.clone(), watering::Trigger::Scheduled)) {
+            error!(
                 log,
-                "running turning pump off name={:?} uuid={}", module.name, module.uuid
+                "pump run failed name={:?} uuid={}: {}", module.name, module.uuid, e
             );
-            pump.set_running(false)?;
-            db.insert_pump_measurement(chrono::Utc::now(), module.uuid, false)?;
         }
     }
 
     Ok(())
 }
 
+// Long enough for the checks after pump runs, which compare the readings of a window before the
+// run with those of a window after it
+fn readings_retention(modules: &[sync::Arc<model::ModuleConfig>]) -> chrono::Duration {
+    modules
+        .iter()
+        .filter_map(|module| {
+            let check = module.pump_check?;
+            let duration = module.pump_duration.unwrap_or(time::Duration::new(0, 0));
+            chrono::Duration::from_std(duration + check.delay + check.window * 2).ok()
+        })
+        .fold(chrono::Duration::minutes(5), cmp::max)
+}
+
 fn load_modules(
+    location: &config::Location,
+    gpio: &config::Gpio,
+    blackout: &config::Blackout,
+    power_groups: &collections::HashMap<String, config::PowerGroup>,
+    sampling: &config::Sampling,
     plant: collections::HashMap<uuid::Uuid, config::Plant>,
 ) -> Result<Vec<sync::Arc<model::ModuleConfig>>, failure::Error> {
-    use std::str::FromStr;
-
     plant
         .into_iter()
         .map(|(uuid, plant)| {
+            let global_blackout = blackout::Calendar::new(location.timezone, blackout);
+
+            if let Some(ref power_group) = plant.pump.power_group {
+                if !power_groups.contains_key(power_group) {
+                    bail!("No such power group: {}", power_group);
+                }
+            }
+
             Ok(sync::Arc::new(model::ModuleConfig {
                 uuid,
                 name: plant.name,
                 description: plant.description,
+                thirsty: plant.thirsty,
                 min_moisture: plant.moisture.min,
                 max_moisture: plant.moisture.max,
                 moisture_voltage_dry: plant.moisture.voltage_dry,
                 moisture_voltage_wet: plant.moisture.voltage_wet,
-                moisture_i2c_address: plant.moisture.channel.i2c_address,
-                moisture_channel: match plant.moisture.channel.analog_pin {
-                    0 => ads1x15::Channel::A0,
-                    1 => ads1x15::Channel::A1,
-                    2 => ads1x15::Channel::A2,
-                    3 => ads1x15::Channel::A3,
-                    x => bail!("No such moisture channel: {}", x),
+                moisture_temperature_coefficient: plant.moisture.temperature_coefficient,
+                moisture_reference_temperature: plant.moisture.reference_temperature,
+                moisture_faults: sensors::fault::Limits {
+                    min_voltage: plant.moisture.voltage_dry.min(plant.moisture.voltage_wet)
+                        - plant.moisture.faults.margin_volts,
+                    max_voltage: plant.moisture.voltage_dry.max(plant.moisture.voltage_wet)
+                        + plant.moisture.faults.margin_volts,
+                    stuck_tolerance: plant.moisture.faults.stuck_tolerance_volts,
+                    stuck_window: time::Duration::from_secs(
+                        plant.moisture.faults.stuck_window_seconds,
+                    ),
+                    max_step: plant.moisture.faults.max_step_volts,
+                    recovery_readings: plant.moisture.faults.recovery_readings,
                 },
+                soil_temperature_probe: plant.soil_temperature.map(|s| s.probe),
+                sample_burst_linger: time::Duration::from_secs(sampling.burst_linger_seconds),
                 pump_enabled: plant.pump.enabled,
-                pump_schedule: plant.pump.schedule.as_ref().map(|schedule| {
-                    cron::Schedule::from_str(&schedule.start)
-                        .map_err(|e| format_err!("failed to parse {}: {}", schedule.start, e))
-                        .unwrap()
-                }),
+                pump_schedule: match plant.pump.schedule {
+                    Some(ref schedule) => Some(load_schedule(location, schedule)?),
+                    None => None,
+                },
                 pump_duration: plant
                     .pump
                     .schedule
                     .as_ref()
                     .map(|schedule| time::Duration::from_secs(schedule.duration_seconds)),
-                pump_channel: plant.pump.channel as u64,
+                pump_output: pumps::Output {
+                    backend: gpio.backend,
+                    chip: plant.pump.chip.clone().unwrap_or_else(|| gpio.chip.clone()),
+                    line: plant.pump.channel,
+                    active_low: plant.pump.active_low,
+                },
+                pump_check: plant.pump.check.map(|check| model::PumpCheck {
+                    delay: time::Duration::from_secs(check.delay_seconds),
+                    window: time::Duration::from_secs(check.window_seconds),
+                    min_moisture_delta: check.min_moisture_delta,
+                    retry: check.retry,
+                }),
+                blackout: match plant.pump.blackout {
+                    Some(ref blackout) => global_blackout.with_override(blackout),
+                    None => global_blackout,
+                },
+                power_group: plant.pump.power_group,
+                pump_flow_ml_per_second: plant.pump.flow_ml_per_second,
             }))
         })
         .collect()
 }
+
+fn load_schedule(
+    location: &config::Location,
+    schedule: &config::PumpSchedule,
+) -> Result<schedule::Schedule, failure::Error> {
+    match (&schedule.start, &schedule.sun) {
+        (&Some(ref start), &None) => schedule::Schedule::cron(
+            start,
+            schedule.daylight_only,
+            location.timezone,
+            location.sun_location(),
+        ),
+        (&None, &Some(ref sun)) => schedule::Schedule::sun(
+            sun.event,
+            chrono::Duration::minutes(sun.offset_minutes),
+            location.timezone,
+            location.sun_location(),
+        ),
+        _ => bail!("A pump schedule needs exactly one of 'start' and 'sun'"),
+    }
+}
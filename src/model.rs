@@ -1,21 +1,59 @@
 use std::time;
 
-use ads1x15;
-use cron;
 use uuid;
 
+use blackout;
+use pumps;
+use schedule;
+use sensors;
+
 pub struct ModuleConfig {
     pub uuid: uuid::Uuid,
     pub name: String,
     pub description: String,
-    pub moisture_i2c_address: u16,
-    pub moisture_channel: ads1x15::Channel,
+    pub thirsty: bool,
+    // compensates the readings of the moisture probe
+    pub soil_temperature_probe: Option<String>,
+    // how long to keep sampling at the burst rate after watering
+    pub sample_burst_linger: time::Duration,
     pub pump_enabled: bool,
-    pub pump_schedule: Option<cron::Schedule>,
+    pub pump_schedule: Option<schedule::Schedule>,
     pub pump_duration: Option<time::Duration>,
-    pub pump_channel: u64,
+    pub pump_output: pumps::Output,
+    pub pump_check: Option<PumpCheck>,
+    pub blackout: blackout::Calendar,
+    pub power_group: Option<String>,
+    pub pump_flow_ml_per_second: Option<f64>,
     pub min_moisture: f64,
     pub max_moisture: f64,
     pub moisture_voltage_dry: f64,
     pub moisture_voltage_wet: f64,
+    pub moisture_temperature_coefficient: f64,
+    pub moisture_reference_temperature: f64,
+    pub moisture_faults: sensors::fault::Limits,
+}
+
+impl ModuleConfig {
+    /// Converts a raw moisture voltage into a moisture fraction, where 0.0 means dry and 1.0 means
+    /// wet.
+    pub fn moisture_fraction(&self, moisture_voltage: f64) -> f64 {
+        (self.moisture_voltage_dry - moisture_voltage)
+            / (self.moisture_voltage_dry - self.moisture_voltage_wet)
+    }
+
+    /// Corrects a raw moisture voltage for the soil temperature, so that it's comparable to the
+    /// voltages measured at the reference temperature.
+    pub fn compensate_moisture_voltage(&self, moisture_voltage: f64, soil_temperature: f64) -> f64 {
+        moisture_voltage
+            - self.moisture_temperature_coefficient
+                * (soil_temperature - self.moisture_reference_temperature)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PumpCheck {
+    pub delay: time::Duration,
+    pub window: time::Duration,
+    pub min_moisture_delta: f64,
+    pub retry: bool,
 }
@@ -1,3 +1,11 @@
+use std::path;
+
+use chrono;
+use uuid;
+
+use export;
+use import;
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "se")]
 pub struct Options {
@@ -19,4 +27,136 @@ pub struct Options {
     /// final log verbosity.
     #[structopt(short = "q", long = "quiet", parse(from_occurrences))]
     pub quiet: u8,
+
+    /// Record the samples of every sensor and the pump events to this file, for replaying them
+    /// later.
+    #[structopt(long = "record", parse(from_os_str))]
+    pub record: Option<path::PathBuf>,
+
+    /// What to do; runs the irrigation controller if omitted.
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// List plants for which automatic watering has been disabled after a failed watering.
+    #[structopt(name = "lockouts")]
+    Lockouts,
+
+    /// Run the pump of a plant now, or as soon as blackout windows allow.
+    #[structopt(name = "water")]
+    Water {
+        /// The UUID of the plant.
+        #[structopt(name = "PLANT")]
+        plant: uuid::Uuid,
+
+        /// Run the pump even during blackout windows.
+        #[structopt(short = "f", long = "force")]
+        force: bool,
+    },
+
+    /// Show which pumps are running or waiting for their power group.
+    #[structopt(name = "queue")]
+    Queue,
+
+    /// Plan how to ration the water reservoir during a vacation.
+    #[structopt(name = "vacation")]
+    Vacation {
+        /// The first day of the vacation; defaults to the configured vacation.
+        #[structopt(long = "from")]
+        from: Option<chrono::NaiveDate>,
+
+        /// The last day of the vacation; defaults to the configured vacation.
+        #[structopt(long = "to")]
+        to: Option<chrono::NaiveDate>,
+
+        /// The volume of the reservoir; defaults to the configured vacation.
+        #[structopt(long = "reservoir-liters")]
+        reservoir_liters: Option<f64>,
+    },
+
+    /// Acknowledge a failed watering, re-enabling automatic watering for the plant.
+    #[structopt(name = "acknowledge")]
+    Acknowledge {
+        /// The UUID of the plant.
+        #[structopt(name = "PLANT")]
+        plant: uuid::Uuid,
+    },
+
+    /// List the devices that respond on the I2C buses, and which configured devices are missing.
+    #[structopt(name = "i2c-scan")]
+    I2cScan {
+        /// The bus to scan, like /dev/i2c-1; defaults to every bus in the config.
+        #[structopt(long = "bus")]
+        bus: Option<String>,
+    },
+
+    /// Export the plant, climate and pump data of a range of days as CSV or Parquet.
+    #[structopt(name = "export")]
+    Export {
+        /// The first day to export.
+        #[structopt(long = "from")]
+        from: chrono::NaiveDate,
+
+        /// The last day to export; defaults to today.
+        #[structopt(long = "to")]
+        to: Option<chrono::NaiveDate>,
+
+        /// Only export the data of this plant, by UUID; can be repeated.  Defaults to every plant.
+        #[structopt(long = "plant")]
+        plants: Vec<uuid::Uuid>,
+
+        /// Either csv or parquet.
+        #[structopt(long = "format", default_value = "csv")]
+        format: export::Format,
+
+        /// The file to write; CSV is written to stdout if omitted.
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: Option<path::PathBuf>,
+    },
+
+    /// Import plant, climate and pump data from CSV or InfluxDB line protocol, keeping the original
+    /// timestamps.
+    #[structopt(name = "import")]
+    Import {
+        /// The file to import.
+        #[structopt(name = "FILE", parse(from_os_str))]
+        path: path::PathBuf,
+
+        /// Either csv or line-protocol; guessed from the file extension if omitted.
+        #[structopt(long = "format")]
+        format: Option<import::Format>,
+
+        /// The unit of numeric timestamps: ns, us, ms or s.
+        #[structopt(long = "precision", default_value = "ns")]
+        precision: import::Precision,
+
+        /// The measurement of CSV files without a measurement column.
+        #[structopt(long = "measurement")]
+        measurement: Option<String>,
+
+        /// How many points to write at a time.
+        #[structopt(long = "batch-size", default_value = "5000")]
+        batch_size: usize,
+
+        /// Skip invalid points instead of stopping at the first one.
+        #[structopt(long = "skip-invalid")]
+        skip_invalid: bool,
+    },
+
+    /// Run the irrigation controller on a recording made with --record instead of the sensors,
+    /// with simulated pumps and a virtual clock that goes from one event to the next.  Nothing is
+    /// written to the database, lockouts are kept in a temporary directory, and the API isn't
+    /// served.
+    #[structopt(name = "replay")]
+    Replay {
+        /// The recording to replay.
+        #[structopt(name = "FILE", parse(from_os_str))]
+        path: path::PathBuf,
+
+        /// How many times faster than real time to replay; as fast as possible by default.
+        #[structopt(long = "speed")]
+        speed: Option<f64>,
+    },
 }
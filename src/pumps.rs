@@ -1,53 +1,224 @@
+use std::sync;
+
 use failure;
+use gpio_cdev;
 use slog;
 use sysfs_gpio;
 
+/// How to access GPIO lines.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// The GPIO character device (`/dev/gpiochipN`); lines are released when precip exits.
+    Cdev,
+    /// The deprecated sysfs interface (`/sys/class/gpio`).
+    Sysfs,
+    /// No hardware at all; the state of the pumps is only kept in memory, like during replays.
+    Simulated,
+}
+
+#[derive(Clone, Debug)]
+pub struct Output {
+    pub backend: Backend,
+    // only used by the cdev backend
+    pub chip: String,
+    // the line offset for the cdev backend, or the global pin number for the sysfs backend
+    pub line: u32,
+    pub active_low: bool,
+}
+
 pub struct Pump {
     log: slog::Logger,
-    pin: sysfs_gpio::Pin,
+    line: Line,
+}
+
+enum Line {
+    Cdev {
+        chip: String,
+        offset: u32,
+        handle: gpio_cdev::LineHandle,
+    },
+    Sysfs {
+        pin: sysfs_gpio::Pin,
+        // sysfs values are raw line levels, so active-low pumps are inverted in software
+        active_low: bool,
+    },
+    Simulated {
+        label: String,
+        running: sync::atomic::AtomicBool,
+    },
 }
 
 impl Pump {
-    pub fn new(log: slog::Logger, pin: u64) -> Result<Self, failure::Error> {
-        debug!(log, "creating pin {}", pin);
-        let pin = sysfs_gpio::Pin::new(pin);
-        debug!(log, "exporting pin {}", pin.get_pin());
-        pin.export()?;
-        debug!(log, "setting direction of pin {} to low", pin.get_pin());
-        pin.set_direction(sysfs_gpio::Direction::Low)?;
-
-        Ok(Pump { log, pin })
+    pub fn new(log: slog::Logger, label: &str, output: &Output) -> Result<Self, failure::Error> {
+        let line = match output.backend {
+            Backend::Cdev => {
+                debug!(
+                    log,
+                    "requesting line {} of {} as {:?}", output.line, output.chip, label
+                );
+                // The kernel inverts the values of active-low lines, and applies the initial
+                // (logical off) value as part of configuring the line as an output.
+                let mut flags = gpio_cdev::LineRequestFlags::OUTPUT;
+                if output.active_low {
+                    flags |= gpio_cdev::LineRequestFlags::ACTIVE_LOW;
+                }
+
+                let handle = gpio_cdev::Chip::new(&output.chip)
+                    .and_then(|mut chip| chip.get_line(output.line))
+                    .and_then(|line| line.request(flags, 0, label))
+                    .map_err(|e| {
+                        format_err!(
+                            "failed to request line {} of {}: {}",
+                            output.line,
+                            output.chip,
+                            e
+                        )
+                    })?;
+
+                Line::Cdev {
+                    chip: output.chip.clone(),
+                    offset: output.line,
+                    handle,
+                }
+            }
+            Backend::Sysfs => {
+                debug!(log, "creating pin {}", output.line);
+                let pin = sysfs_gpio::Pin::new(u64::from(output.line));
+                debug!(log, "exporting pin {}", pin.get_pin());
+                pin.export()?;
+
+                // Setting the direction to an explicit level configures the pin as an output and
+                // sets its level in a single write, so that the pump is never briefly turned on.
+                let (direction, level) = if output.active_low {
+                    (sysfs_gpio::Direction::High, "high")
+                } else {
+                    (sysfs_gpio::Direction::Low, "low")
+                };
+                debug!(
+                    log,
+                    "setting direction of pin {} to {}",
+                    pin.get_pin(),
+                    level
+                );
+                pin.set_direction(direction)?;
+
+                Line::Sysfs {
+                    pin,
+                    active_low: output.active_low,
+                }
+            }
+            Backend::Simulated => Line::Simulated {
+                label: label.to_owned(),
+                running: sync::atomic::AtomicBool::new(false),
+            },
+        };
+
+        Ok(Pump { log, line })
     }
 
     pub fn running(&self) -> Result<bool, failure::Error> {
-        debug!(self.log, "getting value of pin {}", self.pin.get_pin());
-        let result = self.pin.get_value()? != 0;
+        let result = match self.line {
+            Line::Cdev {
+                ref chip,
+                offset,
+                ref handle,
+            } => {
+                debug!(self.log, "getting value of line {} of {}", offset, chip);
+                handle
+                    .get_value()
+                    .map_err(|e| format_err!("failed to get value of line {}: {}", offset, e))?
+                    != 0
+            }
+            Line::Sysfs {
+                ref pin,
+                active_low,
+            } => {
+                debug!(self.log, "getting value of pin {}", pin.get_pin());
+                (pin.get_value()? != 0) != active_low
+            }
+            Line::Simulated { ref running, .. } => running.load(sync::atomic::Ordering::SeqCst),
+        };
         Ok(result)
     }
 
     pub fn set_running(&self, running: bool) -> Result<(), failure::Error> {
-        let value = if running { 1 } else { 0 };
-        debug!(
-            self.log,
-            "setting value of pin {} to {}",
-            self.pin.get_pin(),
-            value
-        );
-        self.pin.set_value(value)?;
+        match self.line {
+            Line::Cdev {
+                ref chip,
+                offset,
+                ref handle,
+            } => {
+                let value = if running { 1 } else { 0 };
+                debug!(
+                    self.log,
+                    "setting value of line {} of {} to {}", offset, chip, value
+                );
+                handle
+                    .set_value(value)
+                    .map_err(|e| format_err!("failed to set value of line {}: {}", offset, e))?;
+            }
+            Line::Sysfs {
+                ref pin,
+                active_low,
+            } => {
+                let value = if running != active_low { 1 } else { 0 };
+                debug!(
+                    self.log,
+                    "setting value of pin {} to {}",
+                    pin.get_pin(),
+                    value
+                );
+                pin.set_value(value)?;
+            }
+            Line::Simulated {
+                ref label,
+                running: ref state,
+            } => {
+                debug!(
+                    self.log,
+                    "setting simulated pump {:?} to {}", label, running
+                );
+                state.store(running, sync::atomic::Ordering::SeqCst);
+            }
+        }
         Ok(())
     }
 }
 
 impl Drop for Pump {
     fn drop(&mut self) {
-        debug!(self.log, "unexporting pin {}", self.pin.get_pin());
-        if let Err(e) = self.pin.unexport() {
-            error!(
-                self.log,
-                "could not unexport pin {}: {}",
-                self.pin.get_pin(),
-                e
-            );
+        match self.line {
+            Line::Cdev {
+                ref chip,
+                offset,
+                ref handle,
+            } => {
+                // The line is released when the handle is dropped, but make sure that the pump is
+                // off when that happens.
+                debug!(self.log, "releasing line {} of {}", offset, chip);
+                if let Err(e) = handle.set_value(0) {
+                    error!(
+                        self.log,
+                        "could not turn off line {} of {}: {}", offset, chip, e
+                    );
+                }
+            }
+            Line::Sysfs {
+                ref pin,
+                active_low,
+            } => {
+                // Unexporting leaves the pin at whatever level it had, so turn the pump off first
+                let off = if active_low { 1 } else { 0 };
+                if let Err(e) = pin.set_value(off) {
+                    error!(self.log, "could not turn off pin {}: {}", pin.get_pin(), e);
+                }
+                debug!(self.log, "unexporting pin {}", pin.get_pin());
+                if let Err(e) = pin.unexport() {
+                    error!(self.log, "could not unexport pin {}: {}", pin.get_pin(), e);
+                }
+            }
+            Line::Simulated { .. } => {}
         }
     }
 }
@@ -0,0 +1,150 @@
+use std::collections;
+use std::sync;
+
+use failure;
+use futures;
+use uuid;
+
+/// Limits how many pumps of each power group may run at once, letting waiting pumps run in the
+/// order that they asked to.
+pub struct RunQueue {
+    groups: sync::Mutex<collections::HashMap<String, Group>>,
+}
+
+/// The right to run a pump; other pumps in the same power group may start once this is dropped.
+pub struct Permit {
+    queue: Option<sync::Arc<RunQueue>>,
+    group: String,
+    uuid: uuid::Uuid,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GroupStatus {
+    pub name: String,
+    pub max_running: usize,
+    pub running: Vec<uuid::Uuid>,
+    pub waiting: Vec<uuid::Uuid>,
+}
+
+struct Group {
+    max_running: usize,
+    running: Vec<uuid::Uuid>,
+    waiting: collections::VecDeque<(uuid::Uuid, futures::sync::oneshot::Sender<Permit>)>,
+}
+
+impl RunQueue {
+    pub fn new(max_running: collections::HashMap<String, usize>) -> Self {
+        let groups = max_running
+            .into_iter()
+            .map(|(name, max_running)| {
+                (
+                    name,
+                    Group {
+                        max_running,
+                        running: Vec::new(),
+                        waiting: collections::VecDeque::new(),
+                    },
+                )
+            })
+            .collect();
+
+        RunQueue {
+            groups: sync::Mutex::new(groups),
+        }
+    }
+
+    /// Waits until the pump of the specified plant may run.
+    ///
+    /// Pumps without a power group may always run; for those, the returned permit does nothing.
+    pub fn acquire(
+        queue: sync::Arc<RunQueue>,
+        group: Option<&str>,
+        uuid: uuid::Uuid,
+    ) -> impl futures::Future<Item = Permit, Error = failure::Error> {
+        use futures::Future;
+
+        let (sender, receiver) = futures::sync::oneshot::channel();
+
+        match group {
+            None => {
+                let _ = sender.send(Permit {
+                    queue: None,
+                    group: String::new(),
+                    uuid,
+                });
+            }
+            Some(name) => {
+                let mut groups = queue.groups.lock().unwrap();
+                match groups.get_mut(name) {
+                    None => {
+                        let _ = sender.send(Permit {
+                            queue: None,
+                            group: name.to_owned(),
+                            uuid,
+                        });
+                    }
+                    Some(group) => {
+                        group.waiting.push_back((uuid, sender));
+                        group.dispatch(&queue, name);
+                    }
+                }
+            }
+        }
+
+        receiver.map_err(|_| failure::err_msg("the run queue was dropped"))
+    }
+
+    pub fn status(&self) -> Vec<GroupStatus> {
+        let groups = self.groups.lock().unwrap();
+        let mut result = groups
+            .iter()
+            .map(|(name, group)| GroupStatus {
+                name: name.clone(),
+                max_running: group.max_running,
+                running: group.running.clone(),
+                waiting: group.waiting.iter().map(|&(uuid, _)| uuid).collect(),
+            })
+            .collect::<Vec<_>>();
+        result.sort_by(|a, b| a.name.cmp(&b.name));
+        result
+    }
+
+    fn release(queue: &sync::Arc<RunQueue>, name: &str, uuid: uuid::Uuid) {
+        let mut groups = queue.groups.lock().unwrap();
+        if let Some(group) = groups.get_mut(name) {
+            group.running.retain(|u| *u != uuid);
+            group.dispatch(queue, name);
+        }
+    }
+}
+
+impl Group {
+    fn dispatch(&mut self, queue: &sync::Arc<RunQueue>, name: &str) {
+        while self.running.len() < self.max_running {
+            let (uuid, sender) = match self.waiting.pop_front() {
+                Some(waiting) => waiting,
+                None => break,
+            };
+
+            let permit = Permit {
+                queue: Some(queue.clone()),
+                group: name.to_owned(),
+                uuid,
+            };
+
+            match sender.send(permit) {
+                Ok(()) => self.running.push(uuid),
+                // The waiting pump gave up; don't let its permit release anything.
+                Err(mut permit) => permit.queue = None,
+            }
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(queue) = self.queue.take() {
+            RunQueue::release(&queue, &self.group, self.uuid);
+        }
+    }
+}
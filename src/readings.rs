@@ -0,0 +1,230 @@
+use std::collections;
+use std::sync;
+
+use chrono;
+use uuid;
+
+use sensors;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Reading {
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub moisture_voltage: f64,
+    pub health: sensors::fault::Health,
+}
+
+/// The most recent sensor readings of every plant, shared between the sampling and pump jobs.
+pub struct Readings {
+    // how long to keep readings, by the time they were taken
+    retention: chrono::Duration,
+    history: sync::RwLock<collections::HashMap<uuid::Uuid, collections::VecDeque<Reading>>>,
+    // plants that should be sampled at the burst rate, until the specified time if any
+    bursts: sync::RwLock<collections::HashMap<uuid::Uuid, Option<chrono::DateTime<chrono::Utc>>>>,
+}
+
+impl Readings {
+    /// Keeps the readings of the specified period before the latest reading of a plant.
+    pub fn new(retention: chrono::Duration) -> Self {
+        Readings {
+            retention,
+            history: sync::RwLock::new(collections::HashMap::new()),
+            bursts: sync::RwLock::new(collections::HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, uuid: uuid::Uuid, reading: Reading) {
+        let mut history = self.history.write().unwrap();
+        let readings = history
+            .entry(uuid)
+            .or_insert_with(collections::VecDeque::new);
+
+        readings.push_back(reading);
+        let oldest = reading.time - self.retention;
+        while readings.front().map_or(false, |r| r.time < oldest) {
+            readings.pop_front();
+        }
+    }
+
+    pub fn latest(&self, uuid: uuid::Uuid) -> Option<Reading> {
+        self.history
+            .read()
+            .unwrap()
+            .get(&uuid)
+            .and_then(|readings| readings.back().cloned())
+    }
+
+    /// The health of the moisture sensor of a plant, as of its latest reading.
+    pub fn health(&self, uuid: uuid::Uuid) -> Option<sensors::fault::Health> {
+        self.latest(uuid).map(|reading| reading.health)
+    }
+
+    /// Whether any reading taken at or after `since` came from a faulty sensor.
+    pub fn faulty_since(&self, uuid: uuid::Uuid, since: chrono::DateTime<chrono::Utc>) -> bool {
+        self.history
+            .read()
+            .unwrap()
+            .get(&uuid)
+            .map_or(false, |readings| {
+                readings
+                    .iter()
+                    .any(|r| r.time >= since && !r.health.is_ok())
+            })
+    }
+
+    /// The mean moisture voltage of all plausible readings taken from `from` up to and including
+    /// `to`.
+    pub fn mean_moisture_voltage_between(
+        &self,
+        uuid: uuid::Uuid,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+    ) -> Option<f64> {
+        let history = self.history.read().unwrap();
+        let readings = history.get(&uuid)?;
+
+        let (sum, count) = readings
+            .iter()
+            .filter(|r| from <= r.time && r.time <= to && r.health.is_ok())
+            .fold((0.0, 0), |(sum, count), r| {
+                (sum + r.moisture_voltage, count + 1)
+            });
+
+        if count == 0 {
+            None
+        } else {
+            Some(sum / count as f64)
+        }
+    }
+
+    /// Starts sampling a plant at the burst rate, until `end_burst` is called.
+    pub fn begin_burst(&self, uuid: uuid::Uuid) {
+        self.bursts.write().unwrap().insert(uuid, None);
+    }
+
+    /// Keeps sampling a plant at the burst rate until the specified time, and then stops.
+    pub fn end_burst(&self, uuid: uuid::Uuid, until: chrono::DateTime<chrono::Utc>) {
+        self.bursts.write().unwrap().insert(uuid, Some(until));
+    }
+
+    pub fn in_burst(&self, uuid: uuid::Uuid, now: chrono::DateTime<chrono::Utc>) -> bool {
+        match self.bursts.read().unwrap().get(&uuid) {
+            Some(&Some(until)) => now < until,
+            Some(&None) => true,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono;
+    use uuid;
+
+    use chrono::TimeZone;
+
+    use sensors;
+
+    use super::*;
+
+    fn reading(seconds: i64, moisture_voltage: f64) -> Reading {
+        Reading {
+            time: chrono::Utc.timestamp(1_541_000_000 + seconds, 0),
+            moisture_voltage,
+            health: sensors::fault::Health::Ok,
+        }
+    }
+
+    #[test]
+    fn keeps_readings_by_time() {
+        let plant = uuid::Uuid::new_v4();
+        let readings = Readings::new(chrono::Duration::minutes(20));
+        // 1 Hz for half an hour, far more than the 300 readings that used to be kept
+        for second in 0..1800 {
+            readings.record(plant, reading(second, 1.0));
+        }
+
+        let from = reading(0, 0.0).time;
+        let to = reading(1799, 0.0).time;
+        let count = readings.history.read().unwrap()[&plant].len();
+        assert_eq!(count, 1201);
+        assert_eq!(
+            readings.mean_moisture_voltage_between(plant, from, reading(598, 0.0).time),
+            None
+        );
+        assert_eq!(
+            readings.mean_moisture_voltage_between(plant, from, to),
+            Some(1.0)
+        );
+    }
+
+    #[test]
+    fn mean_between_is_bounded_on_both_ends() {
+        let plant = uuid::Uuid::new_v4();
+        let readings = Readings::new(chrono::Duration::hours(1));
+        for second in 0..60 {
+            readings.record(plant, reading(second, 2.0));
+        }
+        for second in 60..120 {
+            readings.record(plant, reading(second, 1.0));
+        }
+
+        let before = readings.mean_moisture_voltage_between(
+            plant,
+            reading(0, 0.0).time,
+            reading(59, 0.0).time,
+        );
+        let after = readings.mean_moisture_voltage_between(
+            plant,
+            reading(60, 0.0).time,
+            reading(119, 0.0).time,
+        );
+        assert_eq!(before, Some(2.0));
+        assert_eq!(after, Some(1.0));
+        assert_eq!(
+            readings.mean_moisture_voltage_between(
+                plant,
+                reading(200, 0.0).time,
+                reading(300, 0.0).time
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn mean_between_ignores_faulty_readings() {
+        let plant = uuid::Uuid::new_v4();
+        let readings = Readings::new(chrono::Duration::hours(1));
+        readings.record(plant, reading(0, 1.0));
+        readings.record(
+            plant,
+            Reading {
+                health: sensors::fault::Health::OutOfRange,
+                ..reading(1, 5.0)
+            },
+        );
+
+        assert_eq!(
+            readings.mean_moisture_voltage_between(
+                plant,
+                reading(0, 0.0).time,
+                reading(1, 0.0).time
+            ),
+            Some(1.0)
+        );
+    }
+
+    #[test]
+    fn bursts_last_until_the_time_they_end_at() {
+        let plant = uuid::Uuid::new_v4();
+        let readings = Readings::new(chrono::Duration::hours(1));
+        let at = |seconds| reading(seconds, 0.0).time;
+        assert!(!readings.in_burst(plant, at(0)));
+
+        readings.begin_burst(plant);
+        assert!(readings.in_burst(plant, at(3600)));
+
+        readings.end_burst(plant, at(60));
+        assert!(readings.in_burst(plant, at(59)));
+        assert!(!readings.in_burst(plant, at(60)));
+    }
+}
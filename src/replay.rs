@@ -0,0 +1,337 @@
+//! Recording of sensor samples and pump events to a file, and replaying of such a recording in
+//! place of the sensors, so that an incident can be reproduced at an accelerated time.
+//!
+//! A recording has one JSON object per line, like
+//! `{"event":"pump","time":"2018-11-20T06:00:00Z","plant":"...","running":true}`.
+
+use std::cmp;
+use std::collections;
+use std::fs;
+use std::io;
+use std::path;
+use std::sync;
+use std::time;
+
+use chrono;
+use failure;
+use futures;
+use serde_json;
+use slog;
+use tokio;
+use uuid;
+
+use futures::prelude::async;
+use futures::prelude::await;
+
+use clock;
+use sensors;
+use util;
+
+// How many rounds of the executor in a row have to pass without any job starting or stopping to
+// sleep before the jobs are considered to all be waiting
+const SETTLE_ROUNDS: usize = 2;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Sample {
+        time: chrono::DateTime<chrono::Utc>,
+        measurement: String,
+        sensor: String,
+        values: Vec<(sensors::Quantity, f64)>,
+    },
+    Pump {
+        time: chrono::DateTime<chrono::Utc>,
+        plant: uuid::Uuid,
+        running: bool,
+    },
+}
+
+/// Appends events to a recording.
+pub struct Recorder {
+    log: slog::Logger,
+    path: path::PathBuf,
+    file: sync::Mutex<io::BufWriter<fs::File>>,
+}
+
+/// A sensor whose samples are recorded.
+struct Recorded {
+    recorder: sync::Arc<Recorder>,
+    measurement: String,
+    name: String,
+    sensor: Box<sensors::Sensor>,
+}
+
+/// A recording read into memory.
+pub struct Recording {
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+    // by measurement and sensor name, in order of time
+    samples: collections::HashMap<(String, String), sync::Arc<Vec<sensors::Sample>>>,
+    pumps: Vec<(chrono::DateTime<chrono::Utc>, uuid::Uuid, bool)>,
+}
+
+/// A sensor that returns the latest recorded sample at the time of the clock.
+struct Replayed {
+    name: String,
+    clock: clock::Clock,
+    samples: sync::Arc<Vec<sensors::Sample>>,
+    next: usize,
+}
+
+impl Event {
+    pub fn time(&self) -> chrono::DateTime<chrono::Utc> {
+        match *self {
+            Event::Sample { time, .. } | Event::Pump { time, .. } => time,
+        }
+    }
+}
+
+impl Recorder {
+    /// Appends to the file if it exists.
+    pub fn create(log: slog::Logger, path: &path::Path) -> Result<Self, failure::Error> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format_err!("failed to open recording {:?}: {}", path, e))?;
+        info!(log, "recording samples and pump events path={:?}", path);
+
+        Ok(Recorder {
+            log,
+            path: path.to_owned(),
+            file: sync::Mutex::new(io::BufWriter::new(file)),
+        })
+    }
+
+    /// Failing to record is logged, but doesn't stop anything else.
+    pub fn record(&self, event: &Event) {
+        use std::io::Write;
+
+        let mut file = self.file.lock().unwrap();
+        let result = (|| -> Result<(), failure::Error> {
+            serde_json::to_writer(&mut *file, event)?;
+            file.write_all(b"\n")?;
+            file.flush()?;
+            Ok(())
+        })();
+        if let Err(e) = result {
+            warn!(
+                self.log,
+                "failed to record event path={:?}: {}", self.path, e
+            );
+        }
+    }
+
+    pub fn sensor(
+        recorder: sync::Arc<Recorder>,
+        measurement: String,
+        name: String,
+        sensor: Box<sensors::Sensor>,
+    ) -> Box<sensors::Sensor> {
+        Box::new(Recorded {
+            recorder,
+            measurement,
+            name,
+            sensor,
+        })
+    }
+}
+
+impl sensors::Sensor for Recorded {
+    fn sample(
+        &mut self,
+    ) -> Box<futures::Future<Item = sensors::Sample, Error = failure::Error> + Send> {
+        use futures::Future;
+
+        let recorder = self.recorder.clone();
+        let measurement = self.measurement.clone();
+        let name = self.name.clone();
+        Box::new(self.sensor.sample().map(move |sample| {
+            recorder.record(&Event::Sample {
+                time: sample.time,
+                measurement,
+                sensor: name,
+                values: sample.values.clone(),
+            });
+            sample
+        }))
+    }
+}
+
+impl Recording {
+    pub fn open(path: &path::Path) -> Result<Self, failure::Error> {
+        use std::io::BufRead;
+
+        let file = fs::File::open(path)
+            .map_err(|e| format_err!("failed to open recording {:?}: {}", path, e))?;
+        let mut events = Vec::new();
+        for (i, line) in io::BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event = serde_json::from_str::<Event>(&line)
+                .map_err(|e| format_err!("Line {} of {:?}: {}", i + 1, path, e))?;
+            events.push(event);
+        }
+        // Samples are recorded when they complete, so they can be slightly out of order
+        events.sort_by_key(|e| e.time());
+
+        let (start, end) = match (events.first(), events.last()) {
+            (Some(first), Some(last)) => (first.time(), last.time()),
+            _ => bail!("The recording {:?} is empty", path),
+        };
+
+        let mut samples = collections::HashMap::<_, Vec<_>>::new();
+        let mut pumps = Vec::new();
+        for event in events {
+            match event {
+                Event::Sample {
+                    time,
+                    measurement,
+                    sensor,
+                    values,
+                } => samples
+                    .entry((measurement, sensor))
+                    .or_default()
+                    .push(sensors::Sample { time, values }),
+                Event::Pump {
+                    time,
+                    plant,
+                    running,
+                } => pumps.push((time, plant, running)),
+            }
+        }
+
+        Ok(Recording {
+            start,
+            end,
+            samples: samples
+                .into_iter()
+                .map(|(key, samples)| (key, sync::Arc::new(samples)))
+                .collect(),
+            pumps,
+        })
+    }
+
+    pub fn start(&self) -> chrono::DateTime<chrono::Utc> {
+        self.start
+    }
+
+    pub fn end(&self) -> chrono::DateTime<chrono::Utc> {
+        self.end
+    }
+
+    /// The pump events of the recording, in order of time.
+    pub fn pumps(&self) -> &[(chrono::DateTime<chrono::Utc>, uuid::Uuid, bool)] {
+        &self.pumps
+    }
+
+    /// Replays the samples of a sensor, if it was recorded.
+    pub fn sensor(
+        &self,
+        clock: clock::Clock,
+        measurement: &str,
+        name: &str,
+    ) -> Option<Box<sensors::Sensor>> {
+        self.samples
+            .get(&(measurement.to_owned(), name.to_owned()))
+            .map(|samples| {
+                Box::new(Replayed {
+                    name: name.to_owned(),
+                    clock,
+                    samples: samples.clone(),
+                    next: 0,
+                }) as Box<sensors::Sensor>
+            })
+    }
+}
+
+impl sensors::Sensor for Replayed {
+    fn sample(
+        &mut self,
+    ) -> Box<futures::Future<Item = sensors::Sample, Error = failure::Error> + Send> {
+        let now = self.clock.now();
+        while self.next < self.samples.len() && self.samples[self.next].time <= now {
+            self.next += 1;
+        }
+
+        // The recorded time is kept, so that a replay stores and decides the same as the original
+        Box::new(futures::future::result(match self.next {
+            0 => Err(format_err!(
+                "No sample of {:?} was recorded before {}",
+                self.name,
+                now
+            )),
+            next => Ok(self.samples[next - 1].clone()),
+        }))
+    }
+}
+
+/// Moves the virtual time through the recording, to whatever the jobs wait for next or the next
+/// recorded pump event, once every job that was woken up waits again.  Logs the recorded pump
+/// events as it passes them, for comparison with what the replay does, and completes at the end of
+/// the recording.  Without a speed, the replay runs as fast as the jobs do.
+#[async]
+pub fn run(
+    log: slog::Logger,
+    timeline: sync::Arc<clock::Timeline>,
+    end: chrono::DateTime<chrono::Utc>,
+    pumps: Vec<(chrono::DateTime<chrono::Utc>, uuid::Uuid, bool)>,
+    speed: Option<f64>,
+) -> Result<(), failure::Error> {
+    let mut next_pump = 0;
+    loop {
+        await!(settle(timeline.clone()))?;
+
+        let now = timeline.now();
+        let next = [timeline.next_wakeup(), pumps.get(next_pump).map(|p| p.0)]
+            .iter()
+            .filter_map(|time| *time)
+            .fold(end, cmp::min);
+        if let Some(speed) = speed {
+            let real = (next - now).to_std().unwrap_or(time::Duration::new(0, 0));
+            await!(tokio::timer::Delay::new(
+                time::Instant::now() + scale(real, 1.0 / speed)
+            ))?;
+        }
+        timeline.advance(next);
+
+        while next_pump < pumps.len() && pumps[next_pump].0 <= next {
+            let (time, plant, running) = pumps[next_pump];
+            info!(
+                log,
+                "recorded pump event time={} running={} uuid={}", time, running, plant
+            );
+            next_pump += 1;
+        }
+
+        if next >= end {
+            info!(log, "reached the end of the recording time={}", end);
+            return Ok(());
+        }
+    }
+}
+
+// Lets everything that was woken up run until it waits again, which can take a few rounds of the
+// executor when the jobs wait for each other
+#[async]
+fn settle(timeline: sync::Arc<clock::Timeline>) -> Result<(), failure::Error> {
+    let mut quiet = 0;
+    while quiet < SETTLE_ROUNDS {
+        let generation = timeline.generation();
+        await!(util::yield_now())?;
+        quiet = if timeline.generation() == generation {
+            quiet + 1
+        } else {
+            0
+        };
+    }
+    Ok(())
+}
+
+fn scale(duration: time::Duration, factor: f64) -> time::Duration {
+    let scaled = (duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) * 1e-9) * factor;
+    time::Duration::new(scaled.trunc() as u64, (scaled.fract() * 1e9) as u32)
+}
@@ -0,0 +1,337 @@
+//! Summaries of the samples of a sensor over fixed intervals, so that not every sample has to be
+//! stored.
+
+use std::cmp;
+use std::time;
+
+use chrono;
+
+// Appended to the measurement of a sensor for the measurement of its rollups
+pub const MEASUREMENT_SUFFIX: &str = "_rollup";
+
+/// The distribution of the values of one field during an interval.
+#[derive(Clone, Copy, Debug)]
+pub struct Summary {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub p25: f64,
+    pub p50: f64,
+    pub p75: f64,
+}
+
+/// The summaries of all fields of a sensor during one interval.
+#[derive(Clone, Debug)]
+pub struct Rollup {
+    pub start: chrono::DateTime<chrono::Utc>,
+    pub fields: Vec<(String, Summary)>,
+}
+
+pub struct Accumulator {
+    // in seconds
+    interval: i64,
+    start: Option<i64>,
+    values: Vec<(String, Vec<f64>)>,
+}
+
+impl Summary {
+    /// Summarizes the values, or returns None if there are none.  Percentiles use the nearest rank,
+    /// like InfluxDB does.
+    pub fn of(values: &[f64]) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+
+        let mut sorted = values.to_vec();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(cmp::Ordering::Equal));
+        let percentile = |p: f64| {
+            let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+            sorted[rank.max(1) - 1]
+        };
+
+        Some(Summary {
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+            mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            p25: percentile(25.0),
+            p50: percentile(50.0),
+            p75: percentile(75.0),
+        })
+    }
+
+    /// The fields to store for a field of a sensor, like `moisture_p50`.
+    pub fn fields(&self, field: &str) -> Vec<(String, f64)> {
+        vec![
+            (format!("{}_min", field), self.min),
+            (format!("{}_max", field), self.max),
+            (format!("{}_mean", field), self.mean),
+            (format!("{}_p25", field), self.p25),
+            (format!("{}_p50", field), self.p50),
+            (format!("{}_p75", field), self.p75),
+        ]
+    }
+}
+
+impl Rollup {
+    pub fn fields(&self) -> Vec<(String, f64)> {
+        self.fields
+            .iter()
+            .flat_map(|&(ref field, ref summary)| summary.fields(field))
+            .collect()
+    }
+}
+
+impl Accumulator {
+    /// Intervals are aligned to the epoch, so that rollups of different sensors line up.
+    pub fn new(interval: time::Duration) -> Self {
+        Accumulator {
+            interval: cmp::max(1, interval.as_secs() as i64),
+            start: None,
+            values: Vec::new(),
+        }
+    }
+
+    /// Adds a sample, and returns the rollup of the previous interval if this sample started a new
+    /// one.
+    pub fn add(
+        &mut self,
+        now: chrono::DateTime<chrono::Utc>,
+        fields: &[(String, f64)],
+    ) -> Option<Rollup> {
+        let start = self.interval_start(now);
+
+        let rollup = match self.start {
+            Some(current) if current != start => self.take(current),
+            _ => None,
+        };
+        self.start = Some(start);
+
+        for &(ref field, value) in fields {
+            match self.values.iter().position(|&(ref f, _)| f == field) {
+                Some(i) => self.values[i].1.push(value),
+                None => self.values.push((field.clone(), vec![value])),
+            }
+        }
+
+        rollup
+    }
+
+    /// Returns the rollup of the current interval if it has ended by now, so that it doesn't wait
+    /// for a sample to arrive in a later interval.
+    pub fn flush(&mut self, now: chrono::DateTime<chrono::Utc>) -> Option<Rollup> {
+        match self.start {
+            Some(current) if self.interval_start(now) > current => {
+                self.start = None;
+                self.take(current)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the rollup of the samples so far, even though their interval hasn't ended yet.
+    pub fn finish(&mut self) -> Option<Rollup> {
+        let current = self.start.take()?;
+        self.take(current)
+    }
+
+    fn interval_start(&self, now: chrono::DateTime<chrono::Utc>) -> i64 {
+        let seconds = now.timestamp();
+        // Round down, also before the epoch
+        seconds - (seconds % self.interval + self.interval) % self.interval
+    }
+
+    fn take(&mut self, start: i64) -> Option<Rollup> {
+        let fields = self
+            .values
+            .drain(..)
+            .filter_map(|(field, values)| Summary::of(&values).map(|summary| (field, summary)))
+            .collect::<Vec<_>>();
+
+        if fields.is_empty() {
+            return None;
+        }
+        Some(Rollup {
+            start: chrono::DateTime::from_utc(
+                chrono::NaiveDateTime::from_timestamp(start, 0),
+                chrono::Utc,
+            ),
+            fields,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    fn moisture(value: f64) -> Vec<(String, f64)> {
+        vec![("moisture".to_owned(), value)]
+    }
+
+    fn summary(rollup: &Rollup, field: &str) -> Summary {
+        rollup
+            .fields
+            .iter()
+            .find(|&&(ref f, _)| f == field)
+            .map(|&(_, summary)| summary)
+            .unwrap()
+    }
+
+    #[test]
+    fn nothing_has_no_summary() {
+        assert!(Summary::of(&[]).is_none());
+    }
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let summary = Summary::of(&[7.0, 2.0, 10.0, 4.0, 1.0, 9.0, 3.0, 6.0, 5.0, 8.0]).unwrap();
+        assert_eq!(summary.min, 1.0);
+        assert_eq!(summary.max, 10.0);
+        assert_eq!(summary.mean, 5.5);
+        assert_eq!(summary.p25, 3.0);
+        assert_eq!(summary.p50, 5.0);
+        assert_eq!(summary.p75, 8.0);
+
+        let summary = Summary::of(&[4.0, 1.0, 3.0, 2.0]).unwrap();
+        assert_eq!((summary.p25, summary.p50, summary.p75), (1.0, 2.0, 3.0));
+
+        let summary = Summary::of(&[1.5]).unwrap();
+        assert_eq!(
+            (
+                summary.min,
+                summary.p25,
+                summary.p50,
+                summary.p75,
+                summary.max
+            ),
+            (1.5, 1.5, 1.5, 1.5, 1.5)
+        );
+    }
+
+    #[test]
+    fn intervals_are_aligned_to_the_epoch() {
+        let mut accumulator = Accumulator::new(time::Duration::from_secs(300));
+        let day = chrono::Utc.ymd(2018, 11, 20);
+        assert!(accumulator
+            .add(day.and_hms(6, 2, 30), &moisture(1.0))
+            .is_none());
+        assert!(accumulator
+            .add(day.and_hms(6, 4, 59), &moisture(3.0))
+            .is_none());
+
+        let rollup = accumulator
+            .add(day.and_hms(6, 5, 0), &moisture(5.0))
+            .unwrap();
+        assert_eq!(rollup.start, day.and_hms(6, 0, 0));
+        let moisture = summary(&rollup, "moisture");
+        assert_eq!((moisture.min, moisture.max, moisture.mean), (1.0, 3.0, 2.0));
+    }
+
+    #[test]
+    fn intervals_before_the_epoch_are_rounded_down() {
+        let mut accumulator = Accumulator::new(time::Duration::from_secs(300));
+        assert!(accumulator
+            .add(chrono::Utc.timestamp(-120, 0), &moisture(1.0))
+            .is_none());
+        assert!(accumulator
+            .add(chrono::Utc.timestamp(-1, 0), &moisture(1.0))
+            .is_none());
+
+        let rollup = accumulator
+            .add(chrono::Utc.timestamp(0, 0), &moisture(1.0))
+            .unwrap();
+        assert_eq!(rollup.start, chrono::Utc.timestamp(-300, 0));
+    }
+
+    #[test]
+    fn each_rollup_only_has_the_samples_of_its_interval() {
+        let mut accumulator = Accumulator::new(time::Duration::from_secs(60));
+        let day = chrono::Utc.ymd(2018, 11, 20);
+        accumulator.add(
+            day.and_hms(6, 0, 10),
+            &[("moisture".to_owned(), 1.0), ("voltage".to_owned(), 1.8)],
+        );
+        accumulator.add(day.and_hms(6, 0, 20), &moisture(2.0));
+
+        // Skipping intervals without samples
+        let rollup = accumulator
+            .add(day.and_hms(6, 3, 0), &moisture(7.0))
+            .unwrap();
+        assert_eq!(rollup.start, day.and_hms(6, 0, 0));
+        assert_eq!(rollup.fields.len(), 2);
+        assert_eq!(summary(&rollup, "moisture").max, 2.0);
+        assert_eq!(summary(&rollup, "voltage").mean, 1.8);
+
+        let rollup = accumulator
+            .add(day.and_hms(6, 4, 0), &moisture(9.0))
+            .unwrap();
+        assert_eq!(rollup.start, day.and_hms(6, 3, 0));
+        assert_eq!(rollup.fields.len(), 1);
+        assert_eq!(summary(&rollup, "moisture").min, 7.0);
+        assert_eq!(summary(&rollup, "moisture").max, 7.0);
+    }
+
+    #[test]
+    fn intervals_are_flushed_when_they_end() {
+        let mut accumulator = Accumulator::new(time::Duration::from_secs(60));
+        let day = chrono::Utc.ymd(2018, 11, 20);
+        assert!(accumulator.flush(day.and_hms(6, 0, 0)).is_none());
+        accumulator.add(day.and_hms(6, 0, 10), &moisture(1.0));
+        assert!(accumulator.flush(day.and_hms(6, 0, 59)).is_none());
+
+        let rollup = accumulator.flush(day.and_hms(6, 1, 0)).unwrap();
+        assert_eq!(rollup.start, day.and_hms(6, 0, 0));
+        assert_eq!(summary(&rollup, "moisture").mean, 1.0);
+        assert!(accumulator.flush(day.and_hms(6, 2, 0)).is_none());
+
+        // The next sample starts over, without a rollup of the flushed interval
+        assert!(accumulator
+            .add(day.and_hms(6, 1, 5), &moisture(2.0))
+            .is_none());
+        let rollup = accumulator
+            .add(day.and_hms(6, 2, 5), &moisture(3.0))
+            .unwrap();
+        assert_eq!(rollup.start, day.and_hms(6, 1, 0));
+        assert_eq!(summary(&rollup, "moisture").max, 2.0);
+    }
+
+    #[test]
+    fn finishing_returns_the_unfinished_interval() {
+        let mut accumulator = Accumulator::new(time::Duration::from_secs(60));
+        let day = chrono::Utc.ymd(2018, 11, 20);
+        assert!(accumulator.finish().is_none());
+        accumulator.add(day.and_hms(6, 0, 10), &moisture(1.0));
+        accumulator.add(day.and_hms(6, 0, 20), &moisture(3.0));
+
+        let rollup = accumulator.finish().unwrap();
+        assert_eq!(rollup.start, day.and_hms(6, 0, 0));
+        assert_eq!(summary(&rollup, "moisture").mean, 2.0);
+        assert!(accumulator.finish().is_none());
+    }
+
+    #[test]
+    fn rollups_have_fields_for_each_summary() {
+        let rollup = Rollup {
+            start: chrono::Utc.timestamp(0, 0),
+            fields: vec![("moisture".to_owned(), Summary::of(&[1.0]).unwrap())],
+        };
+        let names = rollup
+            .fields()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "moisture_min",
+                "moisture_max",
+                "moisture_mean",
+                "moisture_p25",
+                "moisture_p50",
+                "moisture_p75",
+            ]
+        );
+    }
+}
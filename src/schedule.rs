@@ -0,0 +1,266 @@
+use chrono;
+use chrono_tz;
+use cron;
+use failure;
+
+use sun;
+
+/// How many cron occurrences to consider before giving up on finding one in daylight.
+const MAX_CRON_CANDIDATES: usize = 10_000;
+/// How many days ahead to look for a sun event before giving up (e.g. during polar night).
+const MAX_SUN_DAYS: i64 = 366;
+
+/// When a pump should run, evaluated in the wall-clock time of the installation's timezone.
+pub struct Schedule {
+    kind: Kind,
+    timezone: chrono_tz::Tz,
+    location: Option<sun::Location>,
+}
+
+enum Kind {
+    Cron {
+        schedule: cron::Schedule,
+        daylight_only: bool,
+    },
+    Sun {
+        event: sun::Event,
+        offset: chrono::Duration,
+    },
+}
+
+impl Schedule {
+    pub fn cron(
+        expression: &str,
+        daylight_only: bool,
+        timezone: chrono_tz::Tz,
+        location: Option<sun::Location>,
+    ) -> Result<Self, failure::Error> {
+        use std::str::FromStr;
+
+        let schedule = cron::Schedule::from_str(expression)
+            .map_err(|e| format_err!("failed to parse {}: {}", expression, e))?;
+
+        if daylight_only && location.is_none() {
+            bail!("A location is required for daylight-only schedules");
+        }
+
+        Ok(Schedule {
+            kind: Kind::Cron {
+                schedule,
+                daylight_only,
+            },
+            timezone,
+            location,
+        })
+    }
+
+    pub fn sun(
+        event: sun::Event,
+        offset: chrono::Duration,
+        timezone: chrono_tz::Tz,
+        location: Option<sun::Location>,
+    ) -> Result<Self, failure::Error> {
+        if location.is_none() {
+            bail!("A location is required for sun-relative schedules");
+        }
+
+        Ok(Schedule {
+            kind: Kind::Sun { event, offset },
+            timezone,
+            location,
+        })
+    }
+
+    /// The first run strictly after the specified instant.
+    ///
+    /// Cron expressions match wall-clock times.  A wall-clock time that occurs twice when DST ends
+    /// only runs at its first occurrence, and a wall-clock time that is skipped when DST starts
+    /// runs at the corresponding instant after the transition instead; runs that would then
+    /// coincide are only returned once, as long as callers pass the previous run as `after`.
+    pub fn next_after(
+        &self,
+        after: chrono::DateTime<chrono::Utc>,
+    ) -> Option<chrono::DateTime<chrono::Utc>> {
+        match self.kind {
+            Kind::Cron {
+                ref schedule,
+                daylight_only,
+            } => self.next_cron_after(schedule, daylight_only, after),
+            Kind::Sun { event, offset } => self.next_sun_after(event, offset, after),
+        }
+    }
+
+    fn next_cron_after(
+        &self,
+        schedule: &cron::Schedule,
+        daylight_only: bool,
+        after: chrono::DateTime<chrono::Utc>,
+    ) -> Option<chrono::DateTime<chrono::Utc>> {
+        use chrono::TimeZone;
+
+        // The cron crate does not handle DST transitions, so evaluate it on wall-clock times
+        // disguised as UTC and resolve them in the real timezone afterwards.
+        let wall_clock = after.with_timezone(&self.timezone).naive_local();
+
+        schedule
+            .after(&chrono::Utc.from_utc_datetime(&wall_clock))
+            .take(MAX_CRON_CANDIDATES)
+            .map(|t| resolve_wall_clock(self.timezone, t.naive_utc()))
+            .filter(|t| *t > after)
+            .find(|t| !daylight_only || self.day_of(*t).is_daylight(*t))
+    }
+
+    fn next_sun_after(
+        &self,
+        event: sun::Event,
+        offset: chrono::Duration,
+        after: chrono::DateTime<chrono::Utc>,
+    ) -> Option<chrono::DateTime<chrono::Utc>> {
+        let location = self.location?;
+        let today = after.with_timezone(&self.timezone).date().naive_local();
+
+        (-1..MAX_SUN_DAYS)
+            .map(|days| today + chrono::Duration::days(days))
+            .filter_map(|date| sun::day(date, location).event(event))
+            .map(|t| t + offset)
+            .find(|t| *t > after)
+    }
+
+    fn day_of(&self, t: chrono::DateTime<chrono::Utc>) -> sun::Day {
+        match self.location {
+            Some(location) => sun::day(
+                t.with_timezone(&self.timezone).date().naive_local(),
+                location,
+            ),
+            None => sun::Day::PolarDay,
+        }
+    }
+}
+
+/// Resolves a wall-clock time in the specified timezone into an instant.
+///
+/// Wall-clock times that occur twice when DST ends resolve to their first occurrence, and
+/// wall-clock times that are skipped when DST starts resolve to the corresponding instant after
+/// the transition.
+pub fn resolve_wall_clock(
+    timezone: chrono_tz::Tz,
+    wall_clock: chrono::NaiveDateTime,
+) -> chrono::DateTime<chrono::Utc> {
+    use chrono::Offset;
+    use chrono::TimeZone;
+
+    match timezone.from_local_datetime(&wall_clock) {
+        chrono::LocalResult::Single(t) | chrono::LocalResult::Ambiguous(t, _) => {
+            t.with_timezone(&chrono::Utc)
+        }
+        chrono::LocalResult::None => {
+            // Interpret it using the offset in effect before the gap.
+            let offset = timezone
+                .offset_from_utc_datetime(&(wall_clock - chrono::Duration::days(1)))
+                .fix();
+            chrono::Utc.from_utc_datetime(&(wall_clock - offset))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono;
+    use chrono_tz;
+
+    use chrono::TimeZone;
+
+    use sun;
+
+    use super::*;
+
+    fn utc(s: &str) -> chrono::DateTime<chrono::Utc> {
+        s.parse().unwrap()
+    }
+
+    fn wall_clock(s: &str) -> chrono::NaiveDateTime {
+        chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn resolves_unambiguous_times() {
+        assert_eq!(
+            resolve_wall_clock(chrono_tz::Europe::Stockholm, wall_clock("2018-06-01 06:00")),
+            utc("2018-06-01T04:00:00Z")
+        );
+        assert_eq!(
+            resolve_wall_clock(chrono_tz::Europe::Stockholm, wall_clock("2018-12-01 06:00")),
+            utc("2018-12-01T05:00:00Z")
+        );
+    }
+
+    #[test]
+    fn resolves_times_in_dst_gap_after_the_gap() {
+        // 02:00 to 03:00 doesn't exist on the last Sunday of March
+        assert_eq!(
+            resolve_wall_clock(chrono_tz::Europe::Stockholm, wall_clock("2018-03-25 02:30")),
+            utc("2018-03-25T01:30:00Z")
+        );
+        assert_eq!(
+            utc("2018-03-25T01:30:00Z").with_timezone(&chrono_tz::Europe::Stockholm),
+            chrono_tz::Europe::Stockholm
+                .ymd(2018, 3, 25)
+                .and_hms(3, 30, 0)
+        );
+    }
+
+    #[test]
+    fn resolves_times_in_dst_overlap_to_first_occurrence() {
+        // 02:00 to 03:00 occurs twice on the last Sunday of October
+        assert_eq!(
+            resolve_wall_clock(chrono_tz::Europe::Stockholm, wall_clock("2018-10-28 02:30")),
+            utc("2018-10-28T00:30:00Z")
+        );
+    }
+
+    #[test]
+    fn cron_runs_once_across_dst_transitions() {
+        let schedule =
+            Schedule::cron("0 30 2 * * * *", false, chrono_tz::Europe::Stockholm, None).unwrap();
+
+        let gap = schedule.next_after(utc("2018-03-24T12:00:00Z")).unwrap();
+        assert_eq!(gap, utc("2018-03-25T01:30:00Z"));
+        assert_eq!(schedule.next_after(gap), Some(utc("2018-03-26T00:30:00Z")));
+
+        let overlap = schedule.next_after(utc("2018-10-27T12:00:00Z")).unwrap();
+        assert_eq!(overlap, utc("2018-10-28T00:30:00Z"));
+        assert_eq!(
+            schedule.next_after(overlap),
+            Some(utc("2018-10-29T01:30:00Z"))
+        );
+    }
+
+    #[test]
+    fn sun_schedules_follow_the_sun() {
+        let stockholm = sun::Location {
+            latitude: 59.33,
+            longitude: 18.07,
+        };
+        let schedule = Schedule::sun(
+            sun::Event::Sunrise,
+            chrono::Duration::minutes(30),
+            chrono_tz::Europe::Stockholm,
+            Some(stockholm),
+        )
+        .unwrap();
+
+        let next = schedule.next_after(utc("2018-06-20T12:00:00Z")).unwrap();
+        assert!((next - utc("2018-06-21T02:00:00Z")).num_minutes().abs() <= 2);
+    }
+
+    #[test]
+    fn sun_schedules_need_a_location() {
+        assert!(Schedule::sun(
+            sun::Event::Sunset,
+            chrono::Duration::zero(),
+            chrono_tz::UTC,
+            None
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,38 @@
+table! {
+    calibrations (plant_uuid) {
+        plant_uuid -> Uuid,
+        moisture_p05 -> Nullable<Float8>,
+        moisture_p95 -> Nullable<Float8>,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    measurements (measurement, tags, field, time) {
+        time -> Timestamptz,
+        measurement -> Text,
+        tags -> Jsonb,
+        field -> Text,
+        value -> Float8,
+        plant_uuid -> Nullable<Uuid>,
+    }
+}
+
+table! {
+    plants (uuid) {
+        uuid -> Uuid,
+        name -> Text,
+        description -> Text,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    pump_events (plant_uuid, time) {
+        time -> Timestamptz,
+        plant_uuid -> Uuid,
+        running -> Bool,
+    }
+}
+
+allow_tables_to_appear_in_same_query!(calibrations, measurements, plants, pump_events,);
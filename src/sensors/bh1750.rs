@@ -0,0 +1,58 @@
+//! A driver for the ROHM BH1750 ambient light sensor.
+
+use std::time;
+
+use failure;
+use i2cdev;
+
+use super::remaining;
+use super::LightSensor;
+
+pub const DEFAULT_ADDRESS: u16 = 0x23;
+
+const CMD_POWER_ON: u8 = 0x01;
+// Continuous measurements at 1 lx resolution
+const CMD_CONTINUOUS_HIGH_RES: u8 = 0x10;
+// The maximum duration of a high resolution measurement
+const MEASUREMENT_MILLIS: u64 = 180;
+
+pub struct Bh1750<D> {
+    device: D,
+    // when the first measurement is complete
+    ready: time::Instant,
+}
+
+impl<D> Bh1750<D>
+where
+    D: i2cdev::core::I2CDevice,
+    D::Error: Send + Sync + 'static,
+{
+    pub fn new(mut device: D) -> Result<Self, failure::Error> {
+        device.write(&[CMD_POWER_ON])?;
+        device.write(&[CMD_CONTINUOUS_HIGH_RES])?;
+        Ok(Bh1750 {
+            device,
+            ready: time::Instant::now() + time::Duration::from_millis(MEASUREMENT_MILLIS),
+        })
+    }
+}
+
+impl<D> LightSensor for Bh1750<D>
+where
+    D: i2cdev::core::I2CDevice + Send,
+    D::Error: Send + Sync + 'static,
+{
+    // Makes sure that the first read returns a complete measurement
+    fn prepare(&mut self) -> Result<time::Duration, failure::Error> {
+        Ok(remaining(self.ready))
+    }
+
+    fn read_lux(&mut self) -> Result<f64, failure::Error> {
+        let mut data = [0u8; 2];
+        self.device.read(&mut data)?;
+
+        let raw = u16::from(data[0]) << 8 | u16::from(data[1]);
+        // The measurement accuracy factor from the datasheet
+        Ok(f64::from(raw) / 1.2)
+    }
+}
@@ -0,0 +1,211 @@
+//! A driver for the Bosch BME280 temperature, pressure and humidity sensor.
+
+use std::time;
+
+use failure;
+use i2cdev;
+
+use super::remaining;
+use super::ClimateReading;
+use super::ClimateSensor;
+
+pub const DEFAULT_ADDRESS: u16 = 0x77;
+
+const CHIP_ID: u8 = 0x60;
+
+const REG_CALIB_00: u8 = 0x88;
+const REG_CHIP_ID: u8 = 0xd0;
+const REG_RESET: u8 = 0xe0;
+const REG_CALIB_26: u8 = 0xe1;
+const REG_CTRL_HUM: u8 = 0xf2;
+const REG_CTRL_MEAS: u8 = 0xf4;
+const REG_CONFIG: u8 = 0xf5;
+const REG_DATA: u8 = 0xf7;
+
+const RESET: u8 = 0xb6;
+// 16x oversampling of humidity
+const CTRL_HUM: u8 = 0b101;
+// 16x oversampling of temperature and pressure, normal mode
+const CTRL_MEAS: u8 = 0b101_101_11;
+// 0.5 ms standby, IIR filter coefficient 16
+const CONFIG: u8 = 0b000_100_00;
+// The start-up time after a reset, while the calibration is copied from the NVM
+const RESET_MILLIS: u64 = 10;
+// The maximum duration of a measurement at the configured oversampling, from section 9.1 of the
+// datasheet
+const MEASUREMENT_MILLIS: u64 = 113;
+
+pub struct Bme280<D> {
+    device: D,
+    // read once the reset has finished
+    calibration: Option<Calibration>,
+    // when the next step of the initialization can happen
+    ready: time::Instant,
+}
+
+struct Calibration {
+    t1: f64,
+    t2: f64,
+    t3: f64,
+    p1: f64,
+    p2: f64,
+    p3: f64,
+    p4: f64,
+    p5: f64,
+    p6: f64,
+    p7: f64,
+    p8: f64,
+    p9: f64,
+    h1: f64,
+    h2: f64,
+    h3: f64,
+    h4: f64,
+    h5: f64,
+    h6: f64,
+}
+
+impl<D> Bme280<D>
+where
+    D: i2cdev::core::I2CDevice,
+    D::Error: Send + Sync + 'static,
+{
+    pub fn new(mut device: D) -> Result<Self, failure::Error> {
+        let chip_id = device.smbus_read_byte_data(REG_CHIP_ID)?;
+        if chip_id != CHIP_ID {
+            bail!(
+                "Not a BME280 (chip id 0x{:02x}, expected 0x{:02x})",
+                chip_id,
+                CHIP_ID
+            );
+        }
+
+        device.smbus_write_byte_data(REG_RESET, RESET)?;
+
+        Ok(Bme280 {
+            device,
+            calibration: None,
+            ready: time::Instant::now() + time::Duration::from_millis(RESET_MILLIS),
+        })
+    }
+}
+
+impl<D> ClimateSensor for Bme280<D>
+where
+    D: i2cdev::core::I2CDevice + Send,
+    D::Error: Send + Sync + 'static,
+{
+    fn prepare(&mut self) -> Result<time::Duration, failure::Error> {
+        if self.calibration.is_none() && remaining(self.ready) == time::Duration::new(0, 0) {
+            let calibration = read_calibration(&mut self.device)?;
+
+            // ctrl_hum only takes effect after a write to ctrl_meas
+            self.device.smbus_write_byte_data(REG_CTRL_HUM, CTRL_HUM)?;
+            self.device.smbus_write_byte_data(REG_CONFIG, CONFIG)?;
+            self.device
+                .smbus_write_byte_data(REG_CTRL_MEAS, CTRL_MEAS)?;
+
+            self.calibration = Some(calibration);
+            // The data registers only hold a measurement once the first one has finished
+            self.ready = time::Instant::now() + time::Duration::from_millis(MEASUREMENT_MILLIS);
+        }
+        Ok(remaining(self.ready))
+    }
+
+    fn read(&mut self) -> Result<ClimateReading, failure::Error> {
+        let c = match self.calibration {
+            Some(ref calibration) => calibration,
+            None => bail!("The BME280 has not been calibrated yet"),
+        };
+
+        let mut data = [0u8; 8];
+        read_registers(&mut self.device, REG_DATA, &mut data)?;
+
+        let adc_p =
+            (u32::from(data[0]) << 12 | u32::from(data[1]) << 4 | u32::from(data[2]) >> 4) as f64;
+        let adc_t =
+            (u32::from(data[3]) << 12 | u32::from(data[4]) << 4 | u32::from(data[5]) >> 4) as f64;
+        let adc_h = (u32::from(data[6]) << 8 | u32::from(data[7])) as f64;
+
+        // Compensation formulas from section 8.1 of the datasheet
+        let var1 = (adc_t / 16384.0 - c.t1 / 1024.0) * c.t2;
+        let var2 = (adc_t / 131_072.0 - c.t1 / 8192.0) * (adc_t / 131_072.0 - c.t1 / 8192.0) * c.t3;
+        let t_fine = var1 + var2;
+        let temperature = t_fine / 5120.0;
+
+        let mut var1 = t_fine / 2.0 - 64000.0;
+        let mut var2 = var1 * var1 * c.p6 / 32768.0;
+        var2 += var1 * c.p5 * 2.0;
+        var2 = var2 / 4.0 + c.p4 * 65536.0;
+        var1 = (c.p3 * var1 * var1 / 524_288.0 + c.p2 * var1) / 524_288.0;
+        var1 = (1.0 + var1 / 32768.0) * c.p1;
+        let pressure = if var1 == 0.0 {
+            None
+        } else {
+            let mut p = 1_048_576.0 - adc_p;
+            p = (p - var2 / 4096.0) * 6250.0 / var1;
+            let var1 = c.p9 * p * p / 2_147_483_648.0;
+            let var2 = p * c.p8 / 32768.0;
+            // in Pa; reported in kPa like the BMP280
+            Some((p + (var1 + var2 + c.p7) / 16.0) / 1000.0)
+        };
+
+        let mut h = t_fine - 76800.0;
+        h = (adc_h - (c.h4 * 64.0 + c.h5 / 16384.0 * h))
+            * (c.h2 / 65536.0 * (1.0 + c.h6 / 67_108_864.0 * h * (1.0 + c.h3 / 67_108_864.0 * h)));
+        h *= 1.0 - c.h1 * h / 524_288.0;
+        let humidity = h.max(0.0).min(100.0);
+
+        Ok(ClimateReading {
+            temperature,
+            pressure,
+            humidity: Some(humidity),
+        })
+    }
+}
+
+fn read_calibration<D>(device: &mut D) -> Result<Calibration, failure::Error>
+where
+    D: i2cdev::core::I2CDevice,
+    D::Error: Send + Sync + 'static,
+{
+    let mut a = [0u8; 26];
+    read_registers(device, REG_CALIB_00, &mut a)?;
+    let mut b = [0u8; 7];
+    read_registers(device, REG_CALIB_26, &mut b)?;
+
+    let u16_at = |d: &[u8], i: usize| f64::from(u16::from(d[i]) | u16::from(d[i + 1]) << 8);
+    let i16_at =
+        |d: &[u8], i: usize| f64::from((u16::from(d[i]) | u16::from(d[i + 1]) << 8) as i16);
+
+    Ok(Calibration {
+        t1: u16_at(&a, 0),
+        t2: i16_at(&a, 2),
+        t3: i16_at(&a, 4),
+        p1: u16_at(&a, 6),
+        p2: i16_at(&a, 8),
+        p3: i16_at(&a, 10),
+        p4: i16_at(&a, 12),
+        p5: i16_at(&a, 14),
+        p6: i16_at(&a, 16),
+        p7: i16_at(&a, 18),
+        p8: i16_at(&a, 20),
+        p9: i16_at(&a, 22),
+        h1: f64::from(a[25]),
+        h2: i16_at(&b, 0),
+        h3: f64::from(b[2]),
+        // 12-bit signed values sharing a nibble
+        h4: f64::from((i16::from(b[3] as i8) << 4) | i16::from(b[4] & 0x0f)),
+        h5: f64::from((i16::from(b[5] as i8) << 4) | i16::from(b[4] >> 4)),
+        h6: f64::from(b[6] as i8),
+    })
+}
+
+fn read_registers<D>(device: &mut D, register: u8, data: &mut [u8]) -> Result<(), failure::Error>
+where
+    D: i2cdev::core::I2CDevice,
+    D::Error: Send + Sync + 'static,
+{
+    device.write(&[register])?;
+    device.read(data)?;
+    Ok(())
+}
@@ -0,0 +1,128 @@
+//! Reads Maxim DS18B20 temperature probes through the kernel 1-Wire (`w1-therm`) driver.
+
+use std::fs;
+use std::path;
+
+use failure;
+use futures;
+use tokio_threadpool;
+
+use futures::Future;
+
+const DEVICES_DIR: &str = "/sys/bus/w1/devices";
+
+#[derive(Clone)]
+pub struct Ds18b20 {
+    id: String,
+    path: path::PathBuf,
+}
+
+impl Ds18b20 {
+    /// Opens the probe with the specified ID, like `28-0316a2795dff`.
+    pub fn new(id: &str) -> Result<Self, failure::Error> {
+        let path = path::Path::new(DEVICES_DIR).join(id).join("w1_slave");
+        if !path.exists() {
+            bail!(
+                "No such 1-Wire device: {} (is the w1-therm module loaded?)",
+                id
+            );
+        }
+
+        Ok(Ds18b20 {
+            id: id.to_owned(),
+            path,
+        })
+    }
+
+    /// Reads the temperature in degrees Celsius; this blocks for the duration of a conversion,
+    /// which is up to 750 ms.
+    pub fn read_celsius(&self) -> Result<f64, failure::Error> {
+        let contents = fs::read_to_string(&self.path)?;
+        parse(&contents).ok_or_else(|| {
+            format_err!(
+                "Invalid reading from 1-Wire device {}: {:?}",
+                self.id,
+                contents
+            )
+        })
+    }
+
+    /// Reads the temperature on a blocking thread, so that the conversion doesn't hold up the
+    /// other jobs of the runtime.
+    pub fn read(&self) -> Box<futures::Future<Item = f64, Error = failure::Error> + Send> {
+        let probe = self.clone();
+        Box::new(
+            futures::future::poll_fn(move || tokio_threadpool::blocking(|| probe.read_celsius()))
+                .map_err(failure::Error::from)
+                .and_then(|result| result),
+        )
+    }
+}
+
+/// Parses the output of the driver, which looks like:
+///
+/// ```text
+/// 72 01 4b 46 7f ff 0e 10 57 : crc=57 YES
+/// 72 01 4b 46 7f ff 0e 10 57 t=23125
+/// ```
+fn parse(contents: &str) -> Option<f64> {
+    let mut lines = contents.lines();
+    if !lines.next()?.trim_right().ends_with("YES") {
+        return None;
+    }
+
+    let millidegrees = lines
+        .next()?
+        .rsplit("t=")
+        .next()?
+        .trim()
+        .parse::<i32>()
+        .ok()?;
+
+    // The power-on reset value is read back when the conversion didn't happen
+    if millidegrees == 85_000 {
+        return None;
+    }
+
+    Some(f64::from(millidegrees) / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_valid_reading_is_in_degrees() {
+        let contents = "72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n\
+                        72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+        assert_eq!(parse(contents), Some(23.125));
+
+        let below_zero = "5e ff 4b 46 7f ff 02 10 d7 : crc=d7 YES\n\
+                          5e ff 4b 46 7f ff 02 10 d7 t=-10125\n";
+        assert_eq!(parse(below_zero), Some(-10.125));
+    }
+
+    #[test]
+    fn readings_with_a_bad_crc_are_rejected() {
+        let contents = "72 01 4b 46 7f ff 0e 10 57 : crc=c3 NO\n\
+                        72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+        assert_eq!(parse(contents), None);
+    }
+
+    #[test]
+    fn the_power_on_value_is_rejected() {
+        let contents = "50 05 4b 46 7f ff 0c 10 1c : crc=1c YES\n\
+                        50 05 4b 46 7f ff 0c 10 1c t=85000\n";
+        assert_eq!(parse(contents), None);
+    }
+
+    #[test]
+    fn truncated_readings_are_rejected() {
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n"), None);
+        assert_eq!(
+            parse("72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n72 01 4b 46 7f ff 0e 10 57 t=\n"),
+            None
+        );
+    }
+}
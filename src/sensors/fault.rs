@@ -0,0 +1,224 @@
+//! Plausibility checks that tell a faulty moisture sensor apart from dry soil.
+
+use std::collections;
+use std::time;
+
+use chrono;
+
+/// The health of a sensor, as judged from its recent readings.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    Ok,
+    // outside of the calibrated range, like a disconnected or shorted probe
+    OutOfRange,
+    // hasn't changed at all for a while, like a probe that lost its ADC
+    Stuck,
+    // jumped further than soil moisture plausibly can between two readings
+    Step,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub min_voltage: f64,
+    pub max_voltage: f64,
+    pub stuck_tolerance: f64,
+    pub stuck_window: time::Duration,
+    pub max_step: f64,
+    pub recovery_readings: u32,
+}
+
+/// Tracks the health of one sensor; a fault is only cleared after enough plausible readings.
+pub struct Detector {
+    limits: Limits,
+    // the readings during the stuck window, and the last one before it
+    window: collections::VecDeque<(chrono::DateTime<chrono::Utc>, f64)>,
+    health: Health,
+    plausible_readings: u32,
+}
+
+impl Health {
+    pub fn is_ok(self) -> bool {
+        self == Health::Ok
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Health::Ok => "ok",
+            Health::OutOfRange => "out_of_range",
+            Health::Stuck => "stuck",
+            Health::Step => "step",
+        }
+    }
+}
+
+impl Detector {
+    pub fn new(limits: Limits) -> Self {
+        Detector {
+            limits,
+            window: collections::VecDeque::new(),
+            health: Health::Ok,
+            plausible_readings: 0,
+        }
+    }
+
+    /// Checks a reading against the previous ones, and returns the health of the sensor.
+    pub fn check(&mut self, now: chrono::DateTime<chrono::Utc>, voltage: f64) -> Health {
+        let last = self.window.back().map(|&(_, v)| v);
+        self.window.push_back((now, voltage));
+
+        let cutoff = chrono::Duration::from_std(self.limits.stuck_window)
+            .map(|window| now - window)
+            .unwrap_or(now);
+        while self.window.len() > 2 && self.window[1].0 <= cutoff {
+            self.window.pop_front();
+        }
+
+        let fault = if voltage < self.limits.min_voltage || voltage > self.limits.max_voltage {
+            Some(Health::OutOfRange)
+        } else if last.map_or(false, |last| (voltage - last).abs() > self.limits.max_step) {
+            Some(Health::Step)
+        } else if self.is_stuck(cutoff) {
+            Some(Health::Stuck)
+        } else {
+            None
+        };
+
+        match fault {
+            Some(fault) => {
+                self.health = fault;
+                self.plausible_readings = 0;
+            }
+            None if !self.health.is_ok() => {
+                self.plausible_readings += 1;
+                if self.plausible_readings >= self.limits.recovery_readings {
+                    self.health = Health::Ok;
+                }
+            }
+            None => {}
+        }
+        self.health
+    }
+
+    fn is_stuck(&self, cutoff: chrono::DateTime<chrono::Utc>) -> bool {
+        // Only judge once the readings cover the whole window
+        if self.window.front().map_or(true, |&(time, _)| time > cutoff) {
+            return false;
+        }
+
+        let (min, max) = self
+            .window
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &(_, v)| {
+                (min.min(v), max.max(v))
+            });
+        max - min <= self.limits.stuck_tolerance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    fn limits() -> Limits {
+        Limits {
+            min_voltage: 1.3,
+            max_voltage: 2.3,
+            stuck_tolerance: 0.001,
+            stuck_window: time::Duration::from_secs(600),
+            max_step: 0.5,
+            recovery_readings: 3,
+        }
+    }
+
+    fn at(minutes: i64) -> chrono::DateTime<chrono::Utc> {
+        chrono::Utc.ymd(2018, 11, 20).and_hms(6, 0, 0) + chrono::Duration::minutes(minutes)
+    }
+
+    #[test]
+    fn plausible_readings_are_ok() {
+        let mut detector = Detector::new(limits());
+        for (minute, &voltage) in [1.8, 1.85, 1.7, 1.9, 2.1].iter().enumerate() {
+            assert_eq!(detector.check(at(minute as i64), voltage), Health::Ok);
+        }
+    }
+
+    #[test]
+    fn out_of_range() {
+        let mut detector = Detector::new(limits());
+        assert_eq!(detector.check(at(0), 1.29), Health::OutOfRange);
+
+        let mut detector = Detector::new(limits());
+        assert_eq!(detector.check(at(0), 2.31), Health::OutOfRange);
+
+        // The limits themselves are in range
+        let mut detector = Detector::new(limits());
+        assert_eq!(detector.check(at(0), 1.3), Health::Ok);
+        let mut detector = Detector::new(limits());
+        assert_eq!(detector.check(at(0), 2.3), Health::Ok);
+    }
+
+    #[test]
+    fn step() {
+        let mut detector = Detector::new(limits());
+        assert_eq!(detector.check(at(0), 1.8), Health::Ok);
+        assert_eq!(detector.check(at(1), 2.2), Health::Ok);
+        assert_eq!(detector.check(at(2), 1.6), Health::Step);
+    }
+
+    #[test]
+    fn stuck_once_the_window_is_covered() {
+        let mut detector = Detector::new(limits());
+        for minute in 0..10 {
+            assert_eq!(detector.check(at(minute), 1.8), Health::Ok);
+        }
+        assert_eq!(detector.check(at(10), 1.8005), Health::Stuck);
+    }
+
+    #[test]
+    fn not_stuck_when_varying_beyond_the_tolerance() {
+        let mut detector = Detector::new(limits());
+        for minute in 0..20 {
+            let voltage = if minute % 2 == 0 { 1.8 } else { 1.802 };
+            assert_eq!(detector.check(at(minute), voltage), Health::Ok);
+        }
+    }
+
+    #[test]
+    fn recovers_after_enough_plausible_readings() {
+        let mut detector = Detector::new(limits());
+        assert_eq!(detector.check(at(0), 1.8), Health::Ok);
+        assert_eq!(detector.check(at(1), 3.3), Health::OutOfRange);
+        // Coming back into range is itself a step
+        assert_eq!(detector.check(at(2), 1.8), Health::Step);
+        assert_eq!(detector.check(at(3), 1.81), Health::Step);
+        assert_eq!(detector.check(at(4), 1.82), Health::Step);
+        assert_eq!(detector.check(at(5), 1.83), Health::Ok);
+    }
+
+    #[test]
+    fn another_fault_restarts_the_recovery() {
+        let mut detector = Detector::new(limits());
+        assert_eq!(detector.check(at(0), 1.0), Health::OutOfRange);
+        assert_eq!(detector.check(at(1), 1.4), Health::OutOfRange);
+        assert_eq!(detector.check(at(2), 1.45), Health::OutOfRange);
+        assert_eq!(detector.check(at(3), 2.4), Health::OutOfRange);
+        assert_eq!(detector.check(at(4), 2.2), Health::OutOfRange);
+        assert_eq!(detector.check(at(5), 2.15), Health::OutOfRange);
+        assert_eq!(detector.check(at(6), 2.1), Health::Ok);
+    }
+
+    #[test]
+    fn recovers_from_being_stuck() {
+        let mut detector = Detector::new(limits());
+        for minute in 0..10 {
+            detector.check(at(minute), 1.8);
+        }
+        assert_eq!(detector.check(at(10), 1.8), Health::Stuck);
+        assert_eq!(detector.check(at(11), 1.85), Health::Stuck);
+        assert_eq!(detector.check(at(12), 1.9), Health::Stuck);
+        assert_eq!(detector.check(at(13), 1.95), Health::Ok);
+    }
+}
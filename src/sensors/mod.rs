@@ -0,0 +1,364 @@
+use std::collections;
+use std::sync;
+use std::time;
+
+use ads1x15;
+use chrono;
+use failure;
+use futures;
+use i2cdev;
+use i2cdev_bmp280;
+use i2csensors;
+use slog;
+
+use i2c;
+
+use futures::prelude::async;
+use futures::prelude::await;
+
+pub mod bh1750;
+pub mod bme280;
+pub mod ds18b20;
+pub mod fault;
+pub mod registry;
+pub mod sht3x;
+pub mod tsl2561;
+
+/// The kinds of sensors that can be declared in the config.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SensorKind {
+    Ads1115,
+    Bmp280,
+    Bme280,
+    Sht31,
+    Bh1750,
+    Tsl2561,
+    Ds18b20,
+}
+
+/// Something that a sensor measures.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quantity {
+    // in degrees Celsius
+    Temperature,
+    // in kPa
+    Pressure,
+    // in percent relative humidity
+    Humidity,
+    // in lux
+    Illuminance,
+    // in volts
+    Voltage,
+    // the temperature compensated voltage of a moisture sensor, in volts
+    Moisture,
+    // in degrees Celsius
+    SoilTemperature,
+}
+
+#[derive(Clone, Debug)]
+pub struct Sample {
+    // when the sample was taken
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub values: Vec<(Quantity, f64)>,
+}
+
+impl Sample {
+    /// The value of a quantity, if the sample has it.
+    pub fn value(&self, quantity: Quantity) -> Option<f64> {
+        self.values
+            .iter()
+            .find(|&&(q, _)| q == quantity)
+            .map(|&(_, value)| value)
+    }
+}
+
+/// A sensor that can be sampled by the generic sampling job.
+pub trait Sensor: Send {
+    fn sample(&mut self) -> Box<futures::Future<Item = Sample, Error = failure::Error> + Send>;
+}
+
+impl Quantity {
+    /// The field name used when the sensor definition doesn't specify one.
+    pub fn default_field(self) -> &'static str {
+        match self {
+            Quantity::Temperature => "temperature",
+            Quantity::Pressure => "pressure",
+            Quantity::Humidity => "humidity",
+            Quantity::Illuminance => "lux",
+            Quantity::Voltage => "voltage",
+            Quantity::Moisture => "moisture",
+            Quantity::SoilTemperature => "soil_temperature",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ClimateReading {
+    // in degrees Celsius
+    pub temperature: f64,
+    // in kPa
+    pub pressure: Option<f64>,
+    // in percent relative humidity
+    pub humidity: Option<f64>,
+}
+
+pub trait ClimateSensor: Send {
+    /// Gets the device closer to having a measurement, and returns how long to wait before calling
+    /// this again, so that the wait doesn't block a thread.  Once this returns zero, the
+    /// measurement can be read.
+    fn prepare(&mut self) -> Result<time::Duration, failure::Error> {
+        Ok(time::Duration::new(0, 0))
+    }
+
+    fn read(&mut self) -> Result<ClimateReading, failure::Error>;
+}
+
+pub trait LightSensor: Send {
+    /// Like `ClimateSensor::prepare`.
+    fn prepare(&mut self) -> Result<time::Duration, failure::Error> {
+        Ok(time::Duration::new(0, 0))
+    }
+
+    // in lux
+    fn read_lux(&mut self) -> Result<f64, failure::Error>;
+}
+
+/// How long is left until the instant, if anything.
+pub fn remaining(until: time::Instant) -> time::Duration {
+    let now = time::Instant::now();
+    if until > now {
+        until - now
+    } else {
+        time::Duration::new(0, 0)
+    }
+}
+
+impl<D> ClimateSensor for i2cdev_bmp280::BMP280<D>
+where
+    D: i2cdev::core::I2CDevice + Send + Sized + 'static,
+    D::Error: Send + Sync + 'static,
+{
+    fn read(&mut self) -> Result<ClimateReading, failure::Error> {
+        let temperature = i2csensors::Thermometer::temperature_celsius(self)? as f64;
+        let pressure = i2csensors::Barometer::pressure_kpa(self)? as f64;
+
+        Ok(ClimateReading {
+            temperature,
+            pressure: Some(pressure),
+            humidity: None,
+        })
+    }
+}
+
+/// Converts the analog inputs of the ADS1115 converters on one I2C bus, one at a time.
+///
+/// Requests are queued to an actor that owns the converters.  The requests that have queued up by
+/// the time the actor gets to them are handled in one pass, ordered by device and channel, so that
+/// plants sampled at the same tick don't race each other for the bus.
+#[derive(Clone)]
+pub struct Ads1x15Sampler {
+    bus: String,
+    requests: futures::sync::mpsc::UnboundedSender<ConversionRequest>,
+}
+
+/// A single conversion of an analog input.
+#[derive(Clone, Copy, Debug)]
+pub struct Conversion {
+    pub voltage: f64,
+    // when the conversion finished
+    pub time: chrono::DateTime<chrono::Utc>,
+}
+
+struct ConversionRequest {
+    address: u16,
+    channel: ads1x15::Channel,
+    reply: futures::sync::oneshot::Sender<Result<Conversion, failure::Error>>,
+}
+
+impl Ads1x15Sampler {
+    /// Creates a sampler for the specified devices, and the actor future that has to be run for
+    /// requests to be handled.
+    ///
+    /// Devices that fail repeatedly are reopened with `open`, which also re-initializes them.
+    pub fn start<D, F>(
+        log: slog::Logger,
+        bus: String,
+        devices: collections::HashMap<u16, sync::Arc<ads1x15::Ads1x15<D>>>,
+        open: F,
+        health: sync::Arc<i2c::Health>,
+    ) -> (
+        Self,
+        impl futures::Future<Item = (), Error = failure::Error> + Send,
+    )
+    where
+        D: i2cdev::core::I2CDevice + Send + Sync + 'static,
+        <D as i2cdev::core::I2CDevice>::Error: Send + Sync + 'static,
+        F: Fn(u16) -> Result<ads1x15::Ads1x15<D>, failure::Error> + Send + 'static,
+    {
+        let (requests, receiver) = futures::sync::mpsc::unbounded();
+        let actor = run_bus(log, bus.clone(), devices, open, health, receiver);
+        (Ads1x15Sampler { bus, requests }, actor)
+    }
+
+    pub fn sample(
+        &self,
+        address: u16,
+        channel: ads1x15::Channel,
+    ) -> impl futures::Future<Item = Conversion, Error = failure::Error> {
+        use futures::Future;
+
+        let (reply, response) = futures::sync::oneshot::channel();
+        let bus = self.bus.clone();
+        let sent = self.requests.unbounded_send(ConversionRequest {
+            address,
+            channel,
+            reply,
+        });
+
+        futures::future::result(sent.map_err(|_| format_err!("The sampler of {} has stopped", bus)))
+            .and_then(move |()| {
+                response
+                    .map_err(move |_| format_err!("The sampler of {} dropped a request", bus))
+                    .and_then(|result| result)
+            })
+    }
+}
+
+#[async]
+fn run_bus<D, F>(
+    log: slog::Logger,
+    bus: String,
+    devices: collections::HashMap<u16, sync::Arc<ads1x15::Ads1x15<D>>>,
+    open: F,
+    health: sync::Arc<i2c::Health>,
+    requests: futures::sync::mpsc::UnboundedReceiver<ConversionRequest>,
+) -> Result<(), failure::Error>
+where
+    D: i2cdev::core::I2CDevice + Send + Sync + 'static,
+    <D as i2cdev::core::I2CDevice>::Error: Send + Sync + 'static,
+    F: Fn(u16) -> Result<ads1x15::Ads1x15<D>, failure::Error> + Send + 'static,
+{
+    use futures::Stream;
+
+    let mut devices = devices;
+    let mut requests = requests;
+
+    loop {
+        let (first, rest) = await!(requests.into_future())
+            .map_err(|_| format_err!("The request queue of {} failed", bus))?;
+        requests = rest;
+
+        let mut batch = match first {
+            Some(request) => vec![request],
+            None => break,
+        };
+        while let Ok(futures::Async::Ready(Some(request))) = requests.poll() {
+            batch.push(request);
+        }
+        batch.sort_by_key(|r| (r.address, channel_index(r.channel)));
+
+        // One pass over the requested channels of every device
+        let mut failed_device = None;
+        let mut previous: Option<(u16, u8, Conversion)> = None;
+        for request in batch {
+            let key = (request.address, channel_index(request.channel));
+
+            if failed_device == Some(request.address) {
+                let _ = request.reply.send(Err(format_err!(
+                    "ADS1115 at 0x{:02x} on {} failed earlier in this pass",
+                    request.address,
+                    bus
+                )));
+                continue;
+            }
+
+            // Several sensors may be reading the same channel
+            if let Some((address, channel, conversion)) = previous {
+                if (address, channel) == key {
+                    let _ = request.reply.send(Ok(conversion));
+                    continue;
+                }
+            }
+
+            let device = match devices.get(&request.address) {
+                Some(device) => device.clone(),
+                None => {
+                    let _ = request.reply.send(Err(format_err!(
+                        "No ADS1115 at 0x{:02x} on {}",
+                        request.address,
+                        bus
+                    )));
+                    continue;
+                }
+            };
+
+            match await!(device.clone().read_single_ended(request.channel)) {
+                Ok(voltage) => {
+                    let conversion = Conversion {
+                        voltage: f64::from(voltage),
+                        time: chrono::Utc::now(),
+                    };
+                    let failed = health.record_success(&bus, request.address);
+                    if failed > 0 {
+                        info!(
+                            log,
+                            "ADS1115 recovered after {} failures address=0x{:02x} bus={}",
+                            failed,
+                            request.address,
+                            bus
+                        );
+                    }
+                    previous = Some((key.0, key.1, conversion));
+                    let _ = request.reply.send(Ok(conversion));
+                }
+                Err(e) => {
+                    warn!(
+                        log,
+                        "ADS1115 conversion failed address=0x{:02x} bus={}: {}",
+                        request.address,
+                        bus,
+                        e
+                    );
+                    if health.record_failure(&bus, request.address, &e) {
+                        warn!(
+                            log,
+                            "reopening ADS1115 address=0x{:02x} bus={}", request.address, bus
+                        );
+                        match open(request.address) {
+                            Ok(device) => {
+                                devices.insert(request.address, sync::Arc::new(device));
+                            }
+                            Err(e) => warn!(
+                                log,
+                                "failed to reopen ADS1115 address=0x{:02x} bus={}: {}",
+                                request.address,
+                                bus,
+                                e
+                            ),
+                        }
+                    }
+                    failed_device = Some(request.address);
+                    let _ = request.reply.send(Err(format_err!(
+                        "ADS1115 at 0x{:02x} on {}: {}",
+                        request.address,
+                        bus,
+                        e
+                    )));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn channel_index(channel: ads1x15::Channel) -> u8 {
+    match channel {
+        ads1x15::Channel::A0 => 0,
+        ads1x15::Channel::A1 => 1,
+        ads1x15::Channel::A2 => 2,
+        ads1x15::Channel::A3 => 3,
+    }
+}
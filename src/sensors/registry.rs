@@ -0,0 +1,634 @@
+//! Opens the sensors declared in the config, and the moisture sensors of the plants.
+
+use std::collections;
+use std::sync;
+use std::time;
+
+use ads1x15;
+use chrono;
+use failure;
+use futures;
+use i2cdev;
+use i2cdev_bmp280;
+use slog;
+use tokio;
+use uuid;
+
+use clock;
+use config;
+use i2c;
+use model;
+use replay;
+
+use futures::prelude::async;
+use futures::prelude::await;
+use futures::Future;
+
+use super::bh1750;
+use super::bme280;
+use super::ds18b20;
+use super::fault;
+use super::sht3x;
+use super::tsl2561;
+use super::Ads1x15Sampler;
+use super::ClimateSensor;
+use super::LightSensor;
+use super::Quantity;
+use super::Sample;
+use super::Sensor;
+use super::SensorKind;
+
+pub const DEFAULT_BUS: &str = "/dev/i2c-1";
+
+const BMP280_DEFAULT_ADDRESS: u16 = 0x77;
+const SOIL_TEMPERATURE_INTERVAL_SECONDS: u64 = 60;
+// How long the last soil temperature is used for when the probe can't be read, rather than
+// stepping between compensated and uncompensated readings
+const SOIL_TEMPERATURE_MAX_AGE_SECONDS: u64 = 1800;
+
+type Device = i2cdev::linux::LinuxI2CDevice;
+
+/// A sensor together with how and where to store its samples.
+pub struct Registered {
+    pub name: String,
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: collections::HashMap<Quantity, String>,
+    pub interval: time::Duration,
+    // how often to sample while the plant is being watered
+    pub burst_interval: Option<time::Duration>,
+    // the plant that the sensor belongs to, if any
+    pub plant: Option<uuid::Uuid>,
+    // checks the plausibility of the readings of the moisture probe of a plant
+    pub detector: Option<fault::Detector>,
+    pub sensor: Box<Sensor>,
+}
+
+impl Registered {
+    pub fn field(&self, quantity: Quantity) -> &str {
+        self.fields
+            .get(&quantity)
+            .map(|f| f.as_str())
+            .unwrap_or_else(|| quantity.default_field())
+    }
+}
+
+/// The opened sensors, and the futures that drive the I2C buses they share.
+pub struct Registry {
+    pub sensors: Vec<Registered>,
+    pub buses: Vec<Box<futures::Future<Item = (), Error = failure::Error> + Send>>,
+}
+
+/// Opens all declared sensors, including the moisture probes of the plants.
+pub fn open(
+    log: slog::Logger,
+    sampling: &config::Sampling,
+    definitions: &collections::HashMap<String, config::Sensor>,
+    modules: &[sync::Arc<model::ModuleConfig>],
+    health: &sync::Arc<i2c::Health>,
+) -> Result<Registry, failure::Error> {
+    check_plants(definitions, modules)?;
+    let (samplers, buses) = open_samplers(&log, definitions, health)?;
+    let mut result = Vec::new();
+
+    for (name, definition) in definitions {
+        let mut sensor = open_sensor(&log, definition, &samplers, health)
+            .map_err(|e| format_err!("failed to open sensor {:?}: {}", name, e))?;
+        if definition.moisture {
+            let module = plant_of(definition, modules)?;
+            let probe = match module.soil_temperature_probe {
+                Some(ref id) => Some(ds18b20::Ds18b20::new(id)?),
+                None => None,
+            };
+            sensor = Box::new(PlantMoisture {
+                log: log.new(o!("plant" => module.name.clone())),
+                module: module.clone(),
+                sensor,
+                probe,
+                soil_temperature: sync::Arc::new(sync::Mutex::new(None)),
+                last_soil_reading: None,
+            });
+        }
+        result.push(declared(sampling, name, definition, modules, sensor)?);
+    }
+
+    Ok(Registry {
+        sensors: result,
+        buses,
+    })
+}
+
+/// Replays the recorded samples of the declared sensors instead of opening them; sensors that
+/// weren't recorded aren't sampled.
+pub fn replay(
+    log: &slog::Logger,
+    sampling: &config::Sampling,
+    definitions: &collections::HashMap<String, config::Sensor>,
+    modules: &[sync::Arc<model::ModuleConfig>],
+    recording: &replay::Recording,
+    clock: clock::Clock,
+) -> Result<Registry, failure::Error> {
+    check_plants(definitions, modules)?;
+    let mut result = Vec::new();
+
+    // The recorded moisture samples are already compensated, and the sensors are sampled in order
+    // of name, so that every replay samples them in the same order
+    let mut definitions = definitions.iter().collect::<Vec<_>>();
+    definitions.sort_by_key(|&(name, _)| name);
+    for (name, definition) in definitions {
+        if let Some(sensor) = recording.sensor(clock.clone(), &definition.measurement, name) {
+            result.push(declared(sampling, name, definition, modules, sensor)?);
+        } else {
+            warn!(log, "sensor was not recorded name={:?}", name);
+        }
+    }
+
+    Ok(Registry {
+        sensors: result,
+        buses: Vec::new(),
+    })
+}
+
+fn check_plants(
+    definitions: &collections::HashMap<String, config::Sensor>,
+    modules: &[sync::Arc<model::ModuleConfig>],
+) -> Result<(), failure::Error> {
+    for (name, definition) in definitions {
+        if let Some(plant) = definition.plant {
+            if !modules.iter().any(|m| m.uuid == plant) {
+                bail!("Sensor {:?} refers to an unknown plant: {}", name, plant);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn plant_of<'a>(
+    definition: &config::Sensor,
+    modules: &'a [sync::Arc<model::ModuleConfig>],
+) -> Result<&'a sync::Arc<model::ModuleConfig>, failure::Error> {
+    definition
+        .plant
+        .and_then(|plant| modules.iter().find(|m| m.uuid == plant))
+        .ok_or_else(|| format_err!("A moisture probe has to belong to a plant"))
+}
+
+fn declared(
+    sampling: &config::Sampling,
+    name: &str,
+    definition: &config::Sensor,
+    modules: &[sync::Arc<model::ModuleConfig>],
+    sensor: Box<Sensor>,
+) -> Result<Registered, failure::Error> {
+    let mut tags = definition
+        .tags
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect::<Vec<_>>();
+    if let Some(plant) = definition.plant {
+        tags.push(("uuid".to_owned(), plant.to_hyphenated().to_string()));
+    }
+
+    let (burst_interval, detector) = if definition.moisture {
+        let module = plant_of(definition, modules)?;
+        (
+            Some(
+                definition
+                    .burst_interval_seconds
+                    .unwrap_or(sampling.burst_interval_seconds),
+            ),
+            Some(fault::Detector::new(module.moisture_faults)),
+        )
+    } else {
+        (definition.burst_interval_seconds, None)
+    };
+
+    Ok(Registered {
+        name: name.to_owned(),
+        measurement: definition.measurement.clone(),
+        tags,
+        fields: definition.fields.clone(),
+        interval: time::Duration::from_secs(
+            definition
+                .interval_seconds
+                .unwrap_or(sampling.interval_seconds),
+        ),
+        burst_interval: burst_interval.map(time::Duration::from_secs),
+        plant: definition.plant,
+        detector,
+        sensor,
+    })
+}
+
+/// Parses the channel of an ADS1115 analog input.
+fn analog_channel(pin: u8) -> Result<ads1x15::Channel, failure::Error> {
+    Ok(match pin {
+        0 => ads1x15::Channel::A0,
+        1 => ads1x15::Channel::A1,
+        2 => ads1x15::Channel::A2,
+        3 => ads1x15::Channel::A3,
+        x => bail!("No such analog channel: {}", x),
+    })
+}
+
+type Samplers = collections::HashMap<String, Ads1x15Sampler>;
+type Buses = Vec<Box<futures::Future<Item = (), Error = failure::Error> + Send>>;
+
+// ADS1115 converters are shared by several sensors, so each of them is only opened once
+fn open_samplers(
+    log: &slog::Logger,
+    definitions: &collections::HashMap<String, config::Sensor>,
+    health: &sync::Arc<i2c::Health>,
+) -> Result<(Samplers, Buses), failure::Error> {
+    let mut addresses = collections::HashMap::<String, collections::HashSet<u16>>::new();
+
+    for definition in definitions.values() {
+        if definition.kind == SensorKind::Ads1115 {
+            let address = definition
+                .i2c_address
+                .ok_or_else(|| format_err!("An ADS1115 sensor needs an i2c_address"))?;
+            addresses
+                .entry(definition.bus.clone())
+                .or_default()
+                .insert(address);
+        }
+    }
+    let mut samplers = collections::HashMap::new();
+    let mut buses = Vec::new();
+    for (bus, addresses) in addresses {
+        let devices = addresses
+            .into_iter()
+            .map(|address| {
+                let i2c_dev = Device::new(&bus, address)?;
+                Ok((
+                    address,
+                    sync::Arc::new(ads1x15::Ads1x15::new_ads1115(i2c_dev)),
+                ))
+            })
+            .collect::<Result<collections::HashMap<_, _>, failure::Error>>()?;
+
+        let open_bus = bus.clone();
+        let (sampler, actor) = Ads1x15Sampler::start(
+            log.new(o!("bus" => bus.clone())),
+            bus.clone(),
+            devices,
+            move |address| {
+                Ok(ads1x15::Ads1x15::new_ads1115(Device::new(
+                    &open_bus, address,
+                )?))
+            },
+            health.clone(),
+        );
+        samplers.insert(bus, sampler);
+        buses.push(Box::new(actor) as Box<futures::Future<Item = _, Error = _> + Send>);
+    }
+
+    Ok((samplers, buses))
+}
+
+fn open_sensor(
+    log: &slog::Logger,
+    definition: &config::Sensor,
+    samplers: &Samplers,
+    health: &sync::Arc<i2c::Health>,
+) -> Result<Box<Sensor>, failure::Error> {
+    let address = definition
+        .i2c_address
+        .or_else(|| default_address(definition.kind));
+    Ok(match definition.kind {
+        SensorKind::Ads1115 => Box::new(AnalogChannel {
+            sampler: samplers[&definition.bus].clone(),
+            // checked when opening the samplers
+            address: address.unwrap_or(0),
+            channel: analog_channel(channel(definition)?.parse()?)?,
+        }),
+        SensorKind::Bmp280 | SensorKind::Bme280 | SensorKind::Sht31 => {
+            Box::new(Climate(sync::Arc::new(sync::Mutex::new(Recovering::open(
+                log,
+                definition,
+                health,
+                open_climate_sensor,
+            )?))))
+        }
+        SensorKind::Bh1750 | SensorKind::Tsl2561 => {
+            Box::new(Light(sync::Arc::new(sync::Mutex::new(Recovering::open(
+                log,
+                definition,
+                health,
+                open_light_sensor,
+            )?))))
+        }
+        SensorKind::Ds18b20 => Box::new(ds18b20::Ds18b20::new(channel(definition)?)?),
+    })
+}
+
+/// The address that a kind of I2C sensor usually has.
+pub fn default_address(kind: SensorKind) -> Option<u16> {
+    match kind {
+        SensorKind::Ads1115 | SensorKind::Ds18b20 => None,
+        SensorKind::Bmp280 => Some(BMP280_DEFAULT_ADDRESS),
+        SensorKind::Bme280 => Some(bme280::DEFAULT_ADDRESS),
+        SensorKind::Sht31 => Some(sht3x::DEFAULT_ADDRESS),
+        SensorKind::Bh1750 => Some(bh1750::DEFAULT_ADDRESS),
+        SensorKind::Tsl2561 => Some(tsl2561::DEFAULT_ADDRESS),
+    }
+}
+
+fn open_climate_sensor(
+    kind: SensorKind,
+    bus: &str,
+    address: u16,
+) -> Result<Box<ClimateSensor>, failure::Error> {
+    let device = Device::new(bus, address)?;
+    Ok(match kind {
+        SensorKind::Bmp280 => Box::new(i2cdev_bmp280::BMP280::new(
+            device,
+            i2cdev_bmp280::BMP280Settings {
+                compensation: i2cdev_bmp280::BMP280CompensationAlgorithm::Float,
+                t_sb: i2cdev_bmp280::BMP280Timing::ms0_5,
+                iir_filter_coeff: i2cdev_bmp280::BMP280FilterCoefficient::UltraHigh,
+                osrs_t: i2cdev_bmp280::BMP280TemperatureOversampling::x16,
+                osrs_p: i2cdev_bmp280::BMP280PressureOversampling::UltraHighResolution,
+                power_mode: i2cdev_bmp280::BMP280PowerMode::NormalMode,
+            },
+        )?),
+        SensorKind::Bme280 => Box::new(bme280::Bme280::new(device)?),
+        SensorKind::Sht31 => Box::new(sht3x::Sht3x::new(device)?),
+        kind => bail!("A {:?} is not a climate sensor", kind),
+    })
+}
+
+fn open_light_sensor(
+    kind: SensorKind,
+    bus: &str,
+    address: u16,
+) -> Result<Box<LightSensor>, failure::Error> {
+    let device = Device::new(bus, address)?;
+    Ok(match kind {
+        SensorKind::Bh1750 => Box::new(bh1750::Bh1750::new(device)?),
+        SensorKind::Tsl2561 => Box::new(tsl2561::Tsl2561::new(device)?),
+        kind => bail!("A {:?} is not a light sensor", kind),
+    })
+}
+
+fn channel(definition: &config::Sensor) -> Result<&str, failure::Error> {
+    definition
+        .channel
+        .as_ref()
+        .map(|c| c.as_str())
+        .ok_or_else(|| format_err!("A {:?} sensor needs a channel", definition.kind))
+}
+
+struct AnalogChannel {
+    sampler: Ads1x15Sampler,
+    address: u16,
+    channel: ads1x15::Channel,
+}
+
+impl Sensor for AnalogChannel {
+    fn sample(&mut self) -> Box<futures::Future<Item = Sample, Error = failure::Error> + Send> {
+        Box::new(
+            self.sampler
+                .sample(self.address, self.channel)
+                .map(|conversion| Sample {
+                    time: conversion.time,
+                    values: vec![(Quantity::Voltage, conversion.voltage)],
+                }),
+        )
+    }
+}
+
+/// An I2C device that is reopened, and so re-initialized, after failing repeatedly.
+struct Recovering<S> {
+    log: slog::Logger,
+    kind: SensorKind,
+    bus: String,
+    address: u16,
+    health: sync::Arc<i2c::Health>,
+    open: fn(SensorKind, &str, u16) -> Result<S, failure::Error>,
+    // closed after repeated failures, until the next attempt to use it
+    device: Option<S>,
+}
+
+impl<S> Recovering<S> {
+    fn open(
+        log: &slog::Logger,
+        definition: &config::Sensor,
+        health: &sync::Arc<i2c::Health>,
+        open: fn(SensorKind, &str, u16) -> Result<S, failure::Error>,
+    ) -> Result<Self, failure::Error> {
+        let address = definition
+            .i2c_address
+            .or_else(|| default_address(definition.kind))
+            .ok_or_else(|| format_err!("A {:?} sensor needs an i2c_address", definition.kind))?;
+        let device = Some(open(definition.kind, &definition.bus, address)?);
+        Ok(Recovering {
+            log: log.new(o!("bus" => definition.bus.clone())),
+            kind: definition.kind,
+            bus: definition.bus.clone(),
+            address,
+            health: health.clone(),
+            open,
+            device,
+        })
+    }
+
+    fn with<F, A>(&mut self, f: F) -> Result<A, failure::Error>
+    where
+        F: FnOnce(&mut S) -> Result<A, failure::Error>,
+    {
+        if self.device.is_none() {
+            match (self.open)(self.kind, &self.bus, self.address) {
+                Ok(device) => self.device = Some(device),
+                Err(e) => {
+                    self.health.record_failure(&self.bus, self.address, &e);
+                    return Err(e);
+                }
+            }
+        }
+
+        let result = match self.device {
+            Some(ref mut device) => f(device),
+            None => unreachable!(),
+        };
+
+        match result {
+            Ok(_) => {
+                let failed = self.health.record_success(&self.bus, self.address);
+                if failed > 0 {
+                    info!(
+                        self.log,
+                        "{:?} recovered after {} failures address=0x{:02x}",
+                        self.kind,
+                        failed,
+                        self.address
+                    );
+                }
+            }
+            Err(ref e) => {
+                if self.health.record_failure(&self.bus, self.address, e) {
+                    warn!(
+                        self.log,
+                        "reopening {:?} address=0x{:02x}", self.kind, self.address
+                    );
+                    self.device = None;
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Prepares a device until it has a measurement, waiting on a timer in between, and then reads
+/// the measurement.
+#[async]
+fn measure<S, A>(
+    device: sync::Arc<sync::Mutex<Recovering<S>>>,
+    prepare: fn(&mut S) -> Result<time::Duration, failure::Error>,
+    read: fn(&mut S) -> Result<A, failure::Error>,
+) -> Result<A, failure::Error>
+where
+    S: Send + 'static,
+    A: Send + 'static,
+{
+    loop {
+        let wait = device.lock().unwrap().with(prepare)?;
+        if wait == time::Duration::new(0, 0) {
+            break;
+        }
+        await!(tokio::timer::Delay::new(time::Instant::now() + wait))?;
+    }
+    let result = device.lock().unwrap().with(read);
+    result
+}
+
+struct Climate(sync::Arc<sync::Mutex<Recovering<Box<ClimateSensor>>>>);
+
+impl Sensor for Climate {
+    fn sample(&mut self) -> Box<futures::Future<Item = Sample, Error = failure::Error> + Send> {
+        Box::new(
+            measure(self.0.clone(), |s| s.prepare(), |s| s.read()).map(|reading| {
+                let mut values = vec![(Quantity::Temperature, reading.temperature)];
+                values.extend(reading.pressure.map(|p| (Quantity::Pressure, p)));
+                values.extend(reading.humidity.map(|h| (Quantity::Humidity, h)));
+                Sample {
+                    time: chrono::Utc::now(),
+                    values,
+                }
+            }),
+        )
+    }
+}
+
+struct Light(sync::Arc<sync::Mutex<Recovering<Box<LightSensor>>>>);
+
+impl Sensor for Light {
+    fn sample(&mut self) -> Box<futures::Future<Item = Sample, Error = failure::Error> + Send> {
+        Box::new(
+            measure(self.0.clone(), |s| s.prepare(), |s| s.read_lux()).map(|lux| Sample {
+                time: chrono::Utc::now(),
+                values: vec![(Quantity::Illuminance, lux)],
+            }),
+        )
+    }
+}
+
+impl Sensor for ds18b20::Ds18b20 {
+    fn sample(&mut self) -> Box<futures::Future<Item = Sample, Error = failure::Error> + Send> {
+        Box::new(self.read().map(|t| Sample {
+            time: chrono::Utc::now(),
+            values: vec![(Quantity::Temperature, t)],
+        }))
+    }
+}
+
+/// The moisture probe of a plant, compensated for the soil temperature if the plant has a
+/// temperature probe.
+struct PlantMoisture {
+    log: slog::Logger,
+    module: sync::Arc<model::ModuleConfig>,
+    // measures the voltage of the probe
+    sensor: Box<Sensor>,
+    probe: Option<ds18b20::Ds18b20>,
+    // the latest soil temperature, and when it was measured
+    soil_temperature: sync::Arc<sync::Mutex<Option<(f64, time::Instant)>>>,
+    // when the probe was last read, whether that succeeded or not
+    last_soil_reading: Option<time::Instant>,
+}
+
+impl Sensor for PlantMoisture {
+    fn sample(&mut self) -> Box<futures::Future<Item = Sample, Error = failure::Error> + Send> {
+        // Soil temperature changes slowly, and each conversion takes most of a second
+        let measure_soil: Box<futures::Future<Item = (), Error = failure::Error> + Send> =
+            match self.probe {
+                Some(ref probe)
+                    if self.last_soil_reading.map_or(true, |t| {
+                        t.elapsed().as_secs() >= SOIL_TEMPERATURE_INTERVAL_SECONDS
+                    }) =>
+                {
+                    self.last_soil_reading = Some(time::Instant::now());
+                    let log = self.log.clone();
+                    let soil_temperature = self.soil_temperature.clone();
+                    Box::new(
+                        probe
+                            .read()
+                            .then(move |result| -> Result<(), failure::Error> {
+                                match result {
+                                    Ok(t) => {
+                                        *soil_temperature.lock().unwrap() =
+                                            Some((t, time::Instant::now()))
+                                    }
+                                    Err(e) => {
+                                        warn!(log, "failed to measure soil temperature: {}", e)
+                                    }
+                                }
+                                Ok(())
+                            }),
+                    )
+                }
+                _ => Box::new(futures::future::ok(())),
+            };
+
+        let module = self.module.clone();
+        let soil_temperature = self.soil_temperature.clone();
+
+        Box::new(
+            measure_soil
+                .join(self.sensor.sample())
+                .and_then(move |((), sample)| {
+                    // A failed reading keeps the last temperature for a while
+                    let soil_temperature =
+                        soil_temperature.lock().unwrap().and_then(|(t, measured)| {
+                            if measured.elapsed().as_secs() <= SOIL_TEMPERATURE_MAX_AGE_SECONDS {
+                                Some(t)
+                            } else {
+                                None
+                            }
+                        });
+                    let voltage = match sample.value(Quantity::Voltage) {
+                        Some(voltage) => voltage,
+                        None => bail!(
+                            "The moisture probe of {:?} measured no voltage",
+                            module.name
+                        ),
+                    };
+                    // The raw voltage is kept too, so that the compensation can be redone later
+                    let values = match soil_temperature {
+                        Some(t) => vec![
+                            (
+                                Quantity::Moisture,
+                                module.compensate_moisture_voltage(voltage, t),
+                            ),
+                            (Quantity::Voltage, voltage),
+                            (Quantity::SoilTemperature, t),
+                        ],
+                        None => vec![(Quantity::Moisture, voltage), (Quantity::Voltage, voltage)],
+                    };
+                    Ok(Sample {
+                        time: sample.time,
+                        values,
+                    })
+                }),
+        )
+    }
+}
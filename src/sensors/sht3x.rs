@@ -0,0 +1,116 @@
+//! A driver for the Sensirion SHT3x (SHT30, SHT31, SHT35) temperature and humidity sensors.
+
+use std::time;
+
+use failure;
+use i2cdev;
+
+use super::remaining;
+use super::ClimateReading;
+use super::ClimateSensor;
+
+pub const DEFAULT_ADDRESS: u16 = 0x44;
+
+// Single shot measurement with high repeatability and without clock stretching
+const CMD_MEASURE: [u8; 2] = [0x24, 0x00];
+const CMD_SOFT_RESET: [u8; 2] = [0x30, 0xa2];
+const MEASUREMENT_MILLIS: u64 = 16;
+const SOFT_RESET_MILLIS: u64 = 2;
+
+pub struct Sht3x<D> {
+    device: D,
+    state: State,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Resetting { until: time::Instant },
+    Idle,
+    Measuring { until: time::Instant },
+}
+
+impl<D> Sht3x<D>
+where
+    D: i2cdev::core::I2CDevice,
+    D::Error: Send + Sync + 'static,
+{
+    pub fn new(mut device: D) -> Result<Self, failure::Error> {
+        device.write(&CMD_SOFT_RESET)?;
+        Ok(Sht3x {
+            device,
+            state: State::Resetting {
+                until: time::Instant::now() + time::Duration::from_millis(SOFT_RESET_MILLIS),
+            },
+        })
+    }
+}
+
+impl<D> ClimateSensor for Sht3x<D>
+where
+    D: i2cdev::core::I2CDevice + Send,
+    D::Error: Send + Sync + 'static,
+{
+    fn prepare(&mut self) -> Result<time::Duration, failure::Error> {
+        let zero = time::Duration::new(0, 0);
+        match self.state {
+            State::Resetting { until } if remaining(until) > zero => Ok(remaining(until)),
+            State::Measuring { until } => Ok(remaining(until)),
+            State::Resetting { .. } | State::Idle => {
+                self.device.write(&CMD_MEASURE)?;
+                let duration = time::Duration::from_millis(MEASUREMENT_MILLIS);
+                self.state = State::Measuring {
+                    until: time::Instant::now() + duration,
+                };
+                Ok(duration)
+            }
+        }
+    }
+
+    fn read(&mut self) -> Result<ClimateReading, failure::Error> {
+        match self.state {
+            State::Measuring { until } if remaining(until) == time::Duration::new(0, 0) => {}
+            _ => bail!("No SHT3x measurement is ready to be read"),
+        }
+        self.state = State::Idle;
+
+        let mut data = [0u8; 6];
+        self.device.read(&mut data)?;
+
+        let raw_temperature = checked_word(&data[0..3])?;
+        let raw_humidity = checked_word(&data[3..6])?;
+
+        Ok(ClimateReading {
+            temperature: -45.0 + 175.0 * f64::from(raw_temperature) / 65535.0,
+            pressure: None,
+            humidity: Some(100.0 * f64::from(raw_humidity) / 65535.0),
+        })
+    }
+}
+
+/// Decodes a big-endian word followed by its CRC-8 checksum.
+fn checked_word(data: &[u8]) -> Result<u16, failure::Error> {
+    let expected = crc8(&data[0..2]);
+    if data[2] != expected {
+        bail!(
+            "SHT3x checksum mismatch (got 0x{:02x}, expected 0x{:02x})",
+            data[2],
+            expected
+        );
+    }
+    Ok(u16::from(data[0]) << 8 | u16::from(data[1]))
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0xffu8;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
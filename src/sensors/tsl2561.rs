@@ -0,0 +1,169 @@
+//! A driver for the TAOS/ams TSL2561 ambient light sensor.
+
+use failure;
+use i2cdev;
+
+use super::LightSensor;
+
+pub const DEFAULT_ADDRESS: u16 = 0x39;
+
+const CMD: u8 = 0x80;
+const CMD_WORD: u8 = 0x20;
+
+const REG_CONTROL: u8 = 0x00;
+const REG_TIMING: u8 = 0x01;
+const REG_ID: u8 = 0x0a;
+const REG_DATA0: u8 = 0x0c;
+const REG_DATA1: u8 = 0x0e;
+
+const POWER_ON: u8 = 0x03;
+// 1x gain and 402 ms integration time, so that direct sunlight doesn't saturate the sensor
+const TIMING: u8 = 0x02;
+// The lux formula is calibrated for 16x gain
+const GAIN_SCALE: f64 = 16.0;
+const MAX_COUNT: u16 = 0xffff;
+
+pub struct Tsl2561<D> {
+    device: D,
+    package: Package,
+}
+
+/// The lux formula differs between the packages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Package {
+    // chipscale
+    Cs,
+    // TMB, dual flat no-lead and chiplead
+    TFnCl,
+}
+
+impl<D> Tsl2561<D>
+where
+    D: i2cdev::core::I2CDevice,
+    D::Error: Send + Sync + 'static,
+{
+    pub fn new(mut device: D) -> Result<Self, failure::Error> {
+        device.smbus_write_byte_data(CMD | REG_CONTROL, POWER_ON)?;
+        let control = device.smbus_read_byte_data(CMD | REG_CONTROL)?;
+        if control & POWER_ON != POWER_ON {
+            bail!("Not a TSL2561 (control register 0x{:02x})", control);
+        }
+
+        let id = device.smbus_read_byte_data(CMD | REG_ID)?;
+        let package = match id >> 4 {
+            0x1 => Package::Cs,
+            0x5 => Package::TFnCl,
+            part => bail!("Not a TSL2561 (part number 0x{:x})", part),
+        };
+
+        device.smbus_write_byte_data(CMD | REG_TIMING, TIMING)?;
+
+        Ok(Tsl2561 { device, package })
+    }
+}
+
+impl<D> LightSensor for Tsl2561<D>
+where
+    D: i2cdev::core::I2CDevice + Send,
+    D::Error: Send + Sync + 'static,
+{
+    fn read_lux(&mut self) -> Result<f64, failure::Error> {
+        // Broadband (visible and infrared) and infrared-only channels
+        let ch0 = self
+            .device
+            .smbus_read_word_data(CMD | CMD_WORD | REG_DATA0)?;
+        let ch1 = self
+            .device
+            .smbus_read_word_data(CMD | CMD_WORD | REG_DATA1)?;
+
+        if ch0 == MAX_COUNT || ch1 == MAX_COUNT {
+            bail!("TSL2561 is saturated (ch0={} ch1={})", ch0, ch1);
+        }
+
+        Ok(lux(
+            self.package,
+            f64::from(ch0) * GAIN_SCALE,
+            f64::from(ch1) * GAIN_SCALE,
+        ))
+    }
+}
+
+/// The empirical lux formula of the package from the datasheet.
+fn lux(package: Package, ch0: f64, ch1: f64) -> f64 {
+    if ch0 == 0.0 {
+        return 0.0;
+    }
+
+    let ratio = ch1 / ch0;
+    let lux = match package {
+        Package::Cs => lux_cs(ch0, ch1, ratio),
+        Package::TFnCl => lux_t_fn_cl(ch0, ch1, ratio),
+    };
+
+    lux.max(0.0)
+}
+
+fn lux_cs(ch0: f64, ch1: f64, ratio: f64) -> f64 {
+    if ratio <= 0.52 {
+        0.0315 * ch0 - 0.0593 * ch0 * ratio.powf(1.4)
+    } else if ratio <= 0.65 {
+        0.0229 * ch0 - 0.0291 * ch1
+    } else if ratio <= 0.80 {
+        0.0157 * ch0 - 0.0180 * ch1
+    } else if ratio <= 1.30 {
+        0.00338 * ch0 - 0.00260 * ch1
+    } else {
+        0.0
+    }
+}
+
+fn lux_t_fn_cl(ch0: f64, ch1: f64, ratio: f64) -> f64 {
+    if ratio <= 0.50 {
+        0.0304 * ch0 - 0.062 * ch0 * ratio.powf(1.4)
+    } else if ratio <= 0.61 {
+        0.0224 * ch0 - 0.031 * ch1
+    } else if ratio <= 0.80 {
+        0.0128 * ch0 - 0.0153 * ch1
+    } else if ratio <= 1.30 {
+        0.00146 * ch0 - 0.00112 * ch1
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn the_formula_depends_on_the_package() {
+        // A ratio of 0.51 is past the first breakpoint of T, FN and CL packages, but not of CS
+        assert_close(
+            lux(Package::TFnCl, 1000.0, 510.0),
+            0.0224 * 1000.0 - 0.031 * 510.0,
+        );
+        assert_close(
+            lux(Package::Cs, 1000.0, 510.0),
+            0.0315 * 1000.0 - 0.0593 * 1000.0 * 0.51f64.powf(1.4),
+        );
+        assert_close(
+            lux(Package::Cs, 1000.0, 1000.0),
+            0.00338 * 1000.0 - 0.00260 * 1000.0,
+        );
+    }
+
+    #[test]
+    fn darkness_and_infrared_only_are_no_lux() {
+        assert_eq!(lux(Package::TFnCl, 0.0, 0.0), 0.0);
+        assert_eq!(lux(Package::Cs, 100.0, 200.0), 0.0);
+    }
+}
@@ -0,0 +1,163 @@
+//! Offline computation of sunrise and sunset times, using the sunrise equation.
+
+use std::f64::consts;
+
+use chrono;
+
+/// The apparent solar elevation at sunrise and sunset, accounting for refraction and the size of
+/// the solar disc.
+const HORIZON_DEGREES: f64 = -0.833;
+/// The Julian date of 2000-01-01 12:00 UTC.
+const J2000: f64 = 2_451_545.0;
+/// The Julian date of the Unix epoch.
+const UNIX_EPOCH_JULIAN_DATE: f64 = 2_440_587.5;
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    Sunrise,
+    Sunset,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Location {
+    // in degrees, north is positive
+    pub latitude: f64,
+    // in degrees, east is positive
+    pub longitude: f64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Day {
+    Normal {
+        sunrise: chrono::DateTime<chrono::Utc>,
+        sunset: chrono::DateTime<chrono::Utc>,
+    },
+    PolarDay,
+    PolarNight,
+}
+
+impl Day {
+    pub fn event(&self, event: Event) -> Option<chrono::DateTime<chrono::Utc>> {
+        match (*self, event) {
+            (Day::Normal { sunrise, .. }, Event::Sunrise) => Some(sunrise),
+            (Day::Normal { sunset, .. }, Event::Sunset) => Some(sunset),
+            _ => None,
+        }
+    }
+
+    pub fn is_daylight(&self, time: chrono::DateTime<chrono::Utc>) -> bool {
+        match *self {
+            Day::Normal { sunrise, sunset } => sunrise <= time && time <= sunset,
+            Day::PolarDay => true,
+            Day::PolarNight => false,
+        }
+    }
+}
+
+/// Computes the sunrise and sunset around the solar noon of the specified date at a location.
+pub fn day(date: chrono::NaiveDate, location: Location) -> Day {
+    let days_since_j2000 = date
+        .signed_duration_since(chrono::NaiveDate::from_ymd(2000, 1, 1))
+        .num_days() as f64;
+    let mean_solar_noon = days_since_j2000 - location.longitude / 360.0;
+
+    let mean_anomaly = (357.5291 + 0.985_600_28 * mean_solar_noon) % 360.0;
+    let m = mean_anomaly.to_radians();
+    let center = 1.9148 * m.sin() + 0.02 * (2.0 * m).sin() + 0.0003 * (3.0 * m).sin();
+    let ecliptic_longitude = ((mean_anomaly + center + 180.0 + 102.9372) % 360.0).to_radians();
+
+    let transit =
+        J2000 + mean_solar_noon + 0.0053 * m.sin() - 0.0069 * (2.0 * ecliptic_longitude).sin();
+
+    let declination = (ecliptic_longitude.sin() * 23.44_f64.to_radians().sin()).asin();
+    let latitude = location.latitude.to_radians();
+    let cos_hour_angle = (HORIZON_DEGREES.to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+
+    if cos_hour_angle < -1.0 {
+        Day::PolarDay
+    } else if cos_hour_angle > 1.0 {
+        Day::PolarNight
+    } else {
+        let hour_angle = cos_hour_angle.acos() / (2.0 * consts::PI);
+        Day::Normal {
+            sunrise: from_julian_date(transit - hour_angle),
+            sunset: from_julian_date(transit + hour_angle),
+        }
+    }
+}
+
+fn from_julian_date(julian_date: f64) -> chrono::DateTime<chrono::Utc> {
+    use chrono::TimeZone;
+
+    let millis = ((julian_date - UNIX_EPOCH_JULIAN_DATE) * 86_400_000.0).round() as i64;
+    chrono::Utc.timestamp_millis(millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono;
+
+    use super::*;
+
+    const STOCKHOLM: Location = Location {
+        latitude: 59.33,
+        longitude: 18.07,
+    };
+    const TROMSO: Location = Location {
+        latitude: 69.65,
+        longitude: 18.96,
+    };
+
+    fn assert_near(actual: Option<chrono::DateTime<chrono::Utc>>, expected: &str) {
+        let expected = expected.parse::<chrono::DateTime<chrono::Utc>>().unwrap();
+        let actual = actual.expect("no sun event");
+        assert!(
+            (actual - expected).num_minutes().abs() <= 2,
+            "{} is not near {}",
+            actual,
+            expected
+        );
+    }
+
+    // Published times, rounded to the minute
+    #[test]
+    fn midsummer_in_stockholm() {
+        let day = day(chrono::NaiveDate::from_ymd(2018, 6, 21), STOCKHOLM);
+        assert_near(day.event(Event::Sunrise), "2018-06-21T01:31:00Z");
+        assert_near(day.event(Event::Sunset), "2018-06-21T20:08:00Z");
+    }
+
+    #[test]
+    fn midwinter_in_stockholm() {
+        let day = day(chrono::NaiveDate::from_ymd(2018, 12, 21), STOCKHOLM);
+        assert_near(day.event(Event::Sunrise), "2018-12-21T07:44:00Z");
+        assert_near(day.event(Event::Sunset), "2018-12-21T13:48:00Z");
+    }
+
+    #[test]
+    fn equinox_at_null_island() {
+        let day = day(
+            chrono::NaiveDate::from_ymd(2018, 3, 20),
+            Location {
+                latitude: 0.0,
+                longitude: 0.0,
+            },
+        );
+        assert_near(day.event(Event::Sunrise), "2018-03-20T06:04:00Z");
+        assert_near(day.event(Event::Sunset), "2018-03-20T18:11:00Z");
+    }
+
+    #[test]
+    fn polar_day_and_night_in_tromso() {
+        let summer = day(chrono::NaiveDate::from_ymd(2018, 6, 21), TROMSO);
+        let winter = day(chrono::NaiveDate::from_ymd(2018, 12, 21), TROMSO);
+        let noon = "2018-06-21T12:00:00Z".parse().unwrap();
+
+        assert_eq!(summer.event(Event::Sunrise), None);
+        assert!(summer.is_daylight(noon));
+        assert_eq!(winter.event(Event::Sunset), None);
+        assert!(!winter.is_daylight(noon));
+    }
+}
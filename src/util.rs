@@ -1,24 +1,65 @@
 use std::time;
 
 use failure;
+use futures;
 use slog;
-use tokio;
 
 use futures::prelude::*;
 
+use clock;
+
+/// Resolves once the jobs are told to stop, in every job that has a clone of it.
+pub type Stop = futures::future::Shared<futures::sync::oneshot::Receiver<()>>;
+
+/// Tells the jobs that have the returned `Stop` to stop, when sent to or dropped.
+pub fn stop() -> (futures::sync::oneshot::Sender<()>, Stop) {
+    let (sender, receiver) = futures::sync::oneshot::channel();
+    (sender, receiver.shared())
+}
+
+/// Ends the stream when the jobs are told to stop, so that a job can finish what it started.
+pub fn until<S>(stream: S, stop: Stop) -> impl Stream<Item = S::Item, Error = failure::Error>
+where
+    S: Stream<Error = failure::Error>,
+{
+    let stopped = stop
+        .then(|_| -> Result<_, failure::Error> { Ok(None) })
+        .into_stream();
+    stream
+        .map(Some)
+        .select(stopped)
+        .take_while(|item| Ok(item.is_some()))
+        .filter_map(|item| item)
+}
+
 #[async_stream(item = ())]
 pub fn every(
     log: slog::Logger,
     name: String,
+    clock: clock::Clock,
     duration: time::Duration,
 ) -> Result<(), failure::Error> {
     debug!(log, "starting timer {:?}", name);
 
     #[async]
-    for _ in tokio::timer::Interval::new(time::Instant::now(), duration) {
+    for _ in clock.interval(duration) {
         debug!(log, "timer tick {:?}", name);
         stream_yield!(());
     }
 
     Ok(())
 }
+
+/// Lets the executor run everything else that is ready before completing.
+pub fn yield_now() -> impl futures::Future<Item = (), Error = failure::Error> {
+    let mut yielded = false;
+    futures::future::poll_fn(move || -> futures::Poll<(), failure::Error> {
+        if yielded {
+            Ok(Async::Ready(()))
+        } else {
+            yielded = true;
+            futures::task::current().notify();
+            Ok(Async::NotReady)
+        }
+    })
+}
@@ -0,0 +1,308 @@
+//! Rationing of the water reservoir while nobody is around to refill it.
+
+use std::collections;
+use std::f64;
+use std::sync;
+
+use chrono;
+use chrono_tz;
+use failure;
+use uuid;
+
+use futures::prelude::async;
+use futures::prelude::await;
+
+use config;
+use db;
+use model;
+use schedule;
+
+/// How much of their usual water each plant gets, shared between the vacation and pump jobs.
+pub struct Budget {
+    scales: sync::RwLock<collections::HashMap<uuid::Uuid, f64>>,
+}
+
+/// The usual water consumption of a plant.
+#[derive(Clone, Debug)]
+pub struct Demand {
+    pub uuid: uuid::Uuid,
+    pub name: String,
+    pub thirsty: bool,
+    pub daily_ml: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Allocation {
+    pub demand: Demand,
+    pub daily_ml: f64,
+    // the fraction of the usual pump duration to run for
+    pub scale: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Plan {
+    pub days: f64,
+    pub reservoir_ml: f64,
+    pub allocations: Vec<Allocation>,
+    // how long the reservoir lasts at the usual consumption
+    pub days_unrationed: f64,
+    // how long the reservoir lasts when following the plan
+    pub days_rationed: f64,
+}
+
+impl Budget {
+    pub fn new() -> Self {
+        Budget {
+            scales: sync::RwLock::new(collections::HashMap::new()),
+        }
+    }
+
+    pub fn scale(&self, uuid: uuid::Uuid) -> f64 {
+        self.scales
+            .read()
+            .unwrap()
+            .get(&uuid)
+            .cloned()
+            .unwrap_or(1.0)
+    }
+
+    pub fn apply(&self, plan: &Plan) {
+        *self.scales.write().unwrap() = plan
+            .allocations
+            .iter()
+            .map(|a| (a.demand.uuid, a.scale))
+            .collect();
+    }
+
+    pub fn clear(&self) {
+        self.scales.write().unwrap().clear();
+    }
+}
+
+/// The start and end of a vacation.
+pub fn period(
+    vacation: &config::Vacation,
+    timezone: chrono_tz::Tz,
+) -> (chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>) {
+    (
+        schedule::resolve_wall_clock(timezone, vacation.from.and_hms(0, 0, 0)),
+        schedule::resolve_wall_clock(timezone, vacation.to.succ().and_hms(0, 0, 0)),
+    )
+}
+
+/// Plans how to ration the reservoir for the remainder of a vacation, based on how much water each
+/// plant used before the vacation and how much has been used since it started.
+#[async]
+pub fn plan_remaining(
+    db: sync::Arc<db::Db>,
+    modules: sync::Arc<Vec<sync::Arc<model::ModuleConfig>>>,
+    vacation: config::Vacation,
+    timezone: chrono_tz::Tz,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<Plan, failure::Error> {
+    let (from, to) = period(&vacation, timezone);
+    let start = if now > from { now } else { from };
+    let history_from = from - chrono::Duration::days(vacation.history_days);
+
+    let usual_events = await!(db.collect_pump_events(history_from, from))?;
+    let usual = consumption_ml(&modules, &usual_events, from);
+    let used = if now > from {
+        let used_events = await!(db.collect_pump_events(from, now))?;
+        consumption_ml(&modules, &used_events, now)
+    } else {
+        collections::HashMap::new()
+    };
+
+    let demands = modules
+        .iter()
+        .filter_map(|m| {
+            usual.get(&m.uuid).map(|ml| Demand {
+                uuid: m.uuid,
+                name: m.name.clone(),
+                thirsty: m.thirsty,
+                daily_ml: ml / vacation.history_days as f64,
+            })
+        })
+        .collect();
+
+    let reservoir_ml = vacation.reservoir_liters * 1000.0 - used.values().sum::<f64>();
+    let days = (to - start).num_seconds() as f64 / 86_400.0;
+
+    Ok(plan(demands, days, reservoir_ml.max(0.0)))
+}
+
+/// Divides the reservoir between plants so that it lasts for the specified number of days.
+///
+/// Thirsty plants get all the water they usually use if possible, and the other plants share the
+/// rest in proportion to what they usually use.
+pub fn plan(demands: Vec<Demand>, days: f64, reservoir_ml: f64) -> Plan {
+    let daily_supply = if days > 0.0 {
+        reservoir_ml / days
+    } else {
+        f64::INFINITY
+    };
+    let thirsty_demand = demands
+        .iter()
+        .filter(|d| d.thirsty)
+        .map(|d| d.daily_ml)
+        .sum::<f64>();
+    let other_demand = demands
+        .iter()
+        .filter(|d| !d.thirsty)
+        .map(|d| d.daily_ml)
+        .sum::<f64>();
+
+    let thirsty_scale = ratio(daily_supply, thirsty_demand);
+    let other_scale = ratio(
+        (daily_supply - thirsty_demand * thirsty_scale).max(0.0),
+        other_demand,
+    );
+
+    let allocations = demands
+        .into_iter()
+        .map(|demand| {
+            let scale = if demand.thirsty {
+                thirsty_scale
+            } else {
+                other_scale
+            };
+            Allocation {
+                daily_ml: demand.daily_ml * scale,
+                demand,
+                scale,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let daily_unrationed = thirsty_demand + other_demand;
+    let daily_rationed = allocations.iter().map(|a| a.daily_ml).sum::<f64>();
+
+    Plan {
+        days,
+        reservoir_ml,
+        allocations,
+        days_unrationed: lasting_days(reservoir_ml, daily_unrationed),
+        days_rationed: lasting_days(reservoir_ml, daily_rationed),
+    }
+}
+
+// Also guards against plants that used no water before the vacation, which would otherwise give
+// infinite or NaN scales
+fn ratio(supply: f64, demand: f64) -> f64 {
+    if demand > 0.0 && demand.is_finite() {
+        (supply / demand).max(0.0).min(1.0)
+    } else {
+        1.0
+    }
+}
+
+// A reservoir that nothing is drawn from lasts forever
+fn lasting_days(reservoir_ml: f64, daily_ml: f64) -> f64 {
+    if daily_ml > 0.0 {
+        reservoir_ml / daily_ml
+    } else {
+        f64::INFINITY
+    }
+}
+
+/// Sums up how much water the pumps used, from the pump events up to `to`.
+fn consumption_ml(
+    modules: &[sync::Arc<model::ModuleConfig>],
+    events: &[db::model::PumpEvent],
+    to: chrono::DateTime<chrono::Utc>,
+) -> collections::HashMap<uuid::Uuid, f64> {
+    let flow_rates = modules
+        .iter()
+        .filter_map(|m| m.pump_flow_ml_per_second.map(|f| (m.uuid, f)))
+        .collect::<collections::HashMap<_, _>>();
+
+    let mut started = collections::HashMap::new();
+    let mut result = flow_rates
+        .keys()
+        .map(|uuid| (*uuid, 0.0))
+        .collect::<collections::HashMap<_, _>>();
+
+    for event in events {
+        if event.pump_running {
+            started.entry(event.module_uuid).or_insert(event.created);
+        } else if let Some(start) = started.remove(&event.module_uuid) {
+            if let (Some(flow), Some(ml)) = (
+                flow_rates.get(&event.module_uuid),
+                result.get_mut(&event.module_uuid),
+            ) {
+                *ml += flow * seconds(event.created - start);
+            }
+        }
+    }
+
+    // Pumps that are still running at the end of the range
+    for (uuid, start) in started {
+        if let (Some(flow), Some(ml)) = (flow_rates.get(&uuid), result.get_mut(&uuid)) {
+            *ml += flow * seconds(to - start);
+        }
+    }
+
+    result
+}
+
+fn seconds(duration: chrono::Duration) -> f64 {
+    duration.num_milliseconds() as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn demand(thirsty: bool, daily_ml: f64) -> Demand {
+        Demand {
+            uuid: uuid::Uuid::new_v4(),
+            name: "plant".to_owned(),
+            thirsty,
+            daily_ml,
+        }
+    }
+
+    #[test]
+    fn thirsty_plants_are_served_first() {
+        let plan = plan(
+            vec![demand(true, 100.0), demand(false, 200.0)],
+            10.0,
+            2000.0,
+        );
+        assert_eq!(plan.allocations[0].scale, 1.0);
+        assert_eq!(plan.allocations[1].scale, 0.5);
+        assert_eq!(plan.days_unrationed, 2000.0 / 300.0);
+        assert_eq!(plan.days_rationed, 10.0);
+    }
+
+    #[test]
+    fn zero_demand_gives_finite_scales() {
+        let plan = plan(vec![demand(true, 0.0), demand(false, 0.0)], 10.0, 2000.0);
+        for allocation in &plan.allocations {
+            assert_eq!(allocation.scale, 1.0);
+            assert_eq!(allocation.daily_ml, 0.0);
+        }
+        assert_eq!(plan.days_unrationed, f64::INFINITY);
+        assert_eq!(plan.days_rationed, f64::INFINITY);
+    }
+
+    #[test]
+    fn empty_reservoir_without_demand_lasts() {
+        let plan = plan(vec![demand(false, 0.0)], 10.0, 0.0);
+        assert_eq!(plan.allocations[0].scale, 1.0);
+        assert!(!plan.days_rationed.is_nan());
+    }
+
+    #[test]
+    fn empty_reservoir_stops_watering() {
+        let plan = plan(vec![demand(true, 100.0), demand(false, 100.0)], 10.0, 0.0);
+        assert_eq!(plan.allocations[0].scale, 0.0);
+        assert_eq!(plan.allocations[1].scale, 0.0);
+    }
+
+    #[test]
+    fn no_days_left_gives_full_scales() {
+        let plan = plan(vec![demand(false, 100.0)], 0.0, 100.0);
+        assert_eq!(plan.allocations[0].scale, 1.0);
+    }
+}
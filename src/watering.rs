@@ -0,0 +1,729 @@
+use std::sync;
+use std::time;
+
+use chrono;
+use failure;
+use slog;
+
+use futures::prelude::async;
+use futures::prelude::await;
+
+use clock;
+use db;
+use lockout;
+use model;
+use pumps;
+use queue;
+use readings;
+use replay;
+use vacation;
+
+/// What caused a pump to run.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Trigger {
+    Scheduled,
+    /// A run requested by a human; when forced, blackout windows are ignored.
+    Manual {
+        force: bool,
+    },
+}
+
+/// Runs the pump of a single plant, and verifies that the plant received water.
+pub struct Waterer {
+    log: slog::Logger,
+    module: sync::Arc<model::ModuleConfig>,
+    pump: pumps::Pump,
+    readings: sync::Arc<readings::Readings>,
+    lockouts: sync::Arc<lockout::Lockouts>,
+    queue: sync::Arc<queue::RunQueue>,
+    budget: sync::Arc<vacation::Budget>,
+    db: sync::Arc<db::Db>,
+    clock: clock::Clock,
+    // records pump events for later replays
+    recorder: Option<sync::Arc<replay::Recorder>>,
+    busy: sync::atomic::AtomicBool,
+}
+
+struct Busy(sync::Arc<Waterer>);
+
+struct Burst(sync::Arc<Waterer>);
+
+struct Running(sync::Arc<Waterer>);
+
+impl Waterer {
+    #[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
+    pub fn new(
+        log: slog::Logger,
+        module: sync::Arc<model::ModuleConfig>,
+        readings: sync::Arc<readings::Readings>,
+        lockouts: sync::Arc<lockout::Lockouts>,
+        queue: sync::Arc<queue::RunQueue>,
+        budget: sync::Arc<vacation::Budget>,
+        db: sync::Arc<db::Db>,
+        clock: clock::Clock,
+        recorder: Option<sync::Arc<replay::Recorder>>,
+    ) -> Result<Self, failure::Error> {
+        let pump = pumps::Pump::new(
+            log.clone(),
+            &format!("precip:{}", module.name),
+            &module.pump_output,
+        )?;
+
+        Ok(Waterer {
+            log,
+            module,
+            pump,
+            readings,
+            lockouts,
+            queue,
+            budget,
+            db,
+            clock,
+            recorder,
+            busy: sync::atomic::AtomicBool::new(false),
+        })
+    }
+
+    pub fn module(&self) -> &sync::Arc<model::ModuleConfig> {
+        &self.module
+    }
+
+    pub fn clock(&self) -> clock::Clock {
+        self.clock.clone()
+    }
+
+    /// When a run triggered at `now` may start, considering blackout windows.
+    pub fn start_time(
+        &self,
+        trigger: Trigger,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Option<chrono::DateTime<chrono::Utc>> {
+        match trigger {
+            Trigger::Manual { force: true } => Some(now),
+            _ => self.module.blackout.next_allowed(now),
+        }
+    }
+
+    // Whether a run must not start because automatic watering is disabled for the plant
+    fn locked_out(&self, trigger: Trigger) -> bool {
+        let locked = trigger == Trigger::Scheduled && self.lockouts.is_locked(self.module.uuid);
+        if locked {
+            warn!(
+                self.log,
+                "skipping pump run, automatic watering is disabled name={:?} uuid={}",
+                self.module.name,
+                self.module.uuid
+            );
+        }
+        locked
+    }
+
+    // Turns the pump on or off, and returns when that happened
+    fn set_running(&self, running: bool) -> Result<chrono::DateTime<chrono::Utc>, failure::Error> {
+        self.pump.set_running(running)?;
+        let now = self.clock.now();
+        if let Some(ref recorder) = self.recorder {
+            recorder.record(&replay::Event::Pump {
+                time: now,
+                plant: self.module.uuid,
+                running,
+            });
+        }
+        Ok(now)
+    }
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        self.0.busy.store(false, sync::atomic::Ordering::SeqCst);
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.set_running(false) {
+            error!(
+                self.0.log,
+                "failed to turn pump off name={:?} uuid={}: {}",
+                self.0.module.name,
+                self.0.module.uuid,
+                e
+            );
+        }
+    }
+}
+
+impl Drop for Burst {
+    fn drop(&mut self) {
+        let module = &self.0.module;
+        self.0
+            .readings
+            .end_burst(module.uuid, self.0.clock.after(module.sample_burst_linger));
+    }
+}
+
+#[async]
+pub fn run(waterer: sync::Arc<Waterer>, trigger: Trigger) -> Result<(), failure::Error> {
+    let log = waterer.log.clone();
+    let module = waterer.module.clone();
+
+    if waterer.locked_out(trigger) {
+        return Ok(());
+    }
+
+    let clock = waterer.clock.clone();
+    let now = clock.now();
+    match waterer.start_time(trigger, now) {
+        None => {
+            warn!(
+                log,
+                "skipping pump run, no time outside of blackout windows name={:?} uuid={}",
+                module.name,
+                module.uuid
+            );
+            return Ok(());
+        }
+        Some(start) => {
+            if start > now {
+                info!(
+                    log,
+                    "deferring pump run until {} name={:?} trigger={:?} uuid={}",
+                    start,
+                    module.name,
+                    trigger,
+                    module.uuid
+                );
+                await!(clock.sleep((start - now).to_std()?))?;
+                // The watering may have failed in another run while this one waited
+                if waterer.locked_out(trigger) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    if waterer.busy.swap(true, sync::atomic::Ordering::SeqCst) {
+        warn!(
+            log,
+            "skipping pump run, pump is already running name={:?} uuid={}",
+            module.name,
+            module.uuid
+        );
+        return Ok(());
+    }
+    let _busy = Busy(waterer.clone());
+
+    let full_duration = module.pump_duration.unwrap_or(time::Duration::new(0, 0));
+    let scale = if trigger == Trigger::Scheduled {
+        waterer.budget.scale(module.uuid)
+    } else {
+        1.0
+    };
+    let duration = scale_duration(full_duration, scale);
+
+    if duration != full_duration {
+        info!(
+            log,
+            "rationing water name={:?} duration={:?} uuid={}", module.name, duration, module.uuid
+        );
+    }
+
+    if duration == time::Duration::new(0, 0) {
+        return Ok(());
+    }
+
+    // Sample moisture more often while watering, so that the check has readings to go by; that
+    // starts a window ahead of the pump, so that the readings before the run are as dense as the
+    // ones after it
+    waterer.readings.begin_burst(module.uuid);
+    let _burst = Burst(waterer.clone());
+    if let Some(check) = module.pump_check {
+        debug!(
+            log,
+            "sampling moisture before pump run name={:?} window={:?} uuid={}",
+            module.name,
+            check.window,
+            module.uuid
+        );
+        await!(clock.sleep(check.window))?;
+    }
+
+    let mut attempt = 1;
+    loop {
+        let started = clock.now();
+        // Taken before the pump runs, while the readings of the window are still known to be from
+        // before the run
+        let before = match module.pump_check {
+            Some(check) => waterer.readings.mean_moisture_voltage_between(
+                module.uuid,
+                started - chrono::Duration::from_std(check.window)?,
+                started,
+            ),
+            None => None,
+        };
+
+        await!(water(waterer.clone(), duration))?;
+
+        let check = match module.pump_check {
+            Some(check) => check,
+            None => break,
+        };
+
+        await!(clock.sleep(check.delay))?;
+
+        let window = chrono::Duration::from_std(check.window)?;
+        let now = clock.now();
+        // A faulty sensor would make a working pump look broken, or the other way around
+        if waterer.readings.faulty_since(module.uuid, started - window) {
+            warn!(
+                log,
+                "not verifying pump run, moisture sensor is faulty name={:?} health={:?} uuid={}",
+                module.name,
+                waterer.readings.health(module.uuid),
+                module.uuid
+            );
+            break;
+        }
+
+        let after = waterer
+            .readings
+            .mean_moisture_voltage_between(module.uuid, now - window, now);
+
+        let (before, after) = match (before, after) {
+            (Some(before), Some(after)) => (before, after),
+            _ => {
+                warn!(
+                    log,
+                    "no moisture readings to verify pump run name={:?} uuid={}",
+                    module.name,
+                    module.uuid
+                );
+                break;
+            }
+        };
+
+        // A rationed run is expected to wet the soil less, in proportion to the water it got
+        let min_delta = check.min_moisture_delta * scale.min(1.0);
+        let delta = module.moisture_fraction(after) - module.moisture_fraction(before);
+        if delta >= min_delta {
+            info!(
+                log,
+                "verified pump run name={:?} min_moisture_delta={} moisture_delta={} uuid={}",
+                module.name,
+                min_delta,
+                delta,
+                module.uuid
+            );
+            break;
+        }
+
+        warn!(
+            log,
+            "watering failed name={:?} attempt={} min_moisture_delta={} moisture_delta={} uuid={}",
+            module.name,
+            attempt,
+            min_delta,
+            delta,
+            module.uuid
+        );
+        let insert = waterer.db.insert_watering_failed_event(
+            clock.now(),
+            module.uuid,
+            attempt,
+            before,
+            after,
+        );
+        if let Err(e) = await!(insert) {
+            warn!(log, "failed to insert failed watering event: {}", e);
+        }
+
+        if check.retry && attempt == 1 {
+            attempt += 1;
+            continue;
+        }
+
+        error!(
+            log,
+            "disabling automatic watering until acknowledged name={:?} uuid={}",
+            module.name,
+            module.uuid
+        );
+        waterer.lockouts.lock(
+            module.uuid,
+            &format!(
+                "moisture changed by {} after {} attempt(s) at {}",
+                delta,
+                attempt,
+                clock.now()
+            ),
+        )?;
+        break;
+    }
+
+    Ok(())
+}
+
+#[async]
+fn water(waterer: sync::Arc<Waterer>, duration: time::Duration) -> Result<(), failure::Error> {
+    let log = waterer.log.clone();
+    let module = waterer.module.clone();
+
+    debug!(
+        log,
+        "waiting for power group name={:?} power_group={:?} uuid={}",
+        module.name,
+        module.power_group,
+        module.uuid
+    );
+    let permit = await!(queue::RunQueue::acquire(
+        waterer.queue.clone(),
+        module.power_group.as_ref().map(|g| g.as_str()),
+        module.uuid
+    ))?;
+
+    info!(
+        log,
+        "running turning pump on name={:?} uuid={}", module.name, module.uuid
+    );
+    // Turns the pump off however this ends, even if the run is cancelled
+    let running = Running(waterer.clone());
+    let started = waterer.set_running(true)?;
+
+    await!(waterer.clock.sleep(duration))?;
+
+    info!(
+        log,
+        "running turning pump off name={:?} uuid={}", module.name, module.uuid
+    );
+    drop(running);
+    let stopped = waterer.clock.now();
+    drop(permit);
+
+    // Written after the run, so that a slow database can't keep the pump running
+    for (time, on) in vec![(started, true), (stopped, false)] {
+        let insert = waterer.db.insert_pump_measurement(time, module.uuid, on);
+        if let Err(e) = await!(insert) {
+            warn!(
+                log,
+                "failed to insert pump event name={:?} running={} uuid={}: {}",
+                module.name,
+                on,
+                module.uuid,
+                e
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn scale_duration(duration: time::Duration, scale: f64) -> time::Duration {
+    let millis = (duration.as_secs() * 1000 + u64::from(duration.subsec_millis())) as f64 * scale;
+    time::Duration::from_millis(millis.max(0.0).round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections;
+    use std::env;
+    use std::fs;
+    use std::path;
+    use std::process;
+
+    use chrono_tz;
+    use futures;
+    use uuid;
+
+    use futures::Async;
+    use futures::Future;
+
+    use blackout;
+    use config;
+    use sensors;
+
+    use super::*;
+
+    struct Fixture {
+        timeline: sync::Arc<clock::Timeline>,
+        plant: uuid::Uuid,
+        readings: sync::Arc<readings::Readings>,
+        lockouts: sync::Arc<lockout::Lockouts>,
+        budget: sync::Arc<vacation::Budget>,
+        waterer: sync::Arc<Waterer>,
+        dir: path::PathBuf,
+    }
+
+    fn at(seconds: i64) -> chrono::DateTime<chrono::Utc> {
+        "2018-11-20T12:00:00Z"
+            .parse::<chrono::DateTime<chrono::Utc>>()
+            .unwrap()
+            + chrono::Duration::seconds(seconds)
+    }
+
+    fn check() -> model::PumpCheck {
+        model::PumpCheck {
+            delay: time::Duration::from_secs(60),
+            window: time::Duration::from_secs(60),
+            min_moisture_delta: 0.1,
+            retry: true,
+        }
+    }
+
+    // Every test has a state directory of its own, since the tests run in parallel
+    fn fixture(name: &str, windows: &config::Blackout) -> Fixture {
+        let log = slog::Logger::root(slog::Discard, o!());
+        let timeline = sync::Arc::new(clock::Timeline::new(at(0)));
+        let plant = uuid::Uuid::new_v4();
+        let dir = env::temp_dir().join(format!("precip-watering-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+
+        let module = sync::Arc::new(model::ModuleConfig {
+            uuid: plant,
+            name: name.to_owned(),
+            description: String::new(),
+            thirsty: false,
+            soil_temperature_probe: None,
+            sample_burst_linger: time::Duration::from_secs(60),
+            pump_enabled: true,
+            pump_schedule: None,
+            pump_duration: Some(time::Duration::from_secs(10)),
+            pump_output: pumps::Output {
+                backend: pumps::Backend::Simulated,
+                chip: String::new(),
+                line: 0,
+                active_low: false,
+            },
+            pump_check: Some(check()),
+            blackout: blackout::Calendar::new(chrono_tz::UTC, windows),
+            power_group: None,
+            pump_flow_ml_per_second: Some(10.0),
+            min_moisture: 0.2,
+            max_moisture: 0.8,
+            moisture_voltage_dry: 2.0,
+            moisture_voltage_wet: 1.0,
+            moisture_temperature_coefficient: 0.0,
+            moisture_reference_temperature: 20.0,
+            moisture_faults: sensors::fault::Limits {
+                min_voltage: 0.5,
+                max_voltage: 2.5,
+                stuck_tolerance: 0.001,
+                stuck_window: time::Duration::from_secs(3600),
+                max_step: 0.5,
+                recovery_readings: 3,
+            },
+        });
+        let readings = sync::Arc::new(readings::Readings::new(chrono::Duration::hours(1)));
+        let lockouts = sync::Arc::new(lockout::Lockouts::open(&dir).unwrap());
+        let budget = sync::Arc::new(vacation::Budget::new());
+        let db = sync::Arc::new(db::Db::discard(&config::Db {
+            hosts: Vec::new(),
+            mode: db::influx::Mode::Failover,
+            health_check_interval_seconds: 30,
+            timeout_seconds: 5,
+            credentials: None,
+            v2: None,
+            postgres: None,
+            retention: config::Retention::default(),
+        }));
+
+        let waterer = sync::Arc::new(
+            Waterer::new(
+                log,
+                module,
+                readings.clone(),
+                lockouts.clone(),
+                sync::Arc::new(queue::RunQueue::new(collections::HashMap::new())),
+                budget.clone(),
+                db,
+                clock::Clock::Virtual(timeline.clone()),
+                None,
+            )
+            .unwrap(),
+        );
+
+        Fixture {
+            timeline,
+            plant,
+            readings,
+            lockouts,
+            budget,
+            waterer,
+            dir,
+        }
+    }
+
+    impl Fixture {
+        // A reading every 10 seconds, from and to the specified seconds after the start
+        fn moisture(&self, from: i64, to: i64, moisture_voltage: f64) {
+            for seconds in (from..to + 1).filter(|s| s % 10 == 0) {
+                self.reading(seconds, moisture_voltage, sensors::fault::Health::Ok);
+            }
+        }
+
+        fn reading(&self, seconds: i64, moisture_voltage: f64, health: sensors::fault::Health) {
+            self.readings.record(
+                self.plant,
+                readings::Reading {
+                    time: at(seconds),
+                    moisture_voltage,
+                    health,
+                },
+            );
+        }
+
+        // Runs the pump like the schedule would, moving the virtual time on whenever it waits
+        fn water(&self) {
+            let mut run = run(self.waterer.clone(), Trigger::Scheduled);
+            self.drive(&mut run);
+        }
+
+        fn drive<F>(&self, future: &mut F)
+        where
+            F: Future<Item = (), Error = failure::Error>,
+        {
+            loop {
+                match poll(|| future.poll()).unwrap() {
+                    Async::Ready(()) => return,
+                    Async::NotReady => self.timeline.advance(
+                        self.timeline
+                            .next_wakeup()
+                            .expect("the run waits for nothing"),
+                    ),
+                }
+            }
+        }
+
+        fn is_locked(&self) -> bool {
+            self.lockouts.is_locked(self.plant)
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    // Polls once, in a task that nothing wakes up
+    fn poll<T, E, F>(mut f: F) -> futures::Poll<T, E>
+    where
+        F: FnMut() -> futures::Poll<T, E>,
+    {
+        futures::future::poll_fn(|| -> Result<_, ()> { Ok(Async::Ready(f())) })
+            .wait()
+            .unwrap()
+    }
+
+    #[test]
+    fn verifies_a_run_that_wets_the_soil() {
+        let fixture = fixture("verified", &config::Blackout::default());
+        // Sampled for a window before the pump runs at 60s, and checked a delay after it stops
+        fixture.moisture(0, 60, 1.8);
+        fixture.moisture(70, 300, 1.6);
+
+        fixture.water();
+
+        assert!(!fixture.is_locked());
+        assert_eq!(fixture.timeline.now(), at(130));
+        assert!(!fixture.waterer.pump.running().unwrap());
+        assert!(fixture.readings.in_burst(fixture.plant, at(189)));
+        assert!(!fixture.readings.in_burst(fixture.plant, at(190)));
+    }
+
+    #[test]
+    fn retries_once_and_then_locks_out() {
+        let fixture = fixture("locked", &config::Blackout::default());
+        fixture.moisture(0, 300, 1.8);
+
+        fixture.water();
+
+        assert!(fixture.is_locked());
+        // Two runs of 10 seconds, each checked 60 seconds after it
+        assert_eq!(fixture.timeline.now(), at(200));
+        let lockouts = fixture.lockouts.list().unwrap();
+        assert_eq!(lockouts.len(), 1);
+        assert!(lockouts[0].1.contains("after 2 attempt(s)"));
+
+        // Not even tried again until acknowledged
+        fixture.water();
+        assert_eq!(fixture.timeline.now(), at(200));
+    }
+
+    #[test]
+    fn skips_the_check_with_a_faulty_sensor() {
+        let fixture = fixture("faulty", &config::Blackout::default());
+        fixture.moisture(0, 60, 1.8);
+        fixture.reading(100, 0.1, sensors::fault::Health::OutOfRange);
+        fixture.moisture(110, 300, 1.8);
+
+        fixture.water();
+
+        assert!(!fixture.is_locked());
+        assert_eq!(fixture.timeline.now(), at(130));
+    }
+
+    #[test]
+    fn skips_the_check_without_readings() {
+        let fixture = fixture("unread", &config::Blackout::default());
+
+        fixture.water();
+
+        assert!(!fixture.is_locked());
+        assert_eq!(fixture.timeline.now(), at(130));
+    }
+
+    #[test]
+    fn expects_less_of_a_rationed_run() {
+        let fixture = fixture("rationed", &config::Blackout::default());
+        fixture.budget.apply(&vacation::Plan {
+            days: 7.0,
+            reservoir_ml: 1000.0,
+            allocations: vec![vacation::Allocation {
+                demand: vacation::Demand {
+                    uuid: fixture.plant,
+                    name: "rationed".to_owned(),
+                    thirsty: false,
+                    daily_ml: 200.0,
+                },
+                daily_ml: 100.0,
+                scale: 0.5,
+            }],
+            days_unrationed: 5.0,
+            days_rationed: 10.0,
+        });
+        // Half of the usual delta, for half of the usual water
+        fixture.moisture(0, 60, 1.8);
+        fixture.moisture(70, 300, 1.74);
+
+        fixture.water();
+
+        assert!(!fixture.is_locked());
+        assert_eq!(fixture.timeline.now(), at(125));
+    }
+
+    #[test]
+    fn a_deferred_run_is_skipped_when_locked_out_meanwhile() {
+        let fixture = fixture(
+            "deferred",
+            &config::Blackout {
+                quiet_hours: vec![config::QuietHours {
+                    from: chrono::NaiveTime::from_hms(11, 0, 0),
+                    to: chrono::NaiveTime::from_hms(12, 30, 0),
+                }],
+                ..config::Blackout::default()
+            },
+        );
+        fixture.moisture(0, 3600, 1.8);
+
+        let mut run = run(fixture.waterer.clone(), Trigger::Scheduled);
+        assert!(poll(|| run.poll()).unwrap().is_not_ready());
+        fixture
+            .lockouts
+            .lock(fixture.plant, "failed in another run")
+            .unwrap();
+        fixture.drive(&mut run);
+
+        assert_eq!(fixture.timeline.now(), at(1800));
+        assert!(!fixture.readings.in_burst(fixture.plant, at(1800)));
+    }
+}